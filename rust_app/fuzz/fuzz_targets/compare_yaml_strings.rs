@@ -0,0 +1,21 @@
+#![no_main]
+
+#[macro_use]
+extern crate log;
+
+use libfuzzer_sys::fuzz_target;
+
+// compare_configs is a binary crate, so the comparison engine is pulled in by path.
+#[path = "../../src/utils/mod.rs"]
+#[allow(dead_code)]
+mod utils;
+
+fuzz_target!(|data: &[u8]| {
+    let input = String::from_utf8_lossy(data);
+    // Both documents are packed in the same input, separated by a NUL byte.
+    let (yaml_a_content, yaml_b_content) = input.split_once('\0').unwrap_or((&input, &input));
+
+    // Config files are read from stacks as they are, so any input has to be
+    // rejected without panicking.
+    let _ = utils::compare_yaml_strings(yaml_a_content, yaml_b_content);
+});
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use rocket::futures::try_join;
+
+use crate::github::ConfigError;
+use crate::models;
+use crate::reconcile::WHOLE_FILE;
+use crate::source::{BlameRange, ConfigSource};
+
+/// The line of every key of a YAML file, by path as `compare_dicts` writes
+/// them. Lists and block scalars are skipped, since paths don't go into them.
+/// Keys inside flow mappings (`a: {b: 1}`) aren't located either, their paths
+/// are blamed on no line.
+pub fn key_lines(yaml: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    let mut parents: Vec<(usize, String)> = Vec::new();
+    // Lines indented deeper than this belong to a list item or a block scalar
+    let mut skip_under: Option<usize> = None;
+
+    for (index, line) in yaml.lines().enumerate() {
+        let content = line.trim_start();
+        let indent = line.len() - content.len();
+        if content.is_empty() || content.starts_with('#') || content.starts_with("---") {
+            continue;
+        }
+        if let Some(skipped_indent) = skip_under {
+            if indent > skipped_indent || (indent == skipped_indent && content.starts_with('-')) {
+                continue;
+            }
+            skip_under = None;
+        }
+        if content.starts_with('-') {
+            skip_under = Some(indent);
+            continue;
+        }
+
+        let (key, value) = match split_key(content) {
+            Some(key_value) => key_value,
+            None => continue,
+        };
+        while parents
+            .last()
+            .is_some_and(|(parent_indent, _)| *parent_indent >= indent)
+        {
+            parents.pop();
+        }
+        let path: String = parents
+            .iter()
+            .map(|(_, parent)| parent.as_str())
+            .chain([key.as_str()])
+            .flat_map(|key| ["/", key])
+            .collect();
+        lines.entry(path).or_insert(index + 1);
+
+        if value.starts_with('|') || value.starts_with('>') {
+            skip_under = Some(indent);
+        }
+        parents.push((indent, key));
+    }
+    lines
+}
+
+/// Splits `key: value`, the key being unquoted.
+fn split_key(content: &str) -> Option<(String, &str)> {
+    let (key, rest) = match content.chars().next()? {
+        quote @ ('"' | '\'') => {
+            let end = content[1..].find(quote)? + 1;
+            (&content[1..end], content[end + 1..].trim_start())
+        }
+        _ => {
+            let end = content
+                .find(": ")
+                .or_else(|| content.strip_suffix(':').map(str::len))?;
+            (content[..end].trim_end(), &content[end..])
+        }
+    };
+    let value = rest.strip_prefix(':')?;
+    Some((key.to_string(), value.trim()))
+}
+
+/// A file of one stack, along with who last changed each of its lines.
+struct BlamedFile {
+    lines: HashMap<String, usize>,
+    ranges: Vec<BlameRange>,
+}
+
+impl BlamedFile {
+    /// Reads and blames `file`, which the stack may not have.
+    async fn read(
+        source: &dyn ConfigSource,
+        stack: &str,
+        file: &str,
+        revision: &str,
+    ) -> Result<Option<Self>, ConfigError> {
+        let content = match source.read_file(stack, file, revision).await {
+            Ok(content) => content,
+            Err(ConfigError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let ranges = source.blame_file(stack, file, revision).await?;
+        Ok(Some(BlamedFile {
+            lines: key_lines(&content),
+            ranges,
+        }))
+    }
+
+    /// The range holding the key at `path`, or the newest one for the whole file.
+    fn blame(&self, path: &str) -> Option<models::LineBlame> {
+        let (line, range) = if path == WHOLE_FILE {
+            (None, self.ranges.iter().max_by_key(|range| range.date)?)
+        } else {
+            let line = *self.lines.get(path)?;
+            let range = self
+                .ranges
+                .iter()
+                .find(|range| range.start_line <= line && line <= range.end_line)?;
+            (Some(line), range)
+        };
+        Some(models::LineBlame {
+            line,
+            sha: range.commit_sha.clone(),
+            author: range.author.clone(),
+            date: range.date,
+        })
+    }
+}
+
+async fn revision(
+    source: &dyn ConfigSource,
+    stack: &str,
+    sha: &Option<String>,
+) -> Result<String, ConfigError> {
+    match sha {
+        Some(sha) => Ok(sha.clone()),
+        None => source.resolve_revision(stack, None).await,
+    }
+}
+
+/// Who last changed each differing path of `diff`, on both stacks at the
+/// commits it was computed at. Diffs without commits are blamed at the heads.
+pub async fn blame_diff(
+    source: &dyn ConfigSource,
+    diff: &models::FileDiff,
+) -> Result<models::BlameDiffResponse, ConfigError> {
+    let (sha_a, sha_b) = try_join!(
+        revision(source, &diff.stack_a, &diff.sha_a),
+        revision(source, &diff.stack_b, &diff.sha_b)
+    )?;
+    let (file_a, file_b) = try_join!(
+        BlamedFile::read(source, &diff.stack_a, &diff.file, &sha_a),
+        BlamedFile::read(source, &diff.stack_b, &diff.file, &sha_b)
+    )?;
+
+    let paths = [
+        ("same_key_diff_value", &diff.same_key_diff_value),
+        ("left_not_right", &diff.left_not_right),
+        ("right_not_left", &diff.right_not_left),
+    ]
+    .into_iter()
+    .flat_map(|(category, paths)| {
+        paths.iter().map(|path| models::PathBlame {
+            category: category.to_string(),
+            path: path.clone(),
+            blame_a: file_a.as_ref().and_then(|file| file.blame(path)),
+            blame_b: file_b.as_ref().and_then(|file| file.blame(path)),
+        })
+    })
+    .collect();
+
+    Ok(models::BlameDiffResponse {
+        id: diff.id.map(|id| id.to_hex()).unwrap_or_default(),
+        stack_a: diff.stack_a.clone(),
+        stack_b: diff.stack_b.clone(),
+        file: diff.file.clone(),
+        sha_a,
+        sha_b,
+        paths,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_located_by_path() {
+        let yaml = "\
+# Overrides
+service:
+  name: svc
+  \"quoted.key\": 1
+  env:
+    - name: A
+      value: b
+  script: |
+    run: this
+database:
+  url: postgres://db
+";
+        let lines = key_lines(yaml);
+
+        assert_eq!(lines["/service"], 2);
+        assert_eq!(lines["/service/name"], 3);
+        assert_eq!(lines["/service/quoted.key"], 4);
+        assert_eq!(lines["/service/env"], 5);
+        assert_eq!(lines["/service/script"], 8);
+        assert_eq!(lines["/database/url"], 11);
+        assert!(!lines.contains_key("/service/env/name"));
+        assert!(!lines.contains_key("/service/script/run"));
+        assert_eq!(lines.len(), 7);
+    }
+
+    /// Stack A has the file, each of its lines changed by its own commit, and
+    /// stack B doesn't.
+    struct OneFileSource;
+
+    #[rocket::async_trait]
+    impl ConfigSource for OneFileSource {
+        async fn resolve_revision(
+            &self,
+            stack: &str,
+            _reference: Option<&str>,
+        ) -> Result<String, ConfigError> {
+            Ok(format!("head-{}", stack))
+        }
+
+        async fn list_files(
+            &self,
+            _stack: &str,
+            _revision: &str,
+        ) -> Result<crate::source::SourceTree, ConfigError> {
+            Ok(Default::default())
+        }
+
+        async fn read_file(
+            &self,
+            stack: &str,
+            path: &str,
+            _revision: &str,
+        ) -> Result<String, ConfigError> {
+            match stack {
+                "stack-a" => Ok("a: 1\nb:\n  c: 2\n".to_string()),
+                _ => Err(ConfigError::NotFound(path.to_string())),
+            }
+        }
+
+        async fn blame_file(
+            &self,
+            _stack: &str,
+            _path: &str,
+            revision: &str,
+        ) -> Result<Vec<BlameRange>, ConfigError> {
+            Ok((1..=3)
+                .map(|line| BlameRange {
+                    start_line: line,
+                    end_line: line,
+                    commit_sha: format!("{}-{}", revision, line),
+                    author: "Jane Doe".to_string(),
+                    date: chrono::DateTime::from_timestamp(line as i64, 0).unwrap(),
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn paths_are_blamed_on_the_stacks_having_them() {
+        let diff = models::FileDiff {
+            id: None,
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+            file: "config.yml".to_string(),
+            left_not_right: vec!["/b/c".to_string(), WHOLE_FILE.to_string()],
+            right_not_left: vec!["/d".to_string()],
+            same_key_diff_value: Vec::new(),
+            sha_a: Some("abc".to_string()),
+            sha_b: None,
+            run_id: None,
+            reviewed: None,
+            pr_url: None,
+            created_at: None,
+            updated_at: None,
+        };
+
+        let response = blame_diff(&OneFileSource, &diff).await.unwrap();
+
+        assert_eq!(
+            (response.sha_a.as_str(), response.sha_b.as_str()),
+            ("abc", "head-stack-b")
+        );
+        let blames: Vec<_> = response
+            .paths
+            .iter()
+            .map(|path| {
+                (
+                    path.category.as_str(),
+                    path.path.as_str(),
+                    path.blame_a
+                        .as_ref()
+                        .map(|blame| (blame.line, blame.sha.as_str())),
+                    path.blame_b.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            blames,
+            vec![
+                ("left_not_right", "/b/c", Some((Some(3), "abc-3")), false),
+                ("left_not_right", "/*", Some((None, "abc-3")), false),
+                ("right_not_left", "/d", None, false),
+            ]
+        );
+    }
+}
@@ -1,15 +1,35 @@
-use bson::doc;
+use bson::{doc, Bson, Document};
 use chrono::Utc;
-use mongodb;
+use mongodb::options::{FindOneOptions, FindOptions};
+use mongodb::IndexModel;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::futures::TryStreamExt;
 use rocket::{Build, Rocket};
+use serde::{Deserialize, Serialize};
 use std::env;
-use std::error::Error;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+use crate::error::AppError;
+use crate::github::GithubClient;
+use crate::models::{ComparisonRun, DiffCategory, DiffQuery, DiffSort, Page, RunCounts, RunStatus};
 use crate::{models, rocket};
 
+const DEFAULT_DIFF_LIMIT: i64 = 100;
+const MAX_DIFF_LIMIT: i64 = 500;
+const DEFAULT_RUN_LIMIT: i64 = 50;
+const MAX_RUN_LIMIT: i64 = 200;
+/// A run takes minutes, one still going after this was interrupted, e.g. by
+/// a restart.
+const STALE_RUN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Diffs stored before config files were matched by pattern name the folder
+/// of the file, which was always this one.
+const LEGACY_CONFIG_FILE_NAME: &str = "config-overrides.yml";
+/// Recorded in `migrations` once legacy diffs were migrated.
+const LEGACY_FILES_MIGRATION: &str = "legacy_files";
+
+#[derive(Clone)]
 pub struct DiffCollection {
     pub collection: mongodb::Collection<models::FileDiff>,
 }
@@ -23,18 +43,18 @@ impl DiffCollection {
     pub async fn find_diff_by_id(
         &self,
         diff_id: bson::oid::ObjectId,
-    ) -> Result<models::FileDiff, Box<dyn Error>> {
+    ) -> Result<models::FileDiff, AppError> {
         match self.collection.find_one(doc! {"_id": diff_id}, None).await {
             Ok(Some(document)) => Ok(document),
-            Ok(None) => Err(anyhow::Error::msg("Not Found").into()),
-            Err(e) => Err(Box::new(e) as Box<dyn Error>),
+            Ok(None) => Err(AppError::NotFound(format!("No diff {}", diff_id))),
+            Err(e) => Err(e.into()),
         }
     }
 
     pub async fn insert_diff_from_diff_base_schema(
         &self,
         payload: models::DiffBaseSchema,
-    ) -> Result<mongodb::results::InsertOneResult, Box<dyn Error>> {
+    ) -> Result<mongodb::results::InsertOneResult, AppError> {
         let now = Utc::now();
         let system_time: SystemTime = now.into();
         let file_diff = models::FileDiff {
@@ -45,61 +65,202 @@ impl DiffCollection {
             left_not_right: payload.left_not_right,
             right_not_left: payload.right_not_left,
             same_key_diff_value: payload.same_key_diff_value,
+            sha_a: payload.sha_a,
+            sha_b: payload.sha_b,
+            run_id: None,
             reviewed: Some("false".to_string()),
+            pr_url: None,
             created_at: Some(system_time.into()),
             updated_at: Some(system_time.into()),
         };
         self.collection
             .insert_one(file_diff, None)
             .await
-            .map_err(|e| Box::new(e) as Box<dyn Error>)
+            .map_err(AppError::from)
     }
 
     pub async fn insert_diff(
-      &self,
-      payload: &models::FileDiff,
-  ) -> Result<mongodb::results::InsertOneResult, Box<dyn Error>> {
-      info!("Inserting for file : {}", &payload.file);
-      self.collection
-          .insert_one(payload, None)
-          .await
-          .map_err(|e| Box::new(e) as Box<dyn Error>)
-  }
-
-    pub async fn get_all_diffs_from_stacks(
-        &self,
-        payload: &models::GetAllDiffsPayload,
-    ) -> Result<Vec<models::FileDiff>, Box<dyn Error>> {
-        match self
+        &self,
+        payload: &models::FileDiff,
+    ) -> Result<mongodb::results::InsertOneResult, AppError> {
+        info!("Inserting for file : {}", &payload.file);
+        self.collection
+            .insert_one(payload, None)
+            .await
+            .map_err(AppError::from)
+    }
+
+    /// One page of the diffs of a pair matching `query`, along with the cursor
+    /// of the next page if there is one.
+    pub async fn find_diffs(
+        &self,
+        stack_a: &str,
+        stack_b: &str,
+        query: &DiffQuery,
+    ) -> Result<(Vec<models::FileDiff>, Option<String>), AppError> {
+        let cursor = query
+            .page
+            .cursor
+            .as_deref()
+            .map(DiffCursor::decode)
+            .transpose()?;
+        self.find_page(
+            diff_filter(stack_a, stack_b, query, cursor.as_ref()),
+            query.sort,
+            &query.page,
+        )
+        .await
+    }
+
+    /// One page of the diffs matching `filter`, along with the cursor of the
+    /// next page if there is one.
+    async fn find_page(
+        &self,
+        filter: Document,
+        sort: DiffSort,
+        page: &Page,
+    ) -> Result<(Vec<models::FileDiff>, Option<String>), AppError> {
+        let limit = page_limit(page);
+        // One more diff than asked tells whether there is a next page
+        let options = FindOptions::builder()
+            .sort(sort_document(sort))
+            .limit(limit + 1)
+            .build();
+
+        let diffs: Vec<models::FileDiff> = self
             .collection
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await?;
+        cut_page(diffs, limit, sort)
+    }
+
+    /// The reviewed diffs of the pair with exactly the paths of one of `diffs`,
+    /// for the same file, which a CI gate doesn't fail on.
+    pub async fn get_reviewed_diffs_like(
+        &self,
+        stack_a: &str,
+        stack_b: &str,
+        diffs: &[&models::FileDiff],
+    ) -> Result<Vec<models::FileDiff>, AppError> {
+        if diffs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let same_paths: Vec<Document> = diffs.iter().map(|diff| same_paths_filter(diff)).collect();
+        self.collection
             .find(
                 doc! {
-                  "stack_a": &payload.stack_a,
-                  "stack_b": &payload.stack_b,
+                  "stack_a": stack_a,
+                  "stack_b": stack_b,
+                  "reviewed": "true",
+                  "$or": same_paths,
                 },
                 None,
             )
+            .await?
+            .try_collect()
             .await
-        {
-            Ok(cursor) => cursor
-                .try_collect()
-                .await
-                .map_err(|e| Box::new(e) as Box<dyn Error>),
-            Err(e) => Err(Box::new(e) as Box<dyn Error>),
+            .map_err(AppError::from)
+    }
+
+    /// Backs the listings of a pair, sorted either way, the reviewed diffs of
+    /// its files and the diffs of a run.
+    pub async fn create_indexes(&self) -> Result<(), AppError> {
+        let indexes = vec![
+            IndexModel::builder()
+                .keys(doc! {"stack_a": 1, "stack_b": 1, "created_at": -1, "_id": -1})
+                .build(),
+            IndexModel::builder()
+                .keys(doc! {"stack_a": 1, "stack_b": 1, "file": 1, "_id": 1})
+                .build(),
+            IndexModel::builder()
+                .keys(doc! {"run_id": 1, "file": 1, "_id": 1})
+                .build(),
+        ];
+        self.collection.create_indexes(indexes, None).await?;
+        Ok(())
+    }
+
+    /// Names the file of legacy diffs by its path, as newer diffs do, so that
+    /// their reviews still apply. Runs once, a marker being left in `migrations`.
+    pub async fn migrate_legacy_files(
+        &self,
+        database: &mongodb::Database,
+    ) -> Result<u64, AppError> {
+        let migrations = database.collection::<Document>("migrations");
+        let marker = doc! {"_id": LEGACY_FILES_MIGRATION};
+        if migrations.find_one(marker.clone(), None).await?.is_some() {
+            return Ok(0);
         }
+
+        let result = self
+            .collection
+            .update_many(
+                legacy_files_filter(),
+                vec![doc! {"$set": {"file": {"$concat": ["$file", "/", LEGACY_CONFIG_FILE_NAME]}}}],
+                None,
+            )
+            .await?;
+        migrations.update_one(
+            marker,
+            doc! {"$set": {"migrated_at": bson::DateTime::now(), "modified": result.modified_count as i64}},
+            mongodb::options::UpdateOptions::builder().upsert(true).build(),
+        ).await?;
+        Ok(result.modified_count)
+    }
+
+    /// The diffs of the last comparison of a pair compared before runs were
+    /// recorded, which all share its creation date, by file.
+    pub async fn get_latest_diffs_without_run(
+        &self,
+        stack_a: &str,
+        stack_b: &str,
+        page: &Page,
+    ) -> Result<(Vec<models::FileDiff>, Option<String>), AppError> {
+        let options = FindOneOptions::builder()
+            .sort(doc! {"created_at": -1})
+            .build();
+        let latest_diff = self
+            .collection
+            .find_one(doc! {"stack_a": stack_a, "stack_b": stack_b}, options)
+            .await?;
+        let created_at = match latest_diff.and_then(|diff| diff.created_at) {
+            Some(created_at) => created_at,
+            None => return Ok((Vec::new(), None)),
+        };
+
+        let filter = doc! {"stack_a": stack_a, "stack_b": stack_b, "created_at": created_at};
+        self.find_page(
+            with_cursor(filter, page, DiffSort::FileAsc)?,
+            DiffSort::FileAsc,
+            page,
+        )
+        .await
+    }
+
+    /// One page of the diffs of a run, by file.
+    pub async fn get_diffs_by_run(
+        &self,
+        run_id: bson::oid::ObjectId,
+        page: &Page,
+    ) -> Result<(Vec<models::FileDiff>, Option<String>), AppError> {
+        let filter = doc! {"run_id": run_id};
+        self.find_page(
+            with_cursor(filter, page, DiffSort::FileAsc)?,
+            DiffSort::FileAsc,
+            page,
+        )
+        .await
     }
 
     pub async fn toggle_review(
         &self,
         diff_id: bson::oid::ObjectId,
-    ) -> Result<mongodb::results::UpdateResult, Box<dyn Error>> {
+    ) -> Result<mongodb::results::UpdateResult, AppError> {
         let file_diff = self.find_diff_by_id(diff_id).await?;
         info!("{:?}", &file_diff.reviewed);
-        let reviewed = match file_diff.reviewed {
-            Some(ref r) if r == "false" => "true".to_string(),
-            Some(ref r) if r == "true" => "false".to_string(),
-            _ => return Err(anyhow::Error::msg("Not Found").into()),
-        };
+        let reviewed = toggled_review(file_diff.reviewed.as_deref());
         info!("{:?}", &reviewed);
 
         let new_doc = doc! {
@@ -112,10 +273,544 @@ impl DiffCollection {
         self.collection
             .update_one(doc! {"_id": diff_id}, new_doc, None)
             .await
-            .map_err(|e| Box::new(e) as Box<dyn Error>)
+            .map_err(AppError::from)
+    }
+
+    pub async fn set_pr_url(
+        &self,
+        diff_id: bson::oid::ObjectId,
+        pr_url: &str,
+    ) -> Result<mongodb::results::UpdateResult, AppError> {
+        let now = Utc::now();
+        let system_time: SystemTime = now.into();
+        let new_doc = doc! {
+          "$set":
+          {
+            "pr_url": pr_url,
+            "updated_at": bson::DateTime::from(system_time),
+          },
+        };
+
+        self.collection
+            .update_one(doc! {"_id": diff_id}, new_doc, None)
+            .await
+            .map_err(AppError::from)
+    }
+}
+/// Where comparison runs are recorded, so that routes can be tested without
+/// Mongo.
+#[rocket::async_trait]
+pub trait RunStore: Send + Sync {
+    async fn start_run(
+        &self,
+        payload: &models::ComputeAllDiffPayload,
+    ) -> Result<bson::oid::ObjectId, AppError>;
+
+    /// Records how a run ended, along with what it found when it succeeded.
+    async fn finish_run(
+        &self,
+        run_id: bson::oid::ObjectId,
+        result: &Result<models::ComputeAllDiffResponse, AppError>,
+    ) -> Result<(), AppError>;
+
+    async fn find_run_by_id(&self, run_id: bson::oid::ObjectId) -> Result<ComparisonRun, AppError>;
+
+    /// The last run of the pair that went through, older runs or ones still
+    /// going being ignored.
+    async fn find_latest_run(
+        &self,
+        stack_a: &str,
+        stack_b: &str,
+    ) -> Result<Option<ComparisonRun>, AppError>;
+
+    /// Runs matching `filter`, newest first.
+    async fn list_runs(&self, filter: &models::RunFilter) -> Result<Vec<ComparisonRun>, AppError>;
+}
+
+#[derive(Clone)]
+pub struct RunCollection {
+    pub collection: mongodb::Collection<ComparisonRun>,
+}
+
+impl RunCollection {
+    pub async fn init(database: &mongodb::Database) -> Self {
+        let collection = database.collection::<ComparisonRun>("runs");
+        RunCollection { collection }
+    }
+
+    pub async fn create_indexes(&self) -> Result<(), AppError> {
+        let indexes = vec![
+            IndexModel::builder()
+                .keys(doc! {"stack_a": 1, "stack_b": 1, "started_at": -1})
+                .build(),
+            IndexModel::builder().keys(doc! {"started_at": -1}).build(),
+        ];
+        self.collection.create_indexes(indexes, None).await?;
+        Ok(())
+    }
+
+    /// Fails the runs still going long after they started, whose instance
+    /// stopped before finishing them.
+    pub async fn fail_stale_runs(&self) -> Result<u64, AppError> {
+        let started_before = SystemTime::now()
+            .checked_sub(STALE_RUN_AGE)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let result = self
+            .collection
+            .update_many(
+                doc! {
+                  "status": to_bson(&RunStatus::Running)?,
+                  "started_at": {"$lt": bson::DateTime::from(started_before)},
+                },
+                doc! {"$set": {
+                  "status": to_bson(&RunStatus::Failed)?,
+                  "finished_at": bson::DateTime::now(),
+                  "error": "Interrupted before finishing",
+                }},
+                None,
+            )
+            .await?;
+        Ok(result.modified_count)
+    }
+}
+
+#[rocket::async_trait]
+impl RunStore for RunCollection {
+    async fn start_run(
+        &self,
+        payload: &models::ComputeAllDiffPayload,
+    ) -> Result<bson::oid::ObjectId, AppError> {
+        match self.fail_stale_runs().await {
+            Ok(0) => {}
+            Ok(failed) => warn!("Failed {} runs interrupted before finishing", failed),
+            Err(e) => error!("Failed to fail stale runs: {}", e),
+        }
+        let run = ComparisonRun {
+            id: None,
+            stack_a: payload.stack_a.clone(),
+            stack_b: payload.stack_b.clone(),
+            ref_a: payload.ref_a.clone(),
+            ref_b: payload.ref_b.clone(),
+            status: RunStatus::Running,
+            started_at: bson::DateTime::now(),
+            finished_at: None,
+            counts: None,
+            error: None,
+        };
+        let inserted_result = self.collection.insert_one(run, None).await?;
+        inserted_result
+            .inserted_id
+            .as_object_id()
+            .ok_or(AppError::Internal("Can't deserialize id".to_string()))
+    }
+
+    async fn finish_run(
+        &self,
+        run_id: bson::oid::ObjectId,
+        result: &Result<models::ComputeAllDiffResponse, AppError>,
+    ) -> Result<(), AppError> {
+        let mut update = doc! {
+          "finished_at": bson::DateTime::now(),
+        };
+        match result {
+            Ok(response) => {
+                update.insert("status", to_bson(&RunStatus::Succeeded)?);
+                update.insert("counts", to_bson(&run_counts(response))?);
+            }
+            Err(e) => {
+                update.insert("status", to_bson(&RunStatus::Failed)?);
+                update.insert("error", e.to_string());
+            }
+        }
+
+        self.collection
+            .update_one(doc! {"_id": run_id}, doc! {"$set": update}, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_run_by_id(&self, run_id: bson::oid::ObjectId) -> Result<ComparisonRun, AppError> {
+        match self.collection.find_one(doc! {"_id": run_id}, None).await {
+            Ok(Some(run)) => Ok(run),
+            Ok(None) => Err(AppError::NotFound(format!("No run {}", run_id))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn find_latest_run(
+        &self,
+        stack_a: &str,
+        stack_b: &str,
+    ) -> Result<Option<ComparisonRun>, AppError> {
+        let options = FindOneOptions::builder()
+            .sort(doc! {"started_at": -1})
+            .build();
+        self.collection
+            .find_one(
+                doc! {
+                  "stack_a": stack_a,
+                  "stack_b": stack_b,
+                  "status": to_bson(&RunStatus::Succeeded)?,
+                },
+                options,
+            )
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn list_runs(&self, filter: &models::RunFilter) -> Result<Vec<ComparisonRun>, AppError> {
+        let mut query = doc! {};
+        if let Some(stack_a) = &filter.stack_a {
+            query.insert("stack_a", stack_a);
+        }
+        if let Some(stack_b) = &filter.stack_b {
+            query.insert("stack_b", stack_b);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! {"started_at": -1})
+            .limit(run_limit(filter))
+            .build();
+
+        self.collection
+            .find(query, options)
+            .await?
+            .try_collect()
+            .await
+            .map_err(AppError::from)
+    }
+}
+
+fn run_limit(filter: &models::RunFilter) -> i64 {
+    filter
+        .limit
+        .unwrap_or(DEFAULT_RUN_LIMIT)
+        .clamp(1, MAX_RUN_LIMIT)
+}
+
+/// Keeps runs in memory, for tests of the routes recording them.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryRunStore {
+    runs: std::sync::Mutex<Vec<ComparisonRun>>,
+}
+
+#[cfg(test)]
+#[rocket::async_trait]
+impl RunStore for MemoryRunStore {
+    async fn start_run(
+        &self,
+        payload: &models::ComputeAllDiffPayload,
+    ) -> Result<bson::oid::ObjectId, AppError> {
+        let id = bson::oid::ObjectId::new();
+        self.runs.lock().unwrap().push(ComparisonRun {
+            id: Some(id),
+            stack_a: payload.stack_a.clone(),
+            stack_b: payload.stack_b.clone(),
+            ref_a: payload.ref_a.clone(),
+            ref_b: payload.ref_b.clone(),
+            status: RunStatus::Running,
+            started_at: bson::DateTime::now(),
+            finished_at: None,
+            counts: None,
+            error: None,
+        });
+        Ok(id)
+    }
+
+    async fn finish_run(
+        &self,
+        run_id: bson::oid::ObjectId,
+        result: &Result<models::ComputeAllDiffResponse, AppError>,
+    ) -> Result<(), AppError> {
+        let mut runs = self.runs.lock().unwrap();
+        let run = runs
+            .iter_mut()
+            .find(|run| run.id == Some(run_id))
+            .ok_or_else(|| AppError::NotFound(format!("No run {}", run_id)))?;
+        run.finished_at = Some(bson::DateTime::now());
+        match result {
+            Ok(response) => {
+                run.status = RunStatus::Succeeded;
+                run.counts = Some(run_counts(response));
+            }
+            Err(e) => {
+                run.status = RunStatus::Failed;
+                run.error = Some(e.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    async fn find_run_by_id(&self, run_id: bson::oid::ObjectId) -> Result<ComparisonRun, AppError> {
+        self.runs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|run| run.id == Some(run_id))
+            .cloned()
+            .ok_or_else(|| AppError::NotFound(format!("No run {}", run_id)))
+    }
+
+    async fn find_latest_run(
+        &self,
+        stack_a: &str,
+        stack_b: &str,
+    ) -> Result<Option<ComparisonRun>, AppError> {
+        Ok(self
+            .runs
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|run| {
+                run.stack_a == stack_a
+                    && run.stack_b == stack_b
+                    && run.status == RunStatus::Succeeded
+            })
+            .cloned())
+    }
+
+    async fn list_runs(&self, filter: &models::RunFilter) -> Result<Vec<ComparisonRun>, AppError> {
+        Ok(self
+            .runs
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|run| {
+                filter
+                    .stack_a
+                    .as_ref()
+                    .is_none_or(|stack_a| &run.stack_a == stack_a)
+            })
+            .filter(|run| {
+                filter
+                    .stack_b
+                    .as_ref()
+                    .is_none_or(|stack_b| &run.stack_b == stack_b)
+            })
+            .take(run_limit(filter) as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Where a page ended, as the sort value and the id of its last diff, which
+/// breaks ties.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct DiffCursor {
+    value: Bson,
+    id: bson::oid::ObjectId,
+}
+
+impl DiffCursor {
+    fn after(diff: &models::FileDiff, sort: DiffSort) -> Option<Self> {
+        let value = match sort {
+            DiffSort::NewestFirst | DiffSort::OldestFirst => {
+                diff.created_at.map(Bson::DateTime).unwrap_or(Bson::Null)
+            }
+            DiffSort::FileAsc | DiffSort::FileDesc => Bson::String(diff.file.clone()),
+        };
+        Some(DiffCursor {
+            value,
+            id: diff.id?,
+        })
+    }
+
+    fn encode(&self) -> Result<String, AppError> {
+        let bytes = bson::to_vec(self).map_err(|e| AppError::Internal(e.to_string()))?;
+        Ok(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+    }
+
+    fn decode(cursor: &str) -> Result<Self, AppError> {
+        base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|bytes| bson::from_slice(&bytes).ok())
+            .ok_or_else(|| AppError::BadRequest(format!("Invalid cursor {}", cursor)))
+    }
+}
+
+/// The sorted field and its direction, ids breaking ties in the same direction.
+fn sort_key(sort: DiffSort) -> (&'static str, i32) {
+    match sort {
+        DiffSort::NewestFirst => ("created_at", -1),
+        DiffSort::OldestFirst => ("created_at", 1),
+        DiffSort::FileAsc => ("file", 1),
+        DiffSort::FileDesc => ("file", -1),
+    }
+}
+
+fn sort_document(sort: DiffSort) -> Document {
+    let (field, direction) = sort_key(sort);
+    doc! {field: direction, "_id": direction}
+}
+
+fn category_field(category: DiffCategory) -> &'static str {
+    match category {
+        DiffCategory::LeftNotRight => "left_not_right",
+        DiffCategory::RightNotLeft => "right_not_left",
+        DiffCategory::SameKeyDiffValue => "same_key_diff_value",
     }
 }
+
+fn diff_filter(
+    stack_a: &str,
+    stack_b: &str,
+    query: &DiffQuery,
+    cursor: Option<&DiffCursor>,
+) -> Document {
+    let mut filter = doc! {
+      "stack_a": stack_a,
+      "stack_b": stack_b,
+    };
+    let mut clauses: Vec<Document> = Vec::new();
+
+    if let Some(reviewed) = query.reviewed {
+        filter.insert("reviewed", reviewed.to_string());
+    }
+    if let Some(file_prefix) = &query.file_prefix {
+        // An anchored prefix regex can use the index on file
+        filter.insert(
+            "file",
+            doc! {"$regex": format!("^{}", escape_regex(file_prefix))},
+        );
+    }
+    let mut created_at = doc! {};
+    if let Some(created_after) = query.created_after {
+        created_at.insert(
+            "$gte",
+            bson::DateTime::from_millis(created_after.timestamp_millis()),
+        );
+    }
+    if let Some(created_before) = query.created_before {
+        created_at.insert(
+            "$lt",
+            bson::DateTime::from_millis(created_before.timestamp_millis()),
+        );
+    }
+    if !created_at.is_empty() {
+        filter.insert("created_at", created_at);
+    }
+    if !query.non_empty.is_empty() {
+        let non_empty: Vec<Document> = query
+            .non_empty
+            .iter()
+            .map(|category| doc! {format!("{}.0", category_field(*category)): {"$exists": true}})
+            .collect();
+        clauses.push(doc! {"$or": non_empty});
+    }
+    if let Some(cursor) = cursor {
+        clauses.push(after_cursor(cursor, query.sort));
+    }
+    if !clauses.is_empty() {
+        filter.insert("$and", clauses);
+    }
+    filter
+}
+
+/// The diffs sorted after `cursor`.
+fn after_cursor(cursor: &DiffCursor, sort: DiffSort) -> Document {
+    let (field, direction) = sort_key(sort);
+    let operator = if direction < 0 { "$lt" } else { "$gt" };
+    doc! {
+      "$or": [
+        {field: {operator: cursor.value.clone()}},
+        {field: cursor.value.clone(), "_id": {operator: cursor.id}},
+      ]
+    }
+}
+
+/// Restricts `filter` to the diffs after the cursor of `page`, if it has one.
+fn with_cursor(filter: Document, page: &Page, sort: DiffSort) -> Result<Document, AppError> {
+    match page.cursor.as_deref().map(DiffCursor::decode).transpose()? {
+        Some(cursor) => Ok(doc! {"$and": [filter, after_cursor(&cursor, sort)]}),
+        None => Ok(filter),
+    }
+}
+
+/// The review state once toggled, diffs stored without one not being reviewed.
+fn toggled_review(reviewed: Option<&str>) -> &'static str {
+    if reviewed == Some("true") {
+        "false"
+    } else {
+        "true"
+    }
+}
+
+/// The size of a page, which every listing is cut in.
+fn page_limit(page: &Page) -> i64 {
+    page.limit
+        .unwrap_or(DEFAULT_DIFF_LIMIT)
+        .clamp(1, MAX_DIFF_LIMIT)
+}
+
+/// Cuts `diffs`, fetched with one more than `limit`, to a page along with the
+/// cursor of the next one.
+fn cut_page(
+    mut diffs: Vec<models::FileDiff>,
+    limit: i64,
+    sort: DiffSort,
+) -> Result<(Vec<models::FileDiff>, Option<String>), AppError> {
+    if diffs.len() as i64 <= limit {
+        return Ok((diffs, None));
+    }
+    diffs.truncate(limit as usize);
+    let next_cursor = diffs
+        .last()
+        .and_then(|diff| DiffCursor::after(diff, sort))
+        .map(|cursor| cursor.encode())
+        .transpose()?;
+    Ok((diffs, next_cursor))
+}
+
+/// Diffs of the same file with the same paths in every category, in any order.
+fn same_paths_filter(diff: &models::FileDiff) -> Document {
+    let mut filter = doc! {"file": &diff.file};
+    for (field, paths) in [
+        ("left_not_right", &diff.left_not_right),
+        ("right_not_left", &diff.right_not_left),
+        ("same_key_diff_value", &diff.same_key_diff_value),
+    ] {
+        let mut same_paths = doc! {"$size": paths.len() as i64};
+        // `$all` of nothing matches no document
+        if !paths.is_empty() {
+            same_paths.insert("$all", paths.clone());
+        }
+        filter.insert(field, same_paths);
+    }
+    filter
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        if "\\.+*?()|[]{}^$".contains(character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+fn run_counts(response: &models::ComputeAllDiffResponse) -> RunCounts {
+    let mut counts = RunCounts {
+        files_with_diff: response.files_with_diff.len() as u32,
+        ..Default::default()
+    };
+    for diff in &response.files_with_diff {
+        counts.left_not_right += diff.left_not_right.len() as u32;
+        counts.right_not_left += diff.right_not_left.len() as u32;
+        counts.same_key_diff_value += diff.same_key_diff_value.len() as u32;
+    }
+    counts
+}
+
+fn to_bson<T: serde::Serialize>(value: &T) -> Result<bson::Bson, AppError> {
+    bson::to_bson(value).map_err(|e| AppError::Internal(e.to_string()))
+}
+
 pub struct MongoDb {
+    #[allow(dead_code)]
     pub client: mongodb::Client,
     pub database: mongodb::Database,
 }
@@ -156,6 +851,16 @@ impl MongoDb {
     }
 }
 
+/// Legacy diffs predate runs and were named by the folder of their file. Files
+/// matched by pattern have a path, unless at the root of the stack where a
+/// YAML one can't be a folder.
+fn legacy_files_filter() -> Document {
+    doc! {
+        "run_id": {"$exists": false},
+        "file": {"$not": {"$regex": "/|\\.ya?ml$"}},
+    }
+}
+
 pub struct MongoDbFairing;
 #[rocket::async_trait]
 impl Fairing for MongoDbFairing {
@@ -169,6 +874,244 @@ impl Fairing for MongoDbFairing {
     async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
         let mongodb = MongoDb::init().await;
         let mongodb_collection = DiffCollection::init(&mongodb.database).await;
-        Ok(rocket.manage(mongodb_collection))
+        let run_collection = RunCollection::init(&mongodb.database).await;
+        if let Err(e) = mongodb_collection.create_indexes().await {
+            error!("Failed to create the indexes of diffs: {}", e);
+        }
+        if let Err(e) = run_collection.create_indexes().await {
+            error!("Failed to create the indexes of runs: {}", e);
+        }
+        match run_collection.fail_stale_runs().await {
+            Ok(0) => {}
+            Ok(failed) => warn!("Failed {} runs interrupted before finishing", failed),
+            Err(e) => error!("Failed to fail stale runs: {}", e),
+        }
+        match mongodb_collection
+            .migrate_legacy_files(&mongodb.database)
+            .await
+        {
+            Ok(0) => {}
+            Ok(migrated) => info!("Named the file of {} legacy diffs by its path", migrated),
+            Err(e) => error!("Failed to migrate the files of legacy diffs: {}", e),
+        }
+        if let Some(github_client) = rocket.state::<Arc<GithubClient>>() {
+            github_client.content_cache().persist_in(&mongodb.database);
+            if let Err(e) = github_client.content_cache().create_indexes().await {
+                error!("Failed to create the indexes of the Github cache: {}", e);
+            }
+        }
+        let runs: Arc<dyn RunStore> = Arc::new(run_collection);
+        Ok(rocket.manage(mongodb_collection).manage(runs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_diff(
+        file: &str,
+        left_not_right: &[&str],
+        same_key_diff_value: &[&str],
+    ) -> models::FileDiff {
+        models::FileDiff {
+            id: None,
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+            file: file.to_string(),
+            left_not_right: left_not_right.iter().map(|p| p.to_string()).collect(),
+            right_not_left: Vec::new(),
+            same_key_diff_value: same_key_diff_value.iter().map(|p| p.to_string()).collect(),
+            sha_a: None,
+            sha_b: None,
+            run_id: None,
+            reviewed: None,
+            pr_url: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn pattern_diffs_are_not_taken_for_legacy_ones() {
+        // `configs/svc/values.json` and `configs/svc/config.yaml.tmpl` have a path,
+        // `config.yml` is a file at the root, diffs of a run postdate patterns
+        assert_eq!(
+            legacy_files_filter(),
+            doc! {
+                "run_id": {"$exists": false},
+                "file": {"$not": {"$regex": "/|\\.ya?ml$"}},
+            }
+        );
+    }
+
+    #[test]
+    fn diff_queries_are_pushed_down() {
+        let query = DiffQuery {
+            reviewed: Some(false),
+            file_prefix: Some("configs/a.b".to_string()),
+            created_after: Some("2024-01-01T00:00:00Z".parse().unwrap()),
+            non_empty: vec![DiffCategory::SameKeyDiffValue],
+            sort: DiffSort::FileAsc,
+            ..Default::default()
+        };
+        let cursor = DiffCursor {
+            value: Bson::String("configs/a.b/x.yml".to_string()),
+            id: bson::oid::ObjectId::parse_str("65a000000000000000000000").unwrap(),
+        };
+
+        assert_eq!(
+            diff_filter("stack-a", "stack-b", &query, Some(&cursor)),
+            doc! {
+                "stack_a": "stack-a",
+                "stack_b": "stack-b",
+                "reviewed": "false",
+                "file": {"$regex": "^configs/a\\.b"},
+                "created_at": {"$gte": bson::DateTime::from_millis(1_704_067_200_000)},
+                "$and": [
+                    {"$or": [{"same_key_diff_value.0": {"$exists": true}}]},
+                    {"$or": [
+                        {"file": {"$gt": "configs/a.b/x.yml"}},
+                        {"file": "configs/a.b/x.yml", "_id": {"$gt": cursor.id}},
+                    ]},
+                ],
+            }
+        );
+        assert_eq!(
+            sort_document(DiffSort::NewestFirst),
+            doc! {"created_at": -1, "_id": -1}
+        );
+    }
+
+    #[test]
+    fn reviewed_diffs_are_matched_on_their_paths() {
+        let diff = file_diff("configs/svc/config.yml", &["/a", "/b"], &[]);
+
+        assert_eq!(
+            same_paths_filter(&diff),
+            doc! {
+                "file": "configs/svc/config.yml",
+                "left_not_right": {"$size": 2_i64, "$all": ["/a", "/b"]},
+                "right_not_left": {"$size": 0_i64},
+                "same_key_diff_value": {"$size": 0_i64},
+            }
+        );
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = DiffCursor {
+            value: Bson::DateTime(bson::DateTime::from_millis(1_704_067_200_000)),
+            id: bson::oid::ObjectId::new(),
+        };
+
+        assert_eq!(
+            DiffCursor::decode(&cursor.encode().unwrap()).unwrap(),
+            cursor
+        );
+        assert!(matches!(
+            DiffCursor::decode("not a cursor"),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn listings_are_always_paginated() {
+        let page = |limit: Option<i64>, cursor: Option<&str>| Page {
+            limit,
+            cursor: cursor.map(str::to_string),
+        };
+
+        assert_eq!(page_limit(&page(None, None)), DEFAULT_DIFF_LIMIT);
+        assert_eq!(page_limit(&page(Some(10), None)), 10);
+        assert_eq!(page_limit(&page(None, Some("c"))), DEFAULT_DIFF_LIMIT);
+        assert_eq!(page_limit(&page(Some(10_000), None)), MAX_DIFF_LIMIT);
+        assert_eq!(page_limit(&page(Some(0), None)), 1);
+
+        let filter = doc! {"run_id": 1};
+        assert_eq!(
+            with_cursor(filter.clone(), &page(Some(10), None), DiffSort::FileAsc).unwrap(),
+            filter
+        );
+        let cursor = DiffCursor {
+            value: Bson::String("a.yml".to_string()),
+            id: bson::oid::ObjectId::new(),
+        };
+        assert_eq!(
+            with_cursor(
+                filter.clone(),
+                &page(None, Some(&cursor.encode().unwrap())),
+                DiffSort::FileAsc
+            )
+            .unwrap(),
+            doc! {"$and": [filter, after_cursor(&cursor, DiffSort::FileAsc)]}
+        );
+    }
+
+    #[test]
+    fn diffs_without_review_state_are_toggled_to_reviewed() {
+        assert_eq!(toggled_review(Some("false")), "true");
+        assert_eq!(toggled_review(Some("true")), "false");
+        assert_eq!(toggled_review(None), "true");
+    }
+
+    #[test]
+    fn pages_link_the_next_one_when_more_diffs_remain() {
+        let diffs = |count: usize| {
+            (0..count)
+                .map(|index| models::FileDiff {
+                    id: Some(bson::oid::ObjectId::new()),
+                    ..file_diff(&format!("configs/{:03}.yml", index), &[], &[])
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (first_page, next_cursor) = cut_page(
+            diffs(DEFAULT_DIFF_LIMIT as usize + 1),
+            DEFAULT_DIFF_LIMIT,
+            DiffSort::FileAsc,
+        )
+        .unwrap();
+        assert_eq!(first_page.len(), DEFAULT_DIFF_LIMIT as usize);
+        let cursor = DiffCursor::decode(&next_cursor.unwrap()).unwrap();
+        assert_eq!(cursor.value, Bson::String("configs/099.yml".to_string()));
+        assert_eq!(cursor.id, first_page[99].id.unwrap());
+
+        let (last_page, next_cursor) = cut_page(
+            diffs(DEFAULT_DIFF_LIMIT as usize),
+            DEFAULT_DIFF_LIMIT,
+            DiffSort::FileAsc,
+        )
+        .unwrap();
+        assert_eq!(last_page.len(), DEFAULT_DIFF_LIMIT as usize);
+        assert_eq!(next_cursor, None);
+    }
+
+    #[test]
+    fn run_counts_add_up_every_file() {
+        let response = models::ComputeAllDiffResponse {
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+            run_id: None,
+            files_with_diff: vec![
+                file_diff("config.yml", &["/*"], &[]),
+                file_diff("config.yml", &["/a"], &["/b", "/c"]),
+            ],
+            compared_files: Vec::new(),
+        };
+
+        assert_eq!(
+            run_counts(&response),
+            RunCounts {
+                files_with_diff: 2,
+                left_not_right: 2,
+                right_not_left: 0,
+                same_key_diff_value: 2,
+            }
+        );
+        assert_eq!(
+            to_bson(&RunStatus::Succeeded).unwrap(),
+            bson::Bson::from("succeeded")
+        );
     }
 }
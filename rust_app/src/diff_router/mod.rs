@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::SystemTime;
@@ -6,181 +7,195 @@ use bson::doc;
 use bson::oid::ObjectId;
 use chrono::Utc;
 use github::GithubClient;
-use rocket::http::Status;
-use rocket::response::{self, Responder, Response};
+use rocket::futures::{stream, try_join, StreamExt};
 use rocket::serde::json::Json;
-use rocket::Request;
 use rocket::State;
 
 use super::models;
-use crate::db::DiffCollection;
-use crate::github::{self, ConfigError, SerializableContent};
+use crate::blame;
+use crate::db::{DiffCollection, RunStore};
+use crate::error::AppError;
+use crate::github::{self, ConfigError};
+use crate::reconcile;
+use crate::report::ci::{CiFormat, CiReport};
+use crate::report::{DiffReport, ReportFormat};
+use crate::source::patterns::FilePatterns;
+use crate::source::{ConfigSource, SourceEntry};
 use crate::utils::compare_yaml_strings;
 
-pub struct HttpCustomError {
-    status: Status,
-    message: String,
-}
+const MAX_CONCURRENT_BLOB_DOWNLOADS: usize = 8;
 
 pub struct AppConfig {
-    pub folder_a: String,
-    pub folder_b: String,
-}
-
-impl<'r, 'o: 'r> Responder<'r, 'o> for HttpCustomError {
-    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
-        Response::build()
-            .status(self.status)
-            .sized_body(self.message.len(), std::io::Cursor::new(self.message))
-            .ok()
-    }
+    pub file_patterns: FilePatterns,
 }
 
 #[get("/getOneDiffById/<diff_id>")]
 pub async fn get_diff_by_id(
     diff_id: &str,
     db: &State<DiffCollection>,
-) -> Result<Json<models::GetOneDiffResponse>, HttpCustomError> {
-    let object_id = match ObjectId::from_str(&diff_id) {
-        Ok(oid) => oid,
-        Err(e) => {
-            return Err(HttpCustomError {
-                status: Status::BadRequest,
-                message: e.to_string(),
-            })
-        }
-    };
+) -> Result<Json<models::GetOneDiffResponse>, AppError> {
+    let object_id = ObjectId::from_str(diff_id)?;
 
-    match db.find_diff_by_id(object_id).await {
-        Ok(file_diff) => Ok(Json(models::GetOneDiffResponse { diff: file_diff })),
-        Err(e) => Err(HttpCustomError {
-            status: Status::NotFound,
-            message: e.to_string(),
-        }),
-    }
+    db.find_diff_by_id(object_id)
+        .await
+        .map(|file_diff| Json(models::GetOneDiffResponse { diff: file_diff }))
 }
 
 #[post("/insertOneDiff", data = "<payload>")]
 pub async fn insert_diff(
     payload: Json<models::DiffBaseSchema>,
     db: &State<DiffCollection>,
-) -> Result<Json<models::FileDiff>, HttpCustomError> {
+) -> Result<Json<models::FileDiff>, AppError> {
     let payload = payload.into_inner();
 
-    let inserted_result = db
-        .insert_diff_from_diff_base_schema(payload)
-        .await
-        .map_err(|e| HttpCustomError {
-            status: Status::InternalServerError,
-            message: e.to_string(),
-        })?;
+    let inserted_result = db.insert_diff_from_diff_base_schema(payload).await?;
 
     let inserted_id = inserted_result
         .inserted_id
         .as_object_id()
-        .ok_or(HttpCustomError {
-            status: Status::InternalServerError,
-            message: "Can't deserialize id".to_string(),
-        })?;
+        .ok_or(AppError::Internal("Can't deserialize id".to_string()))?;
 
-    db.find_diff_by_id(inserted_id)
-        .await
-        .map(Json)
-        .map_err(|e| HttpCustomError {
-            status: Status::InternalServerError,
-            message: e.to_string(),
-        })
+    db.find_diff_by_id(inserted_id).await.map(Json)
 }
 
-#[post("/getLatestDiffsFromStacks", data = "<payload>")]
+#[post("/getLatestDiffsFromStacks?<page..>", data = "<payload>")]
 pub async fn get_latest_diffs_from_stacks(
     payload: Json<models::GetAllDiffsPayload>,
+    page: models::Page,
+    format: ReportFormat,
     mongo: &State<DiffCollection>,
-) -> Result<Json<models::GetAllDiffsResponse>, HttpCustomError> {
+    runs: &State<Arc<dyn RunStore>>,
+) -> Result<DiffReport, AppError> {
     let payload = payload.into_inner();
+    let page = page.or(payload.query.page);
+
+    // Pairs last compared before runs were recorded only have their diffs
+    let (files_with_diff, next_cursor) = match runs
+        .find_latest_run(&payload.stack_a, &payload.stack_b)
+        .await?
+    {
+        Some(run) => {
+            let run_id = run
+                .id
+                .ok_or(AppError::Internal("Run without id".to_string()))?;
+            mongo.get_diffs_by_run(run_id, &page).await?
+        }
+        None => {
+            let (diffs, next_cursor) = mongo
+                .get_latest_diffs_without_run(&payload.stack_a, &payload.stack_b, &page)
+                .await?;
+            if diffs.is_empty() && page.cursor.is_none() {
+                error!(
+                    "No comparison found with {} and {}",
+                    &payload.stack_a, &payload.stack_b
+                );
+                return Err(AppError::NotFound(
+                    "Couldn't get diff for these stacks".to_string(),
+                ));
+            }
+            (diffs, next_cursor)
+        }
+    };
 
-    let mut diffs: Vec<models::FileDiff> = mongo
-        .get_all_diffs_from_stacks(&payload)
-        .await
-        .map_err(|e| HttpCustomError {
-            status: Status::NotFound,
-            message: e.to_string(),
-        })?;
-
-    if diffs.is_empty() {
-        error!(
-            "No comparison found with {} and {}",
-            &payload.stack_a, &payload.stack_b
-        );
-        return Err(HttpCustomError {
-            status: Status::NotFound,
-            message: "Couldn't get diff for these stacks".to_string(),
-        });
-    }
-
-    diffs.sort_by(|a, b| b.created_at.cmp(&a.created_at));
-    let latest_date = diffs.first().unwrap().created_at.unwrap();
+    Ok(DiffReport {
+        format,
+        page,
+        response: models::GetAllDiffsResponse {
+            stack_a: payload.stack_a,
+            stack_b: payload.stack_b,
+            files_with_diff,
+            next_cursor,
+        },
+    })
+}
 
-    let latest_diffs = diffs
-        .into_iter()
-        .filter(|diff| diff.created_at == Some(latest_date))
-        .collect();
+#[get("/runs?<filter..>")]
+pub async fn list_runs(
+    filter: models::RunFilter,
+    runs: &State<Arc<dyn RunStore>>,
+) -> Result<Json<Vec<models::ComparisonRun>>, AppError> {
+    runs.list_runs(&filter).await.map(Json)
+}
 
-    Ok(Json(models::GetAllDiffsResponse {
-        stack_a: payload.stack_a,
-        stack_b: payload.stack_b,
-        files_with_diff: latest_diffs,
-    }))
+/// The diffs a run found, by file.
+#[get("/runs/<run_id>/diffs?<page..>")]
+pub async fn get_run_diffs(
+    run_id: &str,
+    page: models::Page,
+    format: ReportFormat,
+    mongo: &State<DiffCollection>,
+    runs: &State<Arc<dyn RunStore>>,
+) -> Result<DiffReport, AppError> {
+    let run_id = ObjectId::from_str(run_id)?;
+    let run = runs.find_run_by_id(run_id).await?;
+    let (files_with_diff, next_cursor) = mongo.get_diffs_by_run(run_id, &page).await?;
+
+    Ok(DiffReport {
+        format,
+        page,
+        response: models::GetAllDiffsResponse {
+            stack_a: run.stack_a,
+            stack_b: run.stack_b,
+            files_with_diff,
+            next_cursor,
+        },
+    })
 }
 
-#[post("/getAllDiffsFromStacks", data = "<payload>")]
+/// One page of the diffs of a pair, filtered and sorted as the payload asks.
+#[post("/getAllDiffsFromStacks?<page..>", data = "<payload>")]
 pub async fn get_all_diffs_from_stacks(
     payload: Json<models::GetAllDiffsPayload>,
+    page: models::Page,
+    format: ReportFormat,
     mongo: &State<DiffCollection>,
-) -> Result<Json<models::GetAllDiffsResponse>, HttpCustomError> {
-    let payload_unjson = payload.into_inner();
-    mongo
-        .get_all_diffs_from_stacks(&payload_unjson)
-        .await
-        .map(|s| models::GetAllDiffsResponse {
-            stack_a: payload_unjson.stack_a,
-            stack_b: payload_unjson.stack_b,
-            files_with_diff: s,
-        })
-        .map(Json)
-        .map_err(|e| HttpCustomError {
-            status: Status::NotFound,
-            message: e.to_string(),
-        })
+) -> Result<DiffReport, AppError> {
+    let mut payload = payload.into_inner();
+    payload.query.page = page.or(payload.query.page);
+    let (files_with_diff, next_cursor) = mongo
+        .find_diffs(&payload.stack_a, &payload.stack_b, &payload.query)
+        .await?;
+
+    Ok(DiffReport {
+        format,
+        page: payload.query.page,
+        response: models::GetAllDiffsResponse {
+            stack_a: payload.stack_a,
+            stack_b: payload.stack_b,
+            files_with_diff,
+            next_cursor,
+        },
+    })
 }
 
 #[post("/getConfigsFromStacks", data = "<payload>")]
 pub async fn get_configs_from_stacks_name(
     payload: Json<models::GetConfigsFromStacksPayload>,
-    github_client: &State<Arc<GithubClient>>,
-) -> Result<Json<models::GetConfigsFromStacksResponse>, HttpCustomError> {
+    source: &State<Arc<dyn ConfigSource>>,
+) -> Result<Json<models::GetConfigsFromStacksResponse>, AppError> {
     let payload = payload.into_inner();
 
-    let config_a = github_client
-        .get_config_from_stack_and_file_string(&payload.stack_a, &payload.file)
-        .await
-        .map_err(|e| HttpCustomError {
-            status: Status::NotFound,
-            message: e.to_string(),
-        })?;
+    let sha_a = source
+        .resolve_revision(&payload.stack_a, payload.ref_a.as_deref())
+        .await?;
+    let sha_b = source
+        .resolve_revision(&payload.stack_b, payload.ref_b.as_deref())
+        .await?;
 
-    let config_b = github_client
-        .get_config_from_stack_and_file_string(&payload.stack_b, &payload.file)
-        .await
-        .map_err(|e| HttpCustomError {
-            status: Status::NotFound,
-            message: e.to_string(),
-        })?;
+    let config_a = source
+        .read_file(&payload.stack_a, &payload.file, &sha_a)
+        .await?;
+
+    let config_b = source
+        .read_file(&payload.stack_b, &payload.file, &sha_b)
+        .await?;
 
     let response = models::GetConfigsFromStacksResponse {
         stack_a: payload.stack_a,
         stack_b: payload.stack_b,
         file: payload.file,
+        sha_a,
+        sha_b,
         config_a,
         config_b,
     };
@@ -191,121 +206,212 @@ pub async fn get_configs_from_stacks_name(
 #[post("/computeAllDiffs", data = "<payload>")]
 pub async fn compute_diff_for_all_files(
     payload: Json<models::ComputeAllDiffPayload>,
-    github_client: &State<Arc<GithubClient>>,
+    source: &State<Arc<dyn ConfigSource>>,
     app_config: &State<Arc<AppConfig>>,
     mongo: &State<DiffCollection>,
-) -> Result<Json<models::ComputeAllDiffResponse>, HttpCustomError> {
+    runs: &State<Arc<dyn RunStore>>,
+) -> Result<Json<models::ComputeAllDiffResponse>, AppError> {
+    compute_all_diffs(
+        payload.into_inner(),
+        source.as_ref(),
+        app_config,
+        mongo,
+        runs.as_ref(),
+    )
+    .await
+    .map(Json)
+}
+
+#[post("/computeAllDiffs/ci?<format>", data = "<payload>")]
+pub async fn compute_diff_for_all_files_ci(
+    payload: Json<models::ComputeAllDiffPayload>,
+    format: CiFormat,
+    source: &State<Arc<dyn ConfigSource>>,
+    app_config: &State<Arc<AppConfig>>,
+    mongo: &State<DiffCollection>,
+) -> Result<CiReport, AppError> {
+    // A CI gate only reads, its diffs aren't stored as a run
+    let response = diff_stacks(payload.into_inner(), source.as_ref(), app_config).await?;
+
+    // Drift that matches an already reviewed diff for the same file doesn't fail the CI
+    let drifting_diffs: Vec<&models::FileDiff> = response
+        .files_with_diff
+        .iter()
+        .filter(|diff| {
+            !diff.left_not_right.is_empty()
+                || !diff.right_not_left.is_empty()
+                || !diff.same_key_diff_value.is_empty()
+        })
+        .collect();
+    let reviewed_diffs = mongo
+        .get_reviewed_diffs_like(&response.stack_a, &response.stack_b, &drifting_diffs)
+        .await?;
+
+    Ok(CiReport {
+        format,
+        response,
+        reviewed_diffs,
+    })
+}
+
+pub(crate) async fn compute_all_diffs(
+    payload: models::ComputeAllDiffPayload,
+    source: &dyn ConfigSource,
+    app_config: &AppConfig,
+    mongo: &DiffCollection,
+    runs: &dyn RunStore,
+) -> Result<models::ComputeAllDiffResponse, AppError> {
+    // Stacks or refs that don't exist aren't worth a failed run
+    let (sha_a, sha_b) = resolve_revisions(&payload, source).await?;
+    let run_id = runs.start_run(&payload).await?;
+    let result = store_run_diffs(run_id, payload, (sha_a, sha_b), source, app_config, mongo).await;
+    runs.finish_run(run_id, &result).await?;
+    result
+}
+
+async fn store_run_diffs(
+    run_id: ObjectId,
+    payload: models::ComputeAllDiffPayload,
+    (sha_a, sha_b): (String, String),
+    source: &dyn ConfigSource,
+    app_config: &AppConfig,
+    mongo: &DiffCollection,
+) -> Result<models::ComputeAllDiffResponse, AppError> {
+    let mut response = diff_revisions(payload, sha_a, sha_b, source, app_config).await?;
+    response.run_id = Some(run_id);
+    for file_diff in &mut response.files_with_diff {
+        file_diff.run_id = Some(run_id);
+        let _inserted_result = mongo.insert_diff(file_diff).await?;
+    }
+    Ok(response)
+}
+
+/// Computes the diffs between two stacks without storing them.
+pub(crate) async fn diff_stacks(
+    payload: models::ComputeAllDiffPayload,
+    source: &dyn ConfigSource,
+    app_config: &AppConfig,
+) -> Result<models::ComputeAllDiffResponse, AppError> {
+    let (sha_a, sha_b) = resolve_revisions(&payload, source).await?;
+    diff_revisions(payload, sha_a, sha_b, source, app_config).await
+}
+
+/// Every file is read at the same commit so that a run compares two consistent snapshots.
+async fn resolve_revisions(
+    payload: &models::ComputeAllDiffPayload,
+    source: &dyn ConfigSource,
+) -> Result<(String, String), AppError> {
+    Ok(try_join!(
+        source.resolve_revision(&payload.stack_a, payload.ref_a.as_deref()),
+        source.resolve_revision(&payload.stack_b, payload.ref_b.as_deref())
+    )?)
+}
+
+async fn diff_revisions(
+    payload: models::ComputeAllDiffPayload,
+    sha_a: String,
+    sha_b: String,
+    source: &dyn ConfigSource,
+    app_config: &AppConfig,
+) -> Result<models::ComputeAllDiffResponse, AppError> {
     let now = Utc::now();
     let system_time: SystemTime = now.into();
 
     let mut file_diffs: Vec<models::FileDiff> = Vec::new();
-    let payload = payload.into_inner();
 
-    let subfolders_a = github_client
-        .get_contents_for_repo(&payload.stack_a, &app_config.folder_a)
-        .await
-        .map_err(|e| HttpCustomError {
-            status: Status::NotFound,
-            message: e.to_string(),
-        })?;
-    let subfolders_b = github_client
-        .get_contents_for_repo(&payload.stack_a, &app_config.folder_b)
-        .await
-        .map_err(|e| HttpCustomError {
-            status: Status::NotFound,
-            message: e.to_string(),
-        })?;
+    let tree_a = source.list_files(&payload.stack_a, &sha_a).await?;
+    let tree_b = source.list_files(&payload.stack_b, &sha_b).await?;
 
-    let mut subfolders = subfolders_a
-        .items
-        .into_iter()
-        .filter(|content_item| content_item.r#type == "dir")
-        .collect::<Vec<SerializableContent>>();
+    // Files are paired by their path relative to the root of each stack
+    let mut paired_files: BTreeMap<&str, (Option<&SourceEntry>, Option<&SourceEntry>)> =
+        BTreeMap::new();
+    for entry_a in tree_a.matching_files(&app_config.file_patterns) {
+        paired_files.entry(&entry_a.path).or_default().0 = Some(entry_a);
+    }
+    for entry_b in tree_b.matching_files(&app_config.file_patterns) {
+        paired_files.entry(&entry_b.path).or_default().1 = Some(entry_b);
+    }
 
-    subfolders.extend(
-        subfolders_b
-            .items
-            .into_iter()
-            .filter(|content_item| content_item.r#type == "dir")
-            .collect::<Vec<SerializableContent>>(),
-    );
-
-    info!("{} folders config to check", subfolders.len());
-
-    for subfolder in subfolders {
-        info!("Getting config for file {}", &subfolder.path);
-        let content_stack_a = github_client
-            .get_config_from_stack_and_file_string(
-                &payload.stack_a,
-                &format!("{}/config-overrides.yml", &subfolder.path),
-            )
-            .await
-            .map_err(|e| {
-                error!(
-                    "Couldn't get config {} for stack_a {}",
-                    &subfolder.path, &payload.stack_a
-                );
-                HttpCustomError {
-                    status: Status::NotFound,
-                    message: e.to_string(),
-                }
-            })?;
-
-        let content_stack_b = match github_client
-            .get_config_from_stack_and_file_string(
-                &payload.stack_b,
-                &format!("{}/config-overrides.yml", &subfolder.path),
-            )
-            .await
-        {
-            Ok(content) => content,
-            Err(ConfigError::OctocrabError(_)) => {
+    info!("{} config files to check", paired_files.len());
+    let compared_files: Vec<String> = paired_files.keys().map(|file| file.to_string()).collect();
+
+    let mut files_to_compare: Vec<(String, SourceEntry, SourceEntry)> = Vec::new();
+    for (file, entries) in paired_files {
+        let (left_not_right, right_not_left) = match entries {
+            // Entry ids are content-addressed, the same id means the same content
+            (Some(entry_a), Some(entry_b))
+                if !entry_a.id.is_empty() && entry_b.id == entry_a.id =>
+            {
                 info!(
-                    "Couldn't find file {} for stack {}",
-                    &subfolder.path, &payload.stack_b
+                    "Same blob for {} in stack {} and stack {}, skipping",
+                    file, &payload.stack_a, &payload.stack_b
                 );
-                let stack_a_clone = payload.stack_a.clone();
-                let stack_b_clone = payload.stack_b.clone();
-                let file_diff_no_content = models::FileDiff {
-                    id: None,
-                    stack_a: stack_a_clone,
-                    stack_b: stack_b_clone,
-                    file: subfolder.path,
-                    left_not_right: vec!["/*".to_string()],
-                    right_not_left: Vec::new(),
-                    same_key_diff_value: Vec::new(),
-                    reviewed: Some("false".to_string()),
-                    created_at: Some(system_time.into()),
-                    updated_at: Some(system_time.into()),
-                };
-                let _inserted_result =
-                    mongo
-                        .insert_diff(&file_diff_no_content)
-                        .await
-                        .map_err(|e| HttpCustomError {
-                            status: Status::InternalServerError,
-                            message: e.to_string(),
-                        })?;
-                file_diffs.push(file_diff_no_content);
                 continue;
             }
-            Err(e) => {
-                info!(
-                    "Couldn't find file {} for stack {}",
-                    &subfolder.path,
-                    &e.to_string()
-                );
-                return Err(HttpCustomError {
-                    status: Status::InternalServerError,
-                    message: e.to_string(),
-                });
+            (Some(entry_a), Some(entry_b)) => {
+                files_to_compare.push((file.to_string(), entry_a.clone(), entry_b.clone()));
+                continue;
+            }
+            (Some(_), None) => {
+                info!("Couldn't find file {} for stack {}", file, &payload.stack_b);
+                (vec!["/*".to_string()], Vec::new())
+            }
+            (None, _) => {
+                info!("Couldn't find file {} for stack {}", file, &payload.stack_a);
+                (Vec::new(), vec!["/*".to_string()])
             }
         };
 
+        let stack_a_clone = payload.stack_a.clone();
+        let stack_b_clone = payload.stack_b.clone();
+        let file_diff_no_content = models::FileDiff {
+            id: None,
+            stack_a: stack_a_clone,
+            stack_b: stack_b_clone,
+            file: file.to_string(),
+            left_not_right,
+            right_not_left,
+            same_key_diff_value: Vec::new(),
+            sha_a: Some(sha_a.clone()),
+            sha_b: Some(sha_b.clone()),
+            run_id: None,
+            reviewed: Some("false".to_string()),
+            pr_url: None,
+            created_at: Some(system_time.into()),
+            updated_at: Some(system_time.into()),
+        };
+        file_diffs.push(file_diff_no_content);
+    }
+
+    info!("{} config file pairs to read", files_to_compare.len());
+
+    let stack_a = payload.stack_a.as_str();
+    let stack_b = payload.stack_b.as_str();
+    let (sha_a_ref, sha_b_ref) = (sha_a.as_str(), sha_b.as_str());
+    let downloaded_blobs: Vec<Result<(String, String, String), ConfigError>> =
+        stream::iter(files_to_compare)
+            .map(|(file, entry_a, entry_b)| async move {
+                let (content_stack_a, content_stack_b) = try_join!(
+                    source.read_entry(stack_a, sha_a_ref, &entry_a),
+                    source.read_entry(stack_b, sha_b_ref, &entry_b)
+                )?;
+                Ok((file, content_stack_a, content_stack_b))
+            })
+            .buffered(MAX_CONCURRENT_BLOB_DOWNLOADS)
+            .collect()
+            .await;
+
+    for downloaded_blob in downloaded_blobs {
+        let (file, content_stack_a, content_stack_b) = downloaded_blob.map_err(|e| {
+            error!("Couldn't download config blob, because {}", e);
+            AppError::from(e)
+        })?;
+
         let (left_not_right, right_not_left, same_key_same_value, same_key_diff_value) =
-            compare_yaml_strings(&content_stack_a, &content_stack_b);
+            compare_yaml_strings(&content_stack_a, &content_stack_b)
+                .map_err(|e| ConfigError::InvalidYaml(file.clone(), e))?;
 
-        info!("Computed yaml diff for file {}", &subfolder.path);
+        info!("Computed yaml diff for file {}", &file);
 
         if left_not_right.is_empty()
             && right_not_left.is_empty()
@@ -324,28 +430,119 @@ pub async fn compute_diff_for_all_files(
             id: None,
             stack_a: stack_a_clone,
             stack_b: stack_b_clone,
-            file: subfolder.path,
-            left_not_right: left_not_right,
-            right_not_left: right_not_left,
-            same_key_diff_value: same_key_diff_value,
+            file,
+            left_not_right,
+            right_not_left,
+            same_key_diff_value,
+            sha_a: Some(sha_a.clone()),
+            sha_b: Some(sha_b.clone()),
+            run_id: None,
             reviewed: Some("false".to_string()),
+            pr_url: None,
             created_at: Some(system_time.into()),
             updated_at: Some(system_time.into()),
         };
-        let _inserted_result = mongo
-            .insert_diff(&file_diff_with_content)
-            .await
-            .map_err(|e| HttpCustomError {
-                status: Status::InternalServerError,
-                message: e.to_string(),
-            })?;
         file_diffs.push(file_diff_with_content);
     }
 
-    Ok(Json(models::ComputeAllDiffResponse {
+    Ok(models::ComputeAllDiffResponse {
         stack_a: payload.stack_a,
         stack_b: payload.stack_b,
+        run_id: None,
         files_with_diff: file_diffs,
+        compared_files,
+    })
+}
+
+const DEFAULT_HISTORY_LIMIT: u8 = 20;
+
+#[post("/getFileHistory", data = "<payload>")]
+pub async fn get_file_history(
+    payload: Json<models::FileHistoryPayload>,
+    github_client: &State<Arc<GithubClient>>,
+) -> Result<Json<models::FileHistoryResponse>, AppError> {
+    let payload = payload.into_inner();
+    // One more commit than needed is fetched to diff the oldest revision against its predecessor
+    let limit = payload.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, 99);
+
+    let commits = github_client
+        .get_file_commits(
+            &payload.stack,
+            &payload.file,
+            payload.reference.as_deref(),
+            limit + 1,
+        )
+        .await?;
+
+    let github_client: &GithubClient = github_client;
+    let (stack, file) = (payload.stack.as_str(), payload.file.as_str());
+    let shas: Vec<String> = commits.iter().map(|commit| commit.sha.clone()).collect();
+    let revisions: Vec<Result<String, ConfigError>> = stream::iter(shas)
+        .map(|sha| async move {
+            github_client
+                .get_config_from_stack_and_file_string(stack, file, Some(&sha))
+                .await
+        })
+        .buffered(MAX_CONCURRENT_BLOB_DOWNLOADS)
+        .collect()
+        .await;
+
+    let mut contents: Vec<String> = Vec::with_capacity(revisions.len() + 1);
+    for (commit, revision) in commits.iter().zip(revisions) {
+        match revision {
+            Ok(content) => contents.push(content),
+            // The commit deleted the file
+            Err(ConfigError::NotFound(_)) => {
+                info!(
+                    "File {} doesn't exist at commit {} of {}",
+                    &payload.file, &commit.sha, &payload.stack
+                );
+                contents.push(String::new())
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // The whole history fits in the window, so the oldest commit created the file
+    if commits.len() <= limit as usize {
+        contents.push(String::new());
+    }
+
+    let history = commits
+        .into_iter()
+        .take(limit as usize)
+        .enumerate()
+        .map(|(index, commit)| {
+            let (removed, added, changed, error) =
+                match compare_yaml_strings(&contents[index + 1], &contents[index]) {
+                    Ok((removed, added, _same_key_same_value, changed)) => {
+                        (removed, added, changed, None)
+                    }
+                    // A broken revision only hides its own changes, not the whole history
+                    Err(e) => {
+                        let error = ConfigError::InvalidYaml(payload.file.clone(), e);
+                        warn!("Cannot diff commit {}, because {}", &commit.sha, error);
+                        (Vec::new(), Vec::new(), Vec::new(), Some(error.to_string()))
+                    }
+                };
+            let git_author = commit.commit.author;
+            models::FileHistoryEntry {
+                sha: commit.sha,
+                author: git_author.as_ref().map(|author| author.user.name.clone()),
+                author_login: commit.author.map(|author| author.login),
+                date: git_author.and_then(|author| author.date),
+                message: commit.commit.message,
+                added,
+                removed,
+                changed,
+                error,
+            }
+        })
+        .collect();
+
+    Ok(Json(models::FileHistoryResponse {
+        stack: payload.stack,
+        file: payload.file,
+        history,
     }))
 }
 
@@ -353,23 +550,427 @@ pub async fn compute_diff_for_all_files(
 pub async fn toggle_review_endpoint(
     payload: Json<models::ToggleReviewPayload>,
     mongo: &State<DiffCollection>,
-) -> Result<Json<models::ToggleReviewResponse>, HttpCustomError> {
-    let diff_id =
-        bson::oid::ObjectId::from_str(&payload.into_inner().id).map_err(|e| HttpCustomError {
-            status: Status::InternalServerError,
-            message: e.to_string(),
-        })?;
+) -> Result<Json<models::ToggleReviewResponse>, AppError> {
+    let diff_id = bson::oid::ObjectId::from_str(&payload.into_inner().id)?;
 
-    mongo
-        .toggle_review(diff_id)
-        .await
-        .map_err(|e| HttpCustomError {
-            status: Status::InternalServerError,
-            message: e.to_string(),
+    mongo.toggle_review(diff_id).await.map(|updated| {
+        Json(models::ToggleReviewResponse {
+            status: updated.matched_count.to_string(),
         })
-        .map(|updated| {
-            Json(models::ToggleReviewResponse {
-                status: updated.matched_count.to_string(),
+    })
+}
+
+#[post("/blameDiff", data = "<payload>")]
+pub async fn blame_diff(
+    payload: Json<models::BlameDiffPayload>,
+    source: &State<Arc<dyn ConfigSource>>,
+    mongo: &State<DiffCollection>,
+) -> Result<Json<models::BlameDiffResponse>, AppError> {
+    let diff_id = ObjectId::from_str(&payload.into_inner().id)?;
+    let diff = mongo.find_diff_by_id(diff_id).await?;
+
+    blame::blame_diff(source.as_ref(), &diff)
+        .await
+        .map(Json)
+        .map_err(AppError::from)
+}
+
+#[post("/reconcileDiff", data = "<payload>")]
+pub async fn reconcile_diff(
+    payload: Json<models::ReconcileDiffPayload>,
+    github_client: &State<Arc<GithubClient>>,
+    mongo: &State<DiffCollection>,
+) -> Result<Json<models::ReconcileDiffResponse>, AppError> {
+    let payload = payload.into_inner();
+    let diff_id = ObjectId::from_str(&payload.id)?;
+    let diff = mongo.find_diff_by_id(diff_id).await?;
+
+    let unknown_paths = reconcile::unknown_paths(&diff, &payload.paths);
+    if payload.paths.is_empty() || !unknown_paths.is_empty() {
+        return Err(AppError::BadRequest(format!(
+            "Paths not in the diff: {}",
+            unknown_paths.join(", ")
+        )));
+    }
+
+    let pull_request = reconcile::open_reconciliation_pr(github_client, &diff, &payload.paths)
+        .await
+        .map_err(|e| {
+            error!(
+                "Couldn't open a pull request for diff {}, because {}",
+                diff_id, e
+            );
+            AppError::from(e)
+        })?;
+
+    mongo.set_pr_url(diff_id, &pull_request.html_url).await?;
+
+    Ok(Json(models::ReconcileDiffResponse {
+        id: payload.id,
+        pr_number: pull_request.number,
+        pr_url: pull_request.html_url,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryRunStore;
+    use crate::github::mock::{self, MockGithub};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::{json, Value};
+
+    const FILE: &str = "configs/svc/config-overrides.yml";
+
+    fn app_config() -> AppConfig {
+        AppConfig {
+            file_patterns: FilePatterns::config_overrides_under(&["configs"]).unwrap(),
+        }
+    }
+
+    fn payload(stack_a: &str, stack_b: &str) -> models::ComputeAllDiffPayload {
+        models::ComputeAllDiffPayload {
+            stack_a: stack_a.to_string(),
+            stack_b: stack_b.to_string(),
+            ref_a: None,
+            ref_b: None,
+        }
+    }
+
+    /// The routes reading configs, backed by the mock, runs kept in memory and
+    /// a Mongo client that only connects once a diff gets stored.
+    async fn client(github: &MockGithub) -> Client {
+        let github_client = Arc::new(github.client().await);
+        let source: Arc<dyn ConfigSource> = github_client.clone();
+        let mongo_client = mongodb::Client::with_uri_str("mongodb://127.0.0.1:9")
+            .await
+            .unwrap();
+        let rocket = rocket::build()
+            .manage(github_client)
+            .manage(source)
+            .manage(Arc::new(app_config()))
+            .manage(DiffCollection::init(&mongo_client.database("test")).await)
+            .manage(Arc::new(MemoryRunStore::default()) as Arc<dyn RunStore>)
+            .mount(
+                "/",
+                routes![
+                    get_configs_from_stacks_name,
+                    get_all_diffs_from_stacks,
+                    compute_diff_for_all_files,
+                    toggle_review_endpoint,
+                    list_runs,
+                    get_run_diffs,
+                    get_file_history
+                ],
+            );
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn get_configs_from_stacks_reads_both_heads() {
+        let github = MockGithub::start().await;
+        github.commit("stack-a", "HEAD", "c1").await;
+        github.commit("stack-b", "HEAD", "c2").await;
+        github
+            .file("stack-a", FILE, Some("c1"), "b1", "port: 80\n")
+            .await;
+        github
+            .file("stack-b", FILE, Some("c2"), "b2", "port: 8080\n")
+            .await;
+        let client = client(&github).await;
+
+        let response = client
+            .post("/getConfigsFromStacks")
+            .header(ContentType::JSON)
+            .body(json!({ "stack_a": "stack-a", "stack_b": "stack-b", "file": FILE }).to_string())
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        let configs: models::GetConfigsFromStacksResponse = response.into_json().await.unwrap();
+        assert_eq!(
+            (configs.sha_a.as_str(), configs.sha_b.as_str()),
+            ("c1", "c2")
+        );
+        assert_eq!(configs.config_a, "port: 80\n");
+        assert_eq!(configs.config_b, "port: 8080\n");
+    }
+
+    #[tokio::test]
+    async fn unknown_stacks_are_not_found() {
+        let github = MockGithub::start().await;
+        let client = client(&github).await;
+
+        let response = client
+            .post("/getConfigsFromStacks")
+            .header(ContentType::JSON)
+            .body(json!({ "stack_a": "stack-a", "stack_b": "stack-b", "file": FILE }).to_string())
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::NotFound);
+        let error: Value = response.into_json().await.unwrap();
+        assert_eq!(error["code"], "not_found");
+
+        // Stacks that don't exist are refused before a run is recorded
+        let response = client
+            .post("/computeAllDiffs")
+            .header(ContentType::JSON)
+            .body(json!({ "stack_a": "stack-a", "stack_b": "stack-b" }).to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        let runs: Vec<models::ComparisonRun> = client
+            .get("/runs")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(runs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_ids_are_bad_requests() {
+        let github = MockGithub::start().await;
+        let client = client(&github).await;
+
+        let response = client
+            .post("/toggleReview")
+            .header(ContentType::JSON)
+            .body(json!({ "id": "not-an-object-id" }).to_string())
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::BadRequest);
+        let error: Value = response.into_json().await.unwrap();
+        assert_eq!(error["code"], "invalid_id");
+
+        let response = client.get("/runs/not-an-object-id/diffs").dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = client
+            .post("/getAllDiffsFromStacks")
+            .header(ContentType::JSON)
+            .body(
+                json!({ "stack_a": "stack-a", "stack_b": "stack-b", "cursor": "not a cursor" })
+                    .to_string(),
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let error: Value = response.into_json().await.unwrap();
+        assert_eq!(error["code"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn identical_stacks_have_no_diff() {
+        let github = MockGithub::start().await;
+        for (stack, sha) in [("stack-a", "c1"), ("stack-b", "c2")] {
+            github.commit(stack, "HEAD", sha).await;
+            github.tree(stack, sha, &[(FILE, "b1")]).await;
+        }
+        let client = client(&github).await;
+
+        let response = client
+            .post("/computeAllDiffs")
+            .header(ContentType::JSON)
+            .body(json!({ "stack_a": "stack-a", "stack_b": "stack-b" }).to_string())
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        let response: models::ComputeAllDiffResponse = response.into_json().await.unwrap();
+        assert!(response.files_with_diff.is_empty());
+        assert!(response.run_id.is_some());
+    }
+
+    #[tokio::test]
+    async fn runs_are_listed_by_pair() {
+        let github = MockGithub::start().await;
+        for (stack, sha) in [("stack-a", "c1"), ("stack-b", "c2")] {
+            github.commit(stack, "HEAD", sha).await;
+            github.tree(stack, sha, &[(FILE, "b1")]).await;
+        }
+        let client = client(&github).await;
+        for _ in 0..2 {
+            let response = client
+                .post("/computeAllDiffs")
+                .header(ContentType::JSON)
+                .body(json!({ "stack_a": "stack-a", "stack_b": "stack-b" }).to_string())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let runs: Vec<models::ComparisonRun> = client
+            .get("/runs?stack_a=stack-a&stack_b=stack-b&limit=1")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].status, models::RunStatus::Succeeded);
+        assert_eq!(runs[0].counts, Some(models::RunCounts::default()));
+        let runs: Vec<models::ComparisonRun> = client
+            .get("/runs?stack_a=stack-b")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(runs.is_empty());
+
+        let response = client
+            .get(format!(
+                "/runs/{}/diffs?format=csv&limit=2",
+                ObjectId::new()
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[tokio::test]
+    async fn diff_stacks_compares_files_by_path() {
+        let github = MockGithub::start().await;
+        let only_a = "configs/only-a/config-overrides.yml";
+        github.commit("stack-a", "HEAD", "c1").await;
+        github.commit("stack-b", "HEAD", "c2").await;
+        github
+            .tree("stack-a", "c1", &[(FILE, "b1"), (only_a, "b3")])
+            .await;
+        github
+            .tree("stack-b", "c2", &[(FILE, "b2"), ("README.md", "b4")])
+            .await;
+        github.blob("stack-a", "b1", "port: 80\nhost: a\n").await;
+        github.blob("stack-b", "b2", "port: 8080\nhost: a\n").await;
+        let github_client = github.client().await;
+
+        let response = diff_stacks(payload("stack-a", "stack-b"), &github_client, &app_config())
+            .await
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        let files: Vec<_> = response
+            .files_with_diff
+            .iter()
+            .map(|diff| {
+                (
+                    diff.file.as_str(),
+                    diff.left_not_right.clone(),
+                    diff.same_key_diff_value.clone(),
+                )
             })
-        })
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (only_a, vec!["/*".to_string()], Vec::new()),
+                (FILE, Vec::new(), vec!["/port".to_string()]),
+            ]
+        );
+        assert_eq!(response.files_with_diff[1].sha_b.as_deref(), Some("c2"));
+        assert_eq!(response.compared_files, vec![only_a, FILE]);
+    }
+
+    #[tokio::test]
+    async fn folder_b_is_listed_from_stack_b_at_its_ref() {
+        let github = MockGithub::start().await;
+        let in_folder_b = "configs-b/svc/config-overrides.yml";
+        github.commit("stack-a", "release-1", "c1").await;
+        github.commit("stack-b", "release-2", "c2").await;
+        github.tree("stack-a", "c1", &[]).await;
+        github.tree("stack-b", "c2", &[(in_folder_b, "b2")]).await;
+        let github_client = github.client().await;
+        let app_config = AppConfig {
+            file_patterns: FilePatterns::config_overrides_under(&["configs-a", "configs-b"])
+                .unwrap(),
+        };
+        let payload = models::ComputeAllDiffPayload {
+            ref_a: Some("release-1".to_string()),
+            ref_b: Some("release-2".to_string()),
+            ..payload("stack-a", "stack-b")
+        };
+
+        let response = diff_stacks(payload, &github_client, &app_config)
+            .await
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        assert_eq!(response.files_with_diff.len(), 1);
+        let diff = &response.files_with_diff[0];
+        assert_eq!(diff.file, in_folder_b);
+        assert_eq!(diff.right_not_left, vec!["/*"]);
+        assert_eq!(
+            (diff.sha_a.as_deref(), diff.sha_b.as_deref()),
+            (Some("c1"), Some("c2"))
+        );
+    }
+
+    #[tokio::test]
+    async fn file_history_diffs_each_commit_against_the_previous_one() {
+        let github = MockGithub::start().await;
+        let commits: Vec<Value> = ["c4", "c3", "c2", "c1"]
+            .into_iter()
+            .map(|sha| {
+                let mut commit = mock::fixture("commit");
+                commit["sha"] = sha.into();
+                commit
+            })
+            .collect();
+        github
+            .get(
+                &mock::repo_route("stack-a", "/commits"),
+                Value::Array(commits),
+            )
+            .await;
+        for (sha, content) in [
+            ("c4", "port: 81\nhost: a\n"),
+            ("c3", "port: 80\n"),
+            ("c2", "port: [80\n"),
+            ("c1", "port: 80\n"),
+        ] {
+            github
+                .file("stack-a", FILE, Some(sha), &format!("b-{}", sha), content)
+                .await;
+        }
+        let client = client(&github).await;
+
+        let response = client
+            .post("/getFileHistory")
+            .header(ContentType::JSON)
+            .body(json!({ "stack": "stack-a", "file": FILE }).to_string())
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        let history: models::FileHistoryResponse = response.into_json().await.unwrap();
+        let entries: Vec<_> = history
+            .history
+            .iter()
+            .map(|entry| {
+                (
+                    entry.sha.as_str(),
+                    entry.added.clone(),
+                    entry.changed.clone(),
+                    entry.error.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    "c4",
+                    vec!["/host".to_string()],
+                    vec!["/port".to_string()],
+                    false
+                ),
+                ("c3", Vec::new(), Vec::new(), true),
+                ("c2", Vec::new(), Vec::new(), true),
+                ("c1", vec!["/port".to_string()], Vec::new(), false),
+            ]
+        );
+    }
 }
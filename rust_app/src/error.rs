@@ -0,0 +1,202 @@
+use std::fmt;
+
+use mongodb::error::ErrorKind;
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::Request;
+use serde::Serialize;
+
+use crate::github::ConfigError;
+
+/// What every route answers with when it fails, `code` being stable for
+/// clients to match on.
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    InvalidId(bson::oid::Error),
+    NotFound(String),
+    Unauthorized(String),
+    PayloadTooLarge(String),
+    Config(ConfigError),
+    Database(mongodb::error::Error),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> Status {
+        match self {
+            AppError::BadRequest(_) | AppError::InvalidId(_) => Status::BadRequest,
+            AppError::NotFound(_) => Status::NotFound,
+            AppError::Unauthorized(_) => Status::Unauthorized,
+            AppError::PayloadTooLarge(_) => Status::PayloadTooLarge,
+            AppError::Config(e) => match e {
+                ConfigError::InvalidStack(_) => Status::BadRequest,
+                ConfigError::NotFound(_) => Status::NotFound,
+                ConfigError::Forbidden(_) => Status::Forbidden,
+                ConfigError::RateLimited(_) => Status::TooManyRequests,
+                ConfigError::NoContent
+                | ConfigError::InvalidYaml(..)
+                | ConfigError::Unreconcilable(_) => Status::UnprocessableEntity,
+                // GitHub answered something we can't use, or didn't answer
+                ConfigError::DecodeError(_)
+                | ConfigError::Utf8Error(_)
+                | ConfigError::TruncatedTree(_)
+                | ConfigError::OctocrabError(_) => Status::BadGateway,
+                ConfigError::Unsupported(_) => Status::NotImplemented,
+                ConfigError::Other(_) => Status::InternalServerError,
+            },
+            AppError::Database(e) => match *e.kind {
+                ErrorKind::ServerSelection { .. } => Status::ServiceUnavailable,
+                _ => Status::InternalServerError,
+            },
+            AppError::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::InvalidId(_) => "invalid_id",
+            AppError::NotFound(_) => "not_found",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::Config(e) => match e {
+                ConfigError::InvalidStack(_) => "invalid_stack",
+                ConfigError::NotFound(_) => "not_found",
+                ConfigError::Forbidden(_) => "forbidden",
+                ConfigError::RateLimited(_) => "rate_limited",
+                ConfigError::NoContent => "not_a_file",
+                ConfigError::InvalidYaml(..) => "invalid_yaml",
+                ConfigError::Unreconcilable(_) => "unreconcilable",
+                ConfigError::DecodeError(_) | ConfigError::Utf8Error(_) => "invalid_content",
+                ConfigError::TruncatedTree(_) => "truncated_tree",
+                ConfigError::OctocrabError(_) => "upstream_error",
+                ConfigError::Unsupported(_) => "unsupported",
+                ConfigError::Other(_) => "internal_error",
+            },
+            AppError::Database(e) => match *e.kind {
+                ErrorKind::ServerSelection { .. } => "database_unavailable",
+                _ => "database_error",
+            },
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::NotFound(message)
+            | AppError::Unauthorized(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::Internal(message) => write!(f, "{}", message),
+            AppError::InvalidId(e) => write!(f, "Invalid id: {}", e),
+            AppError::Config(e) => write!(f, "{}", e),
+            AppError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<ConfigError> for AppError {
+    fn from(err: ConfigError) -> Self {
+        AppError::Config(err)
+    }
+}
+
+impl From<mongodb::error::Error> for AppError {
+    fn from(err: mongodb::error::Error) -> Self {
+        AppError::Database(err)
+    }
+}
+
+impl From<bson::oid::Error> for AppError {
+    fn from(err: bson::oid::Error) -> Self {
+        AppError::InvalidId(err)
+    }
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for AppError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let status = self.status();
+        if status.code >= 500 {
+            error!("{} failed with {}: {}", req.uri(), status, self);
+        }
+        let body = ErrorBody {
+            code: self.code().to_string(),
+            message: self.to_string(),
+        };
+        (status, Json(body)).respond_to(req)
+    }
+}
+
+/// Errors raised by Rocket itself, such as unknown routes, bodies that don't
+/// parse or failing request guards.
+#[catch(default)]
+pub fn default_catcher(status: Status, _request: &Request) -> (Status, Json<ErrorBody>) {
+    let reason = status.reason().unwrap_or("Error");
+    let body = ErrorBody {
+        code: reason.to_lowercase().replace([' ', '-'], "_"),
+        message: reason.to_string(),
+    };
+    (status, Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::host::StackLocation;
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::Value;
+
+    #[test]
+    fn config_errors_keep_the_github_status() {
+        let errors = [
+            AppError::from(ConfigError::NotFound("stack-a".to_string())),
+            AppError::from(ConfigError::Forbidden("Bad credentials".to_string())),
+            AppError::from(ConfigError::RateLimited("/user/repos".to_string())),
+            AppError::from(StackLocation::parse("a//b", "my-org").unwrap_err()),
+            AppError::from(ConfigError::InvalidYaml(
+                "config.yml".to_string(),
+                serde_yaml::from_str::<serde_yaml::Value>("a: [").unwrap_err(),
+            )),
+            AppError::from(ConfigError::Unsupported("no blame".to_string())),
+            AppError::from(ConfigError::Other(anyhow::anyhow!("Mongo went away"))),
+        ];
+
+        assert_eq!(
+            errors
+                .iter()
+                .map(|e| (e.status().code, e.code()))
+                .collect::<Vec<_>>(),
+            vec![
+                (404, "not_found"),
+                (403, "forbidden"),
+                (429, "rate_limited"),
+                (400, "invalid_stack"),
+                (422, "invalid_yaml"),
+                (501, "unsupported"),
+                (500, "internal_error")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rocket_errors_have_a_json_body() {
+        let rocket = rocket::build().register("/", catchers![default_catcher]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let response = client.get("/unknown").dispatch().await;
+
+        assert_eq!(response.status(), Status::NotFound);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["code"], "not_found");
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::Context;
+use jsonwebtoken::EncodingKey;
+use std::env;
+use std::fs;
+
+/// How `GithubClient` authenticates, selected with `AUTH_MODE_GH`.
+pub enum GithubAuth {
+    PersonalToken(String),
+    /// Installation tokens are requested for the organization, and refreshed
+    /// by octocrab once they expire.
+    App {
+        app_id: u64,
+        private_key: EncodingKey,
+    },
+}
+
+impl GithubAuth {
+    /// `token` (the default) reads `ACCESS_TOKEN_GH`, `app` reads `APP_ID_GH`
+    /// and the PEM private key at `APP_PRIVATE_KEY_PATH_GH`.
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let mode = env::var("AUTH_MODE_GH").unwrap_or_else(|_| "token".to_string());
+        Self::from_vars(&mode, |name| env::var(name).ok())
+    }
+
+    /// Same variables suffixed with the hostname, `ACCESS_TOKEN_GH_GITHUB_COM`
+    /// for github.com.
+    pub fn from_env_for_host(hostname: &str) -> Result<Self, anyhow::Error> {
+        let suffix = host_suffix(hostname);
+        let mode =
+            env::var(format!("AUTH_MODE_GH_{}", suffix)).unwrap_or_else(|_| "token".to_string());
+        Self::from_vars(&mode, |name| env::var(format!("{}_{}", name, suffix)).ok())
+    }
+
+    fn from_vars(mode: &str, var: impl Fn(&str) -> Option<String>) -> Result<Self, anyhow::Error> {
+        match mode {
+            "token" => {
+                let access_token = var("ACCESS_TOKEN_GH").context("No Github token")?;
+                Ok(GithubAuth::PersonalToken(access_token))
+            }
+            "app" => {
+                let app_id = var("APP_ID_GH")
+                    .context("No Github App id")?
+                    .parse()
+                    .context("Invalid Github App id")?;
+                let key_path = var("APP_PRIVATE_KEY_PATH_GH").context("No Github App key")?;
+                let pem = fs::read(&key_path)
+                    .with_context(|| format!("Cannot read Github App key {}", key_path))?;
+                let private_key =
+                    EncodingKey::from_rsa_pem(&pem).context("Invalid Github App key")?;
+                Ok(GithubAuth::App {
+                    app_id,
+                    private_key,
+                })
+            }
+            _ => Err(anyhow::anyhow!(
+                "Unknown AUTH_MODE_GH {}, expected token or app",
+                mode
+            )),
+        }
+    }
+}
+
+pub fn host_suffix(hostname: &str) -> String {
+    hostname
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn vars(values: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let values: HashMap<String, String> = values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| values.get(name).cloned()
+    }
+
+    #[test]
+    fn token_mode_reads_the_access_token() {
+        let auth = GithubAuth::from_vars("token", vars(&[("ACCESS_TOKEN_GH", "ghp_x")])).unwrap();
+
+        assert!(matches!(auth, GithubAuth::PersonalToken(token) if token == "ghp_x"));
+        assert!(GithubAuth::from_vars("token", vars(&[])).is_err());
+    }
+
+    #[test]
+    fn app_mode_requires_a_readable_key() {
+        let missing_key = GithubAuth::from_vars(
+            "app",
+            vars(&[
+                ("APP_ID_GH", "42"),
+                ("APP_PRIVATE_KEY_PATH_GH", "/nonexistent/key.pem"),
+            ]),
+        );
+        let invalid_id = GithubAuth::from_vars("app", vars(&[("APP_ID_GH", "forty-two")]));
+
+        assert!(missing_key.is_err());
+        assert!(invalid_id.is_err());
+        assert!(GithubAuth::from_vars("oauth", vars(&[])).is_err());
+    }
+
+    #[test]
+    fn host_suffix_is_an_env_var_name() {
+        assert_eq!(host_suffix("github.com"), "GITHUB_COM");
+        assert_eq!(host_suffix("ghe.my-corp.io"), "GHE_MY_CORP_IO");
+    }
+}
@@ -0,0 +1,235 @@
+use rocket::serde::json::json;
+use serde::Deserialize;
+
+use super::{ConfigError, GithubClient};
+use crate::source::BlameRange;
+
+/// The REST API has no blame, GraphQL has it on commits.
+const BLAME_QUERY: &str =
+    "query($owner: String!, $name: String!, $expression: String!, $path: String!) {
+  repository(owner: $owner, name: $name) {
+    object(expression: $expression) {
+      ... on Commit {
+        blame(path: $path) {
+          ranges {
+            startingLine
+            endingLine
+            commit { oid authoredDate author { name } }
+          }
+        }
+      }
+    }
+  }
+}";
+
+#[derive(Deserialize)]
+struct GraphqlResponse<T> {
+    data: Option<T>,
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+}
+
+#[derive(Deserialize)]
+struct GraphqlError {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct BlameData {
+    repository: Option<BlameRepository>,
+}
+
+#[derive(Deserialize)]
+struct BlameRepository {
+    object: Option<BlameObject>,
+}
+
+/// Empty when the expression doesn't point to a commit.
+#[derive(Deserialize)]
+struct BlameObject {
+    blame: Option<Blame>,
+}
+
+#[derive(Deserialize)]
+struct Blame {
+    ranges: Vec<GraphqlBlameRange>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlBlameRange {
+    starting_line: usize,
+    ending_line: usize,
+    commit: BlameCommit,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BlameCommit {
+    oid: String,
+    authored_date: chrono::DateTime<chrono::Utc>,
+    author: Option<BlameAuthor>,
+}
+
+#[derive(Deserialize)]
+struct BlameAuthor {
+    name: Option<String>,
+}
+
+impl From<GraphqlBlameRange> for BlameRange {
+    fn from(range: GraphqlBlameRange) -> Self {
+        BlameRange {
+            start_line: range.starting_line,
+            end_line: range.ending_line,
+            commit_sha: range.commit.oid,
+            author: range
+                .commit
+                .author
+                .and_then(|author| author.name)
+                .unwrap_or_default(),
+            date: range.commit.authored_date,
+        }
+    }
+}
+
+impl GithubClient {
+    /// Who last changed each line of `path` as of `revision`.
+    pub async fn get_blame(
+        &self,
+        stack: &str,
+        path: &str,
+        revision: &str,
+    ) -> Result<Vec<BlameRange>, ConfigError> {
+        let (host, location) = self.locate(stack)?;
+        let query = json!({
+            "query": BLAME_QUERY,
+            "variables": {
+                "owner": location.owner,
+                "name": location.repo,
+                "expression": revision,
+                "path": path.trim_start_matches('/'),
+            },
+        });
+        let response: GraphqlResponse<BlameData> =
+            host.post_graphql(location.owner, &query).await?;
+
+        // Errors come with a 200, missing repos and paths being typed NOT_FOUND
+        if let Some(error) = response.errors.into_iter().next() {
+            return match error.kind.as_deref() {
+                Some("NOT_FOUND") => Err(ConfigError::NotFound(format!("{}/{}", stack, path))),
+                _ => Err(
+                    anyhow::anyhow!("Cannot blame {} in {}: {}", path, stack, error.message).into(),
+                ),
+            };
+        }
+        let blame = response
+            .data
+            .and_then(|data| data.repository)
+            .and_then(|repository| repository.object)
+            .and_then(|object| object.blame)
+            .ok_or_else(|| ConfigError::NotFound(format!("{}@{}", stack, revision)))?;
+
+        info!(
+            "Blamed {} in {} at {} with {} ranges",
+            path,
+            stack,
+            revision,
+            blame.ranges.len()
+        );
+        Ok(blame.ranges.into_iter().map(BlameRange::from).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::auth::GithubAuth;
+    use crate::github::mock::{fixture, MockGithub, ORGANIZATION};
+    use crate::github::rate_limit::RetryConfig;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn blame_ranges_come_from_graphql() {
+        let github = MockGithub::start().await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .and(body_partial_json(json!({
+                "variables": { "name": "stack-a", "expression": "abc", "path": "a.yml" }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "repository": { "object": { "blame": { "ranges": [{
+                    "startingLine": 1,
+                    "endingLine": 3,
+                    "commit": {
+                        "oid": "abc",
+                        "authoredDate": "2024-01-02T03:04:05Z",
+                        "author": { "name": "Jane Doe" }
+                    }
+                }] } } } }
+            })))
+            .mount(&github.server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .and(body_partial_json(
+                json!({ "variables": { "path": "missing.yml" } }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "repository": { "object": null } },
+                "errors": [{ "type": "NOT_FOUND", "message": "Could not resolve file" }]
+            })))
+            .mount(&github.server)
+            .await;
+        let client = github.client().await;
+
+        let ranges = client.get_blame("stack-a", "/a.yml", "abc").await.unwrap();
+        assert_eq!(
+            ranges,
+            vec![BlameRange {
+                start_line: 1,
+                end_line: 3,
+                commit_sha: "abc".to_string(),
+                author: "Jane Doe".to_string(),
+                date: "2024-01-02T03:04:05Z".parse().unwrap(),
+            }]
+        );
+        assert!(matches!(
+            client.get_blame("stack-a", "missing.yml", "abc").await,
+            Err(ConfigError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn enterprise_servers_are_queried_at_api_graphql() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v3/user"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture("user")))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/graphql"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "repository": { "object": { "blame": { "ranges": [] } } } }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = GithubClient::new(
+            GithubAuth::PersonalToken("token".to_string()),
+            ORGANIZATION.to_string(),
+            format!("{}/api/v3", server.uri()),
+            RetryConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            client.get_blame("stack-a", "a.yml", "abc").await.unwrap(),
+            Vec::new()
+        );
+    }
+}
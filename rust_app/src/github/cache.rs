@@ -0,0 +1,378 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use bson::doc;
+use mongodb::options::{IndexOptions, ReplaceOptions};
+use mongodb::IndexModel;
+use serde::{Deserialize, Serialize};
+
+const CACHE_COLLECTION: &str = "github_cache";
+
+#[derive(Clone)]
+pub struct CacheConfig {
+    /// How long an entry is served without asking GitHub, after which it is
+    /// revalidated with its ETag.
+    pub ttl: Duration,
+    /// How many bytes of keys, ETags and bodies are kept in memory.
+    pub max_bytes: usize,
+    /// Whether entries are also stored in Mongo, to survive restarts.
+    pub persist: bool,
+    /// How long Mongo keeps an entry, stale ones still saving quota when
+    /// GitHub confirms them.
+    pub retention: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            ttl: Duration::from_secs(300),
+            max_bytes: 64 * 1024 * 1024,
+            persist: false,
+            retention: Duration::from_secs(7 * 24 * 3600),
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Reads `GH_CACHE_TTL_SECS`, `GH_CACHE_MAX_BYTES`, `GH_CACHE_MONGO` and
+    /// `GH_CACHE_RETENTION_SECS`, keeping the default for any missing or
+    /// invalid value.
+    pub fn from_env() -> Self {
+        let default = CacheConfig::default();
+        let read = |name: &str| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        CacheConfig {
+            ttl: read("GH_CACHE_TTL_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.ttl),
+            max_bytes: read("GH_CACHE_MAX_BYTES")
+                .map(|value| value as usize)
+                .unwrap_or(default.max_bytes),
+            persist: env::var("GH_CACHE_MONGO")
+                .map(|value| value == "true")
+                .unwrap_or(default.persist),
+            retention: read("GH_CACHE_RETENTION_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.retention),
+        }
+    }
+}
+
+/// A response body along with the ETag GitHub sent with it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedEntry {
+    #[serde(rename = "_id")]
+    pub key: String,
+    pub etag: Option<String>,
+    pub body: String,
+    pub fetched_at: bson::DateTime,
+}
+
+impl CachedEntry {
+    fn size(&self) -> usize {
+        self.key.len() + self.etag.as_ref().map_or(0, String::len) + self.body.len()
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Stale entries GitHub confirmed with a 304, which don't count against
+    /// the rate limit.
+    pub revalidated: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+struct MemoryEntry {
+    entry: CachedEntry,
+    /// When the entry was last read or written, on the clock of `MemoryEntries`.
+    last_used: u64,
+}
+
+/// The entries in memory, along with their size and the order they were used
+/// in. Each use is queued, earlier uses of an entry being skipped once dequeued.
+#[derive(Default)]
+struct MemoryEntries {
+    entries: HashMap<String, MemoryEntry>,
+    uses: VecDeque<(u64, String)>,
+    clock: u64,
+    bytes: usize,
+}
+
+impl MemoryEntries {
+    fn is_last_use(&self, used: u64, key: &str) -> bool {
+        self.entries
+            .get(key)
+            .is_some_and(|memory_entry| memory_entry.last_used == used)
+    }
+
+    fn record_use(&mut self, key: &str) -> u64 {
+        self.clock += 1;
+        self.uses.push_back((self.clock, key.to_string()));
+        // Drops the uses superseded since, so that hits don't grow the queue
+        if self.uses.len() > 2 * self.entries.len() + 16 {
+            let uses = std::mem::take(&mut self.uses);
+            self.uses = uses
+                .into_iter()
+                .filter(|(used, key)| *used == self.clock || self.is_last_use(*used, key))
+                .collect();
+        }
+        self.clock
+    }
+
+    fn touch(&mut self, key: &str) -> Option<CachedEntry> {
+        self.entries.get(key)?;
+        let last_used = self.record_use(key);
+        let memory_entry = self.entries.get_mut(key)?;
+        memory_entry.last_used = last_used;
+        Some(memory_entry.entry.clone())
+    }
+
+    fn evict_least_recently_used(&mut self) -> bool {
+        while let Some((used, key)) = self.uses.pop_front() {
+            if self.is_last_use(used, &key) {
+                if let Some(evicted) = self.entries.remove(&key) {
+                    self.bytes -= evicted.entry.size();
+                }
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// File and directory reads, keyed by host and route, which hold the repo,
+/// the path and the ref.
+pub struct ContentCache {
+    config: CacheConfig,
+    memory: Mutex<MemoryEntries>,
+    collection: OnceLock<mongodb::Collection<CachedEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    revalidated: AtomicU64,
+}
+
+impl ContentCache {
+    pub fn new(config: CacheConfig) -> Self {
+        ContentCache {
+            config,
+            memory: Mutex::new(MemoryEntries::default()),
+            collection: OnceLock::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            revalidated: AtomicU64::new(0),
+        }
+    }
+
+    /// Backs the cache with a collection of `database`, if configured to.
+    pub fn persist_in(&self, database: &mongodb::Database) {
+        if self.config.persist
+            && self
+                .collection
+                .set(database.collection(CACHE_COLLECTION))
+                .is_ok()
+        {
+            info!("Caching Github contents in collection {}", CACHE_COLLECTION);
+        }
+    }
+
+    /// Lets Mongo drop entries once past their retention.
+    pub async fn create_indexes(&self) -> Result<(), mongodb::error::Error> {
+        let collection = match self.collection.get() {
+            Some(collection) => collection,
+            None => return Ok(()),
+        };
+        let index = IndexModel::builder()
+            .keys(doc! {"fetched_at": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(self.config.retention)
+                    .build(),
+            )
+            .build();
+        collection.create_index(index, None).await?;
+        Ok(())
+    }
+
+    pub fn is_fresh(&self, entry: &CachedEntry) -> bool {
+        let age_millis =
+            bson::DateTime::now().timestamp_millis() - entry.fetched_at.timestamp_millis();
+        age_millis < self.config.ttl.as_millis() as i64
+    }
+
+    /// The entry in memory, or else in Mongo.
+    pub async fn get(&self, key: &str) -> Option<CachedEntry> {
+        if let Some(entry) = self
+            .memory
+            .lock()
+            .ok()
+            .and_then(|mut memory| memory.touch(key))
+        {
+            return Some(entry);
+        }
+        let collection = self.collection.get()?;
+        match collection.find_one(doc! { "_id": key }, None).await {
+            Ok(Some(entry)) => {
+                self.remember(entry.clone());
+                Some(entry)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Cannot read cached {}, because {}", key, e);
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, entry: CachedEntry) {
+        self.remember(entry.clone());
+        if let Some(collection) = self.collection.get() {
+            let options = ReplaceOptions::builder().upsert(true).build();
+            if let Err(e) = collection
+                .replace_one(doc! { "_id": &entry.key }, &entry, options)
+                .await
+            {
+                warn!("Cannot cache {}, because {}", entry.key, e);
+            }
+        }
+    }
+
+    /// Keeps the entry in memory, evicting the least recently used ones until
+    /// it fits. Entries larger than the whole budget aren't kept.
+    fn remember(&self, entry: CachedEntry) {
+        let mut memory = match self.memory.lock() {
+            Ok(memory) => memory,
+            Err(_) => return,
+        };
+        if let Some(previous) = memory.entries.remove(&entry.key) {
+            memory.bytes -= previous.entry.size();
+        }
+        if entry.size() > self.config.max_bytes {
+            return;
+        }
+        while memory.bytes + entry.size() > self.config.max_bytes {
+            if !memory.evict_least_recently_used() {
+                break;
+            }
+        }
+        memory.bytes += entry.size();
+        let last_used = memory.record_use(&entry.key);
+        memory
+            .entries
+            .insert(entry.key.clone(), MemoryEntry { entry, last_used });
+    }
+
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_revalidated(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.revalidated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let memory = match self.memory.lock() {
+            Ok(memory) => memory,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            revalidated: self.revalidated.load(Ordering::Relaxed),
+            entries: memory.entries.len(),
+            bytes: memory.bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, age_secs: i64) -> CachedEntry {
+        CachedEntry {
+            key: key.to_string(),
+            etag: Some(format!("\"{}\"", key)),
+            body: "{}".to_string(),
+            fetched_at: bson::DateTime::from_millis(
+                bson::DateTime::now().timestamp_millis() - age_secs * 1000,
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_entries_once_over_budget() {
+        let cache = ContentCache::new(CacheConfig {
+            ttl: Duration::from_secs(60),
+            max_bytes: 2 * entry("a", 0).size(),
+            ..CacheConfig::default()
+        });
+        cache.insert(entry("b", 120)).await;
+        cache.insert(entry("a", 10)).await;
+        // Read last, so more recently used than `a` although fetched earlier
+        assert!(cache.get("b").await.is_some());
+        cache.insert(entry("c", 0)).await;
+        cache
+            .insert(CachedEntry {
+                body: "x".repeat(100),
+                ..entry("d", 0)
+            })
+            .await;
+
+        assert!(cache.get("a").await.is_none());
+        assert!(cache.get("d").await.is_none());
+        assert!(!cache.is_fresh(&cache.get("b").await.unwrap()));
+        assert!(cache.is_fresh(&cache.get("c").await.unwrap()));
+        for _ in 0..1000 {
+            cache.get("c").await;
+        }
+        assert!(cache.memory.lock().unwrap().uses.len() <= 2 * 2 + 16 + 1);
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(cache.stats().bytes, 2 * entry("a", 0).size());
+    }
+
+    #[tokio::test]
+    async fn persisted_caches_still_serve_from_memory_without_mongo() {
+        let mongo_client =
+            mongodb::Client::with_uri_str("mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=100")
+                .await
+                .unwrap();
+        let database = mongo_client.database("test");
+        let memory_only = ContentCache::new(CacheConfig::default());
+        let persisted = ContentCache::new(CacheConfig {
+            persist: true,
+            ..CacheConfig::default()
+        });
+
+        memory_only.persist_in(&database);
+        persisted.persist_in(&database);
+
+        assert!(memory_only.collection.get().is_none());
+        assert_eq!(
+            persisted
+                .collection
+                .get()
+                .map(|collection| collection.name()),
+            Some(CACHE_COLLECTION)
+        );
+        assert!(memory_only.create_indexes().await.is_ok());
+        assert!(persisted.create_indexes().await.is_err());
+        persisted.insert(entry("a", 0)).await;
+        assert!(persisted.get("a").await.is_some());
+        assert!(persisted.get("b").await.is_none());
+    }
+}
@@ -0,0 +1,245 @@
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use globset::{Glob, GlobMatcher};
+use octocrab::models::Repository;
+use rocket::futures::{stream, StreamExt};
+use serde::Serialize;
+
+use super::{ConfigError, GithubClient, RepoFilter};
+use crate::source::patterns::FilePatterns;
+use crate::source::SourceTree;
+
+const MAX_CONCURRENT_TREE_DOWNLOADS: usize = 8;
+
+/// Discovering stacks lists every repository and downloads the tree of each
+/// candidate, so the catalog is served from memory for a while.
+const CATALOG_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Which repositories of the organization are stacks, those whose name matches
+/// a glob such as `stack-*` or having a topic.
+pub struct StackDiscovery {
+    name_pattern: Option<GlobMatcher>,
+    topic: Option<String>,
+    catalog: Mutex<Option<(Instant, Vec<StackCatalogEntry>)>>,
+}
+
+impl StackDiscovery {
+    /// `None` when neither criterion is set, as every repository would be a
+    /// candidate.
+    pub fn new(
+        name_pattern: Option<&str>,
+        topic: Option<&str>,
+    ) -> Result<Option<Self>, globset::Error> {
+        if name_pattern.is_none() && topic.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(StackDiscovery {
+            name_pattern: name_pattern
+                .map(|pattern| Glob::new(pattern).map(|glob| glob.compile_matcher()))
+                .transpose()?,
+            topic: topic.map(str::to_lowercase),
+            catalog: Mutex::new(None),
+        }))
+    }
+
+    /// Reads `STACK_NAME_PATTERN` and `STACK_TOPIC`.
+    pub fn from_env() -> Result<Option<Self>, globset::Error> {
+        let name_pattern = env::var("STACK_NAME_PATTERN").ok();
+        let topic = env::var("STACK_TOPIC").ok();
+        Self::new(name_pattern.as_deref(), topic.as_deref())
+    }
+
+    pub fn matches(&self, repo: &Repository) -> bool {
+        let name_matches = self
+            .name_pattern
+            .as_ref()
+            .is_some_and(|pattern| pattern.is_match(&repo.name));
+        // Topics are always lowercase on GitHub
+        let topic_matches = self.topic.as_ref().is_some_and(|topic| {
+            repo.topics
+                .iter()
+                .flatten()
+                .any(|repo_topic| repo_topic == topic)
+        });
+        name_matches || topic_matches
+    }
+
+    fn cached_catalog(&self) -> Option<Vec<StackCatalogEntry>> {
+        let catalog = self.catalog.lock().ok()?;
+        catalog
+            .as_ref()
+            .filter(|(listed_at, _)| listed_at.elapsed() < CATALOG_TTL)
+            .map(|(_, stacks)| stacks.clone())
+    }
+
+    fn cache_catalog(&self, stacks: &[StackCatalogEntry]) {
+        if let Ok(mut catalog) = self.catalog.lock() {
+            *catalog = Some((Instant::now(), stacks.to_vec()));
+        }
+    }
+}
+
+/// A repository confirmed to be a stack, `name` being what to pass as
+/// `stack_a` or `stack_b`.
+#[derive(Serialize, Debug, Clone)]
+pub struct StackCatalogEntry {
+    pub name: String,
+    pub default_branch: String,
+    pub pushed_at: Option<DateTime<Utc>>,
+    pub topics: Vec<String>,
+    pub config_files: usize,
+}
+
+impl GithubClient {
+    /// The repositories of the organization matching `discovery` which have
+    /// files matching `patterns` on their default branch, by name. Archived
+    /// ones are left out.
+    pub async fn discover_stacks(
+        &self,
+        discovery: &StackDiscovery,
+        patterns: &FilePatterns,
+    ) -> Result<Vec<StackCatalogEntry>, ConfigError> {
+        if let Some(stacks) = discovery.cached_catalog() {
+            return Ok(stacks);
+        }
+
+        let filter = RepoFilter {
+            archived: Some(false),
+            ..Default::default()
+        };
+        let candidates: Vec<Repository> = self
+            .default_host
+            .get_all_pages_with_retry::<Repository>(
+                &self.organization_name,
+                &self.default_host.owner_repos_route(&self.organization_name),
+            )
+            .await?
+            .into_iter()
+            .filter(|repo| filter.matches(repo) && discovery.matches(repo))
+            .collect();
+        info!("Found {} candidate stacks", candidates.len());
+
+        let entries: Vec<Result<Option<StackCatalogEntry>, ConfigError>> = stream::iter(candidates)
+            .map(|repo| self.catalog_entry(repo, patterns))
+            .buffer_unordered(MAX_CONCURRENT_TREE_DOWNLOADS)
+            .collect()
+            .await;
+
+        let mut stacks = Vec::new();
+        for entry in entries {
+            stacks.extend(entry?);
+        }
+        stacks.sort_by(|a, b| a.name.cmp(&b.name));
+        discovery.cache_catalog(&stacks);
+        Ok(stacks)
+    }
+
+    /// `None` when the repository has no config files, or no commits yet.
+    async fn catalog_entry(
+        &self,
+        repo: Repository,
+        patterns: &FilePatterns,
+    ) -> Result<Option<StackCatalogEntry>, ConfigError> {
+        let name = repo.name.clone();
+        let default_branch = match repo.default_branch {
+            Some(default_branch) => default_branch,
+            None => return Ok(None),
+        };
+
+        let tree = match self.get_tree(&name, &default_branch).await {
+            Ok(tree) => SourceTree::from(tree),
+            Err(e @ (ConfigError::RateLimited(_) | ConfigError::Forbidden(_))) => return Err(e),
+            Err(e) => {
+                warn!("Cannot list the files of {}, because {}", name, e);
+                return Ok(None);
+            }
+        };
+        let config_files = tree.matching_files(patterns).len();
+        if config_files == 0 {
+            info!("{} has no config files, it isn't a stack", name);
+            return Ok(None);
+        }
+
+        Ok(Some(StackCatalogEntry {
+            name,
+            default_branch,
+            pushed_at: repo.pushed_at,
+            topics: repo.topics.unwrap_or_default(),
+            config_files,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::mock::{self, MockGithub, ORGANIZATION};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, ResponseTemplate};
+
+    #[tokio::test]
+    async fn stacks_are_repos_matching_by_name_or_topic_with_configs() {
+        let github = MockGithub::start().await;
+        let mut tagged = mock::repository("payments");
+        tagged["topics"] = vec!["config-stack"].into();
+        tagged["pushed_at"] = "2024-01-02T03:04:05Z".into();
+        // Listed once, the catalog being served from memory afterwards
+        Mock::given(method("GET"))
+            .and(path(format!("/orgs/{}/repos", ORGANIZATION)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(vec![
+                mock::repository("stack-a"),
+                mock::repository("stack-empty"),
+                mock::repository("website"),
+                tagged,
+            ]))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        let file = ("configs/svc/config-overrides.yml", "blob");
+        github.tree("stack-a", "main", &[file]).await;
+        github.tree("payments", "main", &[file]).await;
+        github
+            .tree("stack-empty", "main", &[("README.md", "blob")])
+            .await;
+        let client = github.client().await;
+
+        let discovery = StackDiscovery::new(Some("stack-*"), Some("Config-Stack"))
+            .unwrap()
+            .unwrap();
+        let patterns = FilePatterns::config_overrides_under(&["configs"]).unwrap();
+        let stacks = client.discover_stacks(&discovery, &patterns).await.unwrap();
+
+        assert_eq!(
+            stacks
+                .iter()
+                .map(|stack| (stack.name.as_str(), stack.config_files))
+                .collect::<Vec<_>>(),
+            vec![("payments", 1), ("stack-a", 1)]
+        );
+        assert_eq!(stacks[0].topics, vec!["config-stack"]);
+        assert_eq!(
+            stacks[0].pushed_at,
+            Some("2024-01-02T03:04:05Z".parse().unwrap())
+        );
+        assert_eq!(stacks[1].default_branch, "main");
+        assert_eq!(
+            client
+                .discover_stacks(&discovery, &patterns)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn discovery_needs_a_name_pattern_or_a_topic() {
+        assert!(StackDiscovery::new(None, None).unwrap().is_none());
+        assert!(StackDiscovery::new(None, Some("config-stack"))
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,689 @@
+use http::header::{HeaderValue, ETAG, IF_NONE_MATCH};
+use http::{HeaderMap, StatusCode, Uri};
+use hyper::client::HttpConnector;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::info;
+use octocrab::models::{AppId, Installation};
+use octocrab::{
+    DefaultOctocrabBuilderConfig, FromResponse, NoAuth, NoSvc, NotLayerReady, Octocrab,
+    OctocrabBuilder, Page,
+};
+use secrecy::ExposeSecret;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::auth::GithubAuth;
+use super::cache::{CachedEntry, ContentCache};
+use super::rate_limit::{is_rate_limited, RateLimitStatus, RetryConfig};
+use super::ConfigError;
+
+/// How long a download may wait for the response before it's retried.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Installation tokens expire after an hour, they are replaced a bit earlier.
+const INSTALLATION_TOKEN_LIFETIME: Duration = Duration::from_secs(50 * 60);
+
+/// The GraphQL endpoint, relative to `GithubHost::graphql_base_uri`.
+const GRAPHQL_ROUTE: &str = "/graphql";
+
+/// Where a stack lives, written `repo`, `owner/repo` or `host/owner/repo`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StackLocation<'a> {
+    pub host: Option<&'a str>,
+    pub owner: &'a str,
+    pub repo: &'a str,
+}
+
+impl<'a> StackLocation<'a> {
+    pub fn parse(stack: &'a str, default_owner: &'a str) -> Result<Self, ConfigError> {
+        let parts: Vec<&str> = stack.split('/').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(ConfigError::InvalidStack(format!(
+                "Invalid stack {}",
+                stack
+            )));
+        }
+
+        match parts.as_slice() {
+            [repo] => Ok(StackLocation {
+                host: None,
+                owner: default_owner,
+                repo,
+            }),
+            [owner, repo] => Ok(StackLocation {
+                host: None,
+                owner,
+                repo,
+            }),
+            [host, owner, repo] => Ok(StackLocation {
+                host: Some(host),
+                owner,
+                repo,
+            }),
+            _ => Err(ConfigError::InvalidStack(format!(
+                "Invalid stack {}",
+                stack
+            ))),
+        }
+    }
+
+    /// The API route of the repository, followed by `suffix`.
+    pub fn route(&self, suffix: &str) -> String {
+        format!("/repos/{}/{}{}", self.owner, self.repo, suffix)
+    }
+}
+
+/// A GitHub or GitHub Enterprise server, with its own credentials and quota.
+#[derive(Clone)]
+pub struct GithubHost {
+    octocrab: Octocrab,
+    /// Only set with app authentication, to install on other owners.
+    app_octocrab: Option<Octocrab>,
+    installations: Arc<Mutex<HashMap<String, Octocrab>>>,
+    base_path: String,
+    retry_config: RetryConfig,
+    rate_limit: Arc<Mutex<RateLimitStatus>>,
+    /// Downloads `download_url`s, which are outside of the API.
+    raw_client: hyper::Client<HttpsConnector<HttpConnector>>,
+    /// Enterprise servers serve GraphQL at `/api/graphql`, outside of the REST
+    /// base path, so GraphQL queries go through clients of their own.
+    graphql_base_uri: String,
+    /// Only set with token authentication, apps query on behalf of an owner.
+    graphql_octocrab: Option<Octocrab>,
+    graphql_installations: Arc<Mutex<HashMap<String, (Octocrab, Instant)>>>,
+}
+
+impl GithubHost {
+    pub async fn connect(
+        hostname: &str,
+        auth: GithubAuth,
+        default_owner: &str,
+        retry_config: RetryConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let (base_uri, base_path) = api_base(hostname);
+        let graphql_base_uri = graphql_base(&base_uri);
+        let builder = octocrab_builder(&base_uri)?;
+
+        let mut installations = HashMap::new();
+        let (octocrab, app_octocrab, graphql_octocrab) = match auth {
+            GithubAuth::PersonalToken(access_token) => {
+                let graphql_octocrab = octocrab_builder(&graphql_base_uri)?
+                    .personal_token(access_token.clone())
+                    .build()?;
+                let octocrab = builder.personal_token(access_token).build()?;
+                match octocrab.current().user().await {
+                    Ok(user) => {
+                        info!(
+                            "Authenticated on {} as {}, {} & {}",
+                            hostname, user.login, user.r#type, user.id
+                        )
+                    }
+                    Err(e) => {
+                        info!("Authentication failed. Please check your access token");
+                        return Err(e.into());
+                    }
+                }
+                (octocrab, None, Some(graphql_octocrab))
+            }
+            GithubAuth::App {
+                app_id,
+                private_key,
+            } => {
+                let app_octocrab = builder.app(AppId(app_id), private_key).build()?;
+                match app_octocrab.current().app().await {
+                    Ok(app) => info!(
+                        "Authenticated on {} as app {}, {}",
+                        hostname,
+                        app.slug.unwrap_or(app.name),
+                        app.id
+                    ),
+                    Err(e) => {
+                        info!("Authentication failed. Please check your app id and private key");
+                        return Err(e.into());
+                    }
+                }
+                let octocrab = install(&app_octocrab, default_owner)
+                    .await
+                    .inspect_err(|_| info!("The app isn't installed on {}", default_owner))?;
+                installations.insert(default_owner.to_string(), octocrab.clone());
+                (octocrab, Some(app_octocrab), None)
+            }
+        };
+
+        Ok(GithubHost {
+            octocrab,
+            app_octocrab,
+            installations: Arc::new(Mutex::new(installations)),
+            base_path,
+            retry_config,
+            rate_limit: Arc::new(Mutex::new(RateLimitStatus::default())),
+            raw_client: hyper::Client::builder().build(
+                HttpsConnectorBuilder::new()
+                    .with_native_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build(),
+            ),
+            graphql_base_uri,
+            graphql_octocrab,
+            graphql_installations: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// The repositories of `owner` only, which are those an app is installed
+    /// on for its default owner.
+    pub fn owner_repos_route(&self, owner: &str) -> String {
+        match self.app_octocrab {
+            Some(_) => "/installation/repositories?per_page=100".to_string(),
+            None => format!("/orgs/{}/repos?per_page=100", owner),
+        }
+    }
+
+    pub fn rate_limit(&self) -> RateLimitStatus {
+        self.rate_limit
+            .lock()
+            .map(|rate_limit| rate_limit.clone())
+            .unwrap_or_default()
+    }
+
+    /// Installation tokens are scoped to one owner, so each owner gets its own
+    /// client. Tokens only work with the default one.
+    async fn octocrab_for(&self, owner: &str) -> octocrab::Result<Octocrab> {
+        let app_octocrab = match &self.app_octocrab {
+            Some(app_octocrab) => app_octocrab,
+            None => return Ok(self.octocrab.clone()),
+        };
+        if let Some(octocrab) = self
+            .installations
+            .lock()
+            .ok()
+            .and_then(|installations| installations.get(owner).cloned())
+        {
+            return Ok(octocrab);
+        }
+
+        let octocrab = install(app_octocrab, owner).await?;
+        if let Ok(mut installations) = self.installations.lock() {
+            installations.insert(owner.to_string(), octocrab.clone());
+        }
+        Ok(octocrab)
+    }
+
+    /// GraphQL clients can't request installation tokens themselves, their
+    /// route being under the REST base path, so they are given one.
+    async fn graphql_octocrab_for(&self, owner: &str) -> Result<Octocrab, ConfigError> {
+        let app_octocrab = match (&self.graphql_octocrab, &self.app_octocrab) {
+            (Some(graphql_octocrab), _) => return Ok(graphql_octocrab.clone()),
+            (None, Some(app_octocrab)) => app_octocrab,
+            (None, None) => return Err(anyhow::anyhow!("No credentials for GraphQL").into()),
+        };
+        if let Some(octocrab) = self
+            .graphql_installations
+            .lock()
+            .ok()
+            .and_then(|installations| {
+                installations
+                    .get(owner)
+                    .filter(|(_, expires_at)| Instant::now() < *expires_at)
+                    .map(|(octocrab, _)| octocrab.clone())
+            })
+        {
+            return Ok(octocrab);
+        }
+
+        let installation = find_installation(app_octocrab, owner).await?;
+        let (_, token) = app_octocrab.installation_and_token(installation.id).await?;
+        let octocrab = octocrab_builder(&self.graphql_base_uri)?
+            .personal_token(token.expose_secret().clone())
+            .build()?;
+        if let Ok(mut installations) = self.graphql_installations.lock() {
+            installations.insert(
+                owner.to_string(),
+                (
+                    octocrab.clone(),
+                    Instant::now() + INSTALLATION_TOKEN_LIFETIME,
+                ),
+            );
+        }
+        Ok(octocrab)
+    }
+
+    /// GETs `route` on behalf of `owner`, keeping track of the rate-limit
+    /// headers and retrying rate-limited, failed or unreachable requests with backoff.
+    pub async fn get_with_retry<R: FromResponse>(
+        &self,
+        owner: &str,
+        route: &str,
+    ) -> Result<R, ConfigError> {
+        from_response(route, self.send_with_retry(owner, route, None).await).await
+    }
+
+    /// GETs `route` through `cache`, fresh entries being served as is and
+    /// stale ones revalidated with their ETag.
+    pub async fn get_cached<R: FromResponse>(
+        &self,
+        owner: &str,
+        route: &str,
+        key: &str,
+        cache: &ContentCache,
+    ) -> Result<R, ConfigError> {
+        let cached = cache.get(key).await;
+        if let Some(entry) = cached.as_ref().filter(|entry| cache.is_fresh(entry)) {
+            cache.record_hit();
+            return parse_body(&entry.body).await;
+        }
+
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = cached
+            .as_ref()
+            .and_then(|entry| entry.etag.as_deref())
+            .and_then(|etag| HeaderValue::from_str(etag).ok())
+        {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+        let response = self.send_with_retry(owner, route, Some(headers)).await?;
+        if let (StatusCode::NOT_MODIFIED, Some(mut entry)) = (response.status(), cached) {
+            cache.record_revalidated();
+            entry.fetched_at = bson::DateTime::now();
+            let value = parse_body(&entry.body).await;
+            cache.insert(entry).await;
+            return value;
+        }
+
+        cache.record_miss();
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        let response = check_status(route, Ok(response)).await?;
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(anyhow::Error::from)?;
+        let body = String::from_utf8(body.to_vec())?;
+        let value = parse_body(&body).await;
+        if value.is_ok() {
+            cache
+                .insert(CachedEntry {
+                    key: key.to_string(),
+                    etag,
+                    body,
+                    fetched_at: bson::DateTime::now(),
+                })
+                .await;
+        }
+        value
+    }
+
+    async fn send_with_retry(
+        &self,
+        owner: &str,
+        route: &str,
+        headers: Option<HeaderMap>,
+    ) -> octocrab::Result<http::Response<hyper::Body>> {
+        let octocrab = &self.octocrab_for(owner).await?;
+        self.retry("GET", route, true, move || {
+            octocrab._get_with_headers(route, headers.clone())
+        })
+        .await
+    }
+
+    /// Sends the request `send` makes until it succeeds or mustn't be retried.
+    /// Only rate-limited writes are retried, as GitHub didn't apply them: one that
+    /// failed or timed out may have been.
+    async fn retry<F, Fut, E>(
+        &self,
+        method: &str,
+        route: &str,
+        idempotent: bool,
+        send: F,
+    ) -> Result<http::Response<hyper::Body>, E>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<http::Response<hyper::Body>, E>>,
+        E: fmt::Display,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = send().await;
+            let (delay, reason) = match &result {
+                Ok(response) => {
+                    if let Ok(mut rate_limit) = self.rate_limit.lock() {
+                        rate_limit.record(response.headers());
+                    }
+                    let retryable =
+                        idempotent || is_rate_limited(response.status(), response.headers());
+                    (
+                        self.retry_config
+                            .retry_delay(response.status(), response.headers(), attempt)
+                            .filter(|_| retryable),
+                        response.status().to_string(),
+                    )
+                }
+                Err(e) if idempotent => (Some(self.retry_config.backoff(attempt)), e.to_string()),
+                Err(e) => (None, e.to_string()),
+            };
+
+            match delay {
+                Some(delay) if attempt < self.retry_config.max_retries => {
+                    attempt += 1;
+                    warn!(
+                        "{} {} failed with {}, retry {}/{} in {:?}",
+                        method, route, reason, attempt, self.retry_config.max_retries, delay
+                    );
+                    if let Ok(mut rate_limit) = self.rate_limit.lock() {
+                        rate_limit.retries += 1;
+                    }
+                    tokio::time::sleep(delay).await;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    pub async fn post<P: Serialize + ?Sized, R: FromResponse>(
+        &self,
+        owner: &str,
+        route: &str,
+        body: &P,
+    ) -> Result<R, ConfigError> {
+        let octocrab = &self.octocrab_for(owner).await?;
+        let result = self
+            .retry("POST", route, false, move || {
+                octocrab._post(route, Some(body))
+            })
+            .await;
+        from_response(route, result).await
+    }
+
+    /// POSTs a GraphQL query on behalf of `owner`.
+    pub async fn post_graphql<P: Serialize + ?Sized, R: FromResponse>(
+        &self,
+        owner: &str,
+        body: &P,
+    ) -> Result<R, ConfigError> {
+        let octocrab = &self.graphql_octocrab_for(owner).await?;
+        let result = self
+            .retry("POST", GRAPHQL_ROUTE, false, move || {
+                octocrab._post(GRAPHQL_ROUTE, Some(body))
+            })
+            .await;
+        from_response(GRAPHQL_ROUTE, result).await
+    }
+
+    pub async fn put<P: Serialize + ?Sized, R: FromResponse>(
+        &self,
+        owner: &str,
+        route: &str,
+        body: &P,
+    ) -> Result<R, ConfigError> {
+        let octocrab = &self.octocrab_for(owner).await?;
+        let result = self
+            .retry("PUT", route, false, move || {
+                octocrab._put(route, Some(body))
+            })
+            .await;
+        from_response(route, result).await
+    }
+
+    pub async fn patch<P: Serialize + ?Sized, R: FromResponse>(
+        &self,
+        owner: &str,
+        route: &str,
+        body: &P,
+    ) -> Result<R, ConfigError> {
+        let octocrab = &self.octocrab_for(owner).await?;
+        let result = self
+            .retry("PATCH", route, false, move || {
+                octocrab._patch(route, Some(body))
+            })
+            .await;
+        from_response(route, result).await
+    }
+
+    /// Downloads a raw file. Its URL points outside of the API, so it can't go
+    /// through octocrab which prepends the API base path, and private
+    /// repositories put a token in it that mustn't be logged.
+    pub async fn download(&self, download_url: &str) -> Result<String, ConfigError> {
+        let uri: Uri = download_url
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid download url {}: {}", download_url, e))?;
+        let response = self
+            .retry("GET", uri.path(), true, || async {
+                tokio::time::timeout(DOWNLOAD_TIMEOUT, self.raw_client.get(uri.clone()))
+                    .await
+                    .map_err(|_| anyhow::anyhow!("no response after {:?}", DOWNLOAD_TIMEOUT))?
+                    .map_err(anyhow::Error::from)
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Download of {} failed with {}",
+                uri.path(),
+                response.status()
+            )
+            .into());
+        }
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(anyhow::Error::from)?;
+        String::from_utf8(body.to_vec()).map_err(ConfigError::from)
+    }
+
+    pub async fn get_all_pages_with_retry<T: DeserializeOwned>(
+        &self,
+        owner: &str,
+        route: &str,
+    ) -> Result<Vec<T>, ConfigError> {
+        let mut page: Page<T> = self.get_with_retry(owner, route).await?;
+        let mut items = page.take_items();
+        while let Some(next_route) = page
+            .next
+            .as_ref()
+            .map(|uri| relative_route(uri, &self.base_path))
+        {
+            page = self.get_with_retry(owner, &next_route).await?;
+            items.append(&mut page.take_items());
+        }
+        Ok(items)
+    }
+}
+
+async fn from_response<R: FromResponse>(
+    route: &str,
+    result: octocrab::Result<http::Response<hyper::Body>>,
+) -> Result<R, ConfigError> {
+    let response = check_status(route, result).await?;
+    Ok(R::from_response(response).await?)
+}
+
+/// Octocrab errors lose the status of failed responses, which tells whether
+/// the resource is missing, forbidden or rate limited.
+async fn check_status(
+    route: &str,
+    result: octocrab::Result<http::Response<hyper::Body>>,
+) -> Result<http::Response<hyper::Body>, ConfigError> {
+    let response = result?;
+    let status = response.status();
+    let rate_limited = is_rate_limited(status, response.headers());
+    match octocrab::map_github_error(response).await {
+        Ok(response) => Ok(response),
+        Err(_) if rate_limited => Err(ConfigError::RateLimited(route.to_string())),
+        Err(_) if status == StatusCode::NOT_FOUND => Err(ConfigError::NotFound(route.to_string())),
+        Err(e) if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN => {
+            Err(ConfigError::Forbidden(e.to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads a cached body as if it were the response.
+async fn parse_body<R: FromResponse>(body: &str) -> Result<R, ConfigError> {
+    let response = http::Response::new(hyper::Body::from(body.to_string()));
+    Ok(R::from_response(response).await?)
+}
+
+/// Retries are handled by `GithubHost::retry`, with backoff.
+fn octocrab_builder(
+    base_uri: &str,
+) -> octocrab::Result<OctocrabBuilder<NoSvc, DefaultOctocrabBuilderConfig, NoAuth, NotLayerReady>> {
+    let mut builder = OctocrabBuilder::new();
+    builder.add_retry_config(octocrab::service::middleware::retry::RetryConfig::None);
+    builder.base_uri(base_uri)
+}
+
+/// Looks up the installation of the app on an organization, or else a user.
+async fn find_installation(app_octocrab: &Octocrab, owner: &str) -> octocrab::Result<Installation> {
+    let installation = match app_octocrab.apps().get_org_installation(owner).await {
+        Ok(installation) => installation,
+        Err(_) => {
+            app_octocrab
+                .get::<Installation, _, ()>(format!("/users/{}/installation", owner), None)
+                .await?
+        }
+    };
+    info!(
+        "Using installation {} on {}",
+        installation.id, installation.account.login
+    );
+    Ok(installation)
+}
+
+async fn install(app_octocrab: &Octocrab, owner: &str) -> octocrab::Result<Octocrab> {
+    let installation = find_installation(app_octocrab, owner).await?;
+    Ok(app_octocrab.installation(installation.id))
+}
+
+/// github.com serves its API on a subdomain, Enterprise servers under a path.
+/// A full URL is taken as the API base as is, e.g. for a local mock server.
+fn api_base(hostname: &str) -> (String, String) {
+    if let Some((_, address)) = hostname.split_once("://") {
+        let base_path = address
+            .find('/')
+            .map(|index| address[index..].trim_end_matches('/').to_string())
+            .unwrap_or_default();
+        (hostname.trim_end_matches('/').to_string(), base_path)
+    } else if hostname == "github.com" {
+        ("https://api.github.com".to_string(), String::new())
+    } else {
+        let base_path = "/api/v3".to_string();
+        (format!("https://{}{}", hostname, base_path), base_path)
+    }
+}
+
+/// Enterprise servers serve GraphQL next to their `/v3` REST API, github.com
+/// at the root of its API.
+fn graphql_base(base_uri: &str) -> String {
+    base_uri.strip_suffix("/v3").unwrap_or(base_uri).to_string()
+}
+
+/// Pagination links are absolute, while octocrab prepends the API base path to
+/// every URI it is given.
+fn relative_route(uri: &Uri, base_path: &str) -> String {
+    let path_and_query = uri
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    path_and_query
+        .strip_prefix(base_path)
+        .unwrap_or(path_and_query)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_route_strips_the_api_base_path() {
+        let uri: Uri = "https://github.example.com/api/v3/user/repos?per_page=100&page=2"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            relative_route(&uri, "/api/v3"),
+            "/user/repos?per_page=100&page=2"
+        );
+        assert_eq!(
+            relative_route(
+                &"https://api.github.com/user/repos?page=2".parse().unwrap(),
+                ""
+            ),
+            "/user/repos?page=2"
+        );
+    }
+
+    #[test]
+    fn graphql_is_served_next_to_the_rest_api() {
+        assert_eq!(
+            graphql_base("https://api.github.com"),
+            "https://api.github.com"
+        );
+        assert_eq!(
+            graphql_base("https://github.example.com/api/v3"),
+            "https://github.example.com/api"
+        );
+    }
+
+    #[test]
+    fn api_base_depends_on_the_host() {
+        assert_eq!(
+            api_base("github.com"),
+            ("https://api.github.com".to_string(), String::new())
+        );
+        assert_eq!(
+            api_base("github.example.com"),
+            (
+                "https://github.example.com/api/v3".to_string(),
+                "/api/v3".to_string()
+            )
+        );
+        assert_eq!(
+            api_base("http://127.0.0.1:8080/"),
+            ("http://127.0.0.1:8080".to_string(), String::new())
+        );
+        assert_eq!(
+            api_base("http://127.0.0.1:8080/api/v3"),
+            (
+                "http://127.0.0.1:8080/api/v3".to_string(),
+                "/api/v3".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn stack_location_defaults_to_the_configured_owner() {
+        assert_eq!(
+            StackLocation::parse("stack-a", "my-org").unwrap(),
+            StackLocation {
+                host: None,
+                owner: "my-org",
+                repo: "stack-a"
+            }
+        );
+        assert_eq!(
+            StackLocation::parse("other-org/stack-b", "my-org").unwrap(),
+            StackLocation {
+                host: None,
+                owner: "other-org",
+                repo: "stack-b"
+            }
+        );
+
+        let location = StackLocation::parse("github.com/someone/stack-c", "my-org").unwrap();
+        assert_eq!(location.host, Some("github.com"));
+        assert_eq!(
+            location.route("/git/trees/main"),
+            "/repos/someone/stack-c/git/trees/main"
+        );
+
+        assert!(StackLocation::parse("a/b/c/d", "my-org").is_err());
+        assert!(StackLocation::parse("my-org/", "my-org").is_err());
+    }
+}
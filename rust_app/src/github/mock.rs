@@ -0,0 +1,165 @@
+//! An in-process GitHub REST API serving the fixtures of `fixtures/`, which
+//! `GithubClient` reaches through its base URI.
+
+use rocket::serde::json::{from_str, json, Value};
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::auth::GithubAuth;
+use super::rate_limit::RetryConfig;
+use super::{encode_path, GithubClient};
+
+pub const ORGANIZATION: &str = "my-org";
+
+pub fn fixture(name: &str) -> Value {
+    let fixture = match name {
+        "user" => include_str!("fixtures/user.json"),
+        "repository" => include_str!("fixtures/repository.json"),
+        "commit" => include_str!("fixtures/commit.json"),
+        "content" => include_str!("fixtures/content.json"),
+        _ => panic!("No fixture {}", name),
+    };
+    from_str(fixture).unwrap()
+}
+
+pub fn repository(name: &str) -> Value {
+    let mut repository = fixture("repository");
+    repository["name"] = name.into();
+    repository["full_name"] = format!("{}/{}", ORGANIZATION, name).into();
+    repository
+}
+
+pub fn content(file: &str, sha: &str, content: &str) -> Value {
+    let mut item = fixture("content");
+    item["name"] = file.rsplit('/').next().unwrap_or(file).into();
+    item["path"] = file.into();
+    item["sha"] = sha.into();
+    item["size"] = content.len().into();
+    item["content"] = base64::encode(content).into();
+    item
+}
+
+pub struct MockGithub {
+    pub server: MockServer,
+}
+
+impl MockGithub {
+    /// Serves the authenticated user, which `GithubClient` checks on startup.
+    pub async fn start() -> Self {
+        let mock_github = MockGithub {
+            server: MockServer::start().await,
+        };
+        mock_github.get("/user", fixture("user")).await;
+        mock_github
+    }
+
+    pub async fn client(&self) -> GithubClient {
+        GithubClient::new(
+            GithubAuth::PersonalToken("token".to_string()),
+            ORGANIZATION.to_string(),
+            self.server.uri(),
+            RetryConfig::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    pub async fn get(&self, route: &str, body: Value) {
+        Mock::given(method("GET"))
+            .and(path(route))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// The repositories of the organization.
+    pub async fn list_repos(&self, repositories: Vec<Value>) {
+        self.get(
+            &format!("/orgs/{}/repos", ORGANIZATION),
+            Value::Array(repositories),
+        )
+        .await;
+    }
+
+    pub async fn repository(&self, name: &str) {
+        self.get(&repo_route(name, ""), repository(name)).await;
+    }
+
+    pub async fn commit(&self, repo: &str, reference: &str, sha: &str) {
+        let mut commit = fixture("commit");
+        commit["sha"] = sha.into();
+        self.get(
+            &repo_route(repo, &format!("/commits/{}", encode_path(reference))),
+            commit,
+        )
+        .await;
+    }
+
+    /// A recursive tree of `files`, as paths and blob SHAs, with their folders.
+    pub async fn tree(&self, repo: &str, sha: &str, files: &[(&str, &str)]) {
+        let mut entries: Vec<Value> = Vec::new();
+        let mut folders: Vec<String> = Vec::new();
+        for (file, blob_sha) in files {
+            let mut folder = String::new();
+            for part in file
+                .split('/')
+                .rev()
+                .skip(1)
+                .collect::<Vec<_>>()
+                .iter()
+                .rev()
+            {
+                folder = if folder.is_empty() {
+                    part.to_string()
+                } else {
+                    format!("{}/{}", folder, part)
+                };
+                if !folders.contains(&folder) {
+                    folders.push(folder.clone());
+                    entries.push(json!({ "path": folder, "type": "tree", "sha": "tree" }));
+                }
+            }
+            entries.push(json!({ "path": file, "type": "blob", "sha": blob_sha }));
+        }
+        self.get(
+            &repo_route(repo, &format!("/git/trees/{}", sha)),
+            json!({ "sha": sha, "tree": entries, "truncated": false }),
+        )
+        .await;
+    }
+
+    pub async fn blob(&self, repo: &str, sha: &str, content: &str) {
+        self.get(
+            &repo_route(repo, &format!("/git/blobs/{}", sha)),
+            json!({ "sha": sha, "content": base64::encode(content), "encoding": "base64" }),
+        )
+        .await;
+    }
+
+    /// A file of the contents API with its blob SHA, at `reference` or
+    /// whatever the ref asked for.
+    pub async fn file(
+        &self,
+        repo: &str,
+        file: &str,
+        reference: Option<&str>,
+        sha: &str,
+        body: &str,
+    ) {
+        let mock = Mock::given(method("GET")).and(path(repo_route(
+            repo,
+            &format!("/contents/{}", file.trim_start_matches('/')),
+        )));
+        let mock = match reference {
+            Some(reference) => mock.and(query_param("ref", reference)),
+            None => mock,
+        };
+        mock.respond_with(ResponseTemplate::new(200).set_body_json(content(file, sha, body)))
+            .mount(&self.server)
+            .await;
+    }
+}
+
+pub fn repo_route(repo: &str, suffix: &str) -> String {
+    format!("/repos/{}/{}{}", ORGANIZATION, repo, suffix)
+}
@@ -1,8 +1,37 @@
 use base64::decode;
 use log::info;
-use octocrab::{Octocrab, OctocrabBuilder};
+use octocrab::models::repos::{Content, ContentItems, RepoCommit};
+use octocrab::Page;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rocket::FromForm;
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
+
+pub mod auth;
+pub mod blame;
+pub mod cache;
+pub mod discovery;
+pub mod host;
+#[cfg(test)]
+pub mod mock;
+pub mod pull_request;
+pub mod rate_limit;
+
+use crate::source::{BlameRange, ConfigSource, SourceEntry, SourceTree};
+use auth::GithubAuth;
+use cache::{CacheConfig, ContentCache};
+use host::{GithubHost, StackLocation};
+use rate_limit::{RateLimitStatus, RetryConfig};
+
+/// Characters left as is in a path segment, the unreserved ones of RFC 3986.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
 
 #[derive(Serialize, Deserialize)]
 pub struct SerializableContentItems {
@@ -21,12 +50,95 @@ pub struct SerializableContent {
     pub r#type: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct GitTree {
+    pub tree: Vec<GitTreeEntry>,
+    pub truncated: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GitTreeEntry {
+    pub path: String,
+    pub r#type: String,
+    pub sha: String,
+}
+
+#[derive(Deserialize)]
+struct GitBlob {
+    content: String,
+    encoding: String,
+}
+
+impl From<GitTree> for SourceTree {
+    /// Submodules and symlinks are left out, as neither holds a config.
+    fn from(tree: GitTree) -> Self {
+        SourceTree {
+            entries: tree
+                .tree
+                .into_iter()
+                .filter(|entry| entry.r#type == "tree" || entry.r#type == "blob")
+                .map(|entry| SourceEntry {
+                    is_dir: entry.r#type == "tree",
+                    path: entry.path,
+                    id: entry.sha,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(FromForm, Debug, Default)]
+pub struct RepoFilter {
+    pub name_prefix: Option<String>,
+    pub archived: Option<bool>,
+    pub visibility: Option<String>,
+}
+
+impl RepoFilter {
+    pub fn matches(&self, repo: &octocrab::models::Repository) -> bool {
+        if let Some(name_prefix) = &self.name_prefix {
+            if !repo.name.starts_with(name_prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(archived) = self.archived {
+            if repo.archived.unwrap_or(false) != archived {
+                return false;
+            }
+        }
+        if let Some(visibility) = &self.visibility {
+            // Older GitHub Enterprise versions don't return the visibility field
+            let repo_visibility = match (&repo.visibility, repo.private) {
+                (Some(repo_visibility), _) => repo_visibility.as_str(),
+                (None, Some(true)) => "private",
+                (None, _) => "public",
+            };
+            if !repo_visibility.eq_ignore_ascii_case(visibility) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 #[derive(Debug)]
 pub enum ConfigError {
     NotFound(String),
+    Forbidden(String),
+    RateLimited(String),
     NoContent,
     DecodeError(base64::DecodeError),
     Utf8Error(std::string::FromUtf8Error),
+    /// A config file which isn't valid YAML, by path.
+    InvalidYaml(String, serde_yaml::Error),
+    /// A tree too large for GitHub to list in one call, by repository and reference.
+    TruncatedTree(String),
+    /// Values that can't be copied from one config file to the other, and why.
+    Unreconcilable(String),
+    /// A stack, or a path in it, which a request can't name, and why.
+    InvalidStack(String),
+    /// An operation the source of a stack doesn't have, and why.
+    Unsupported(String),
     OctocrabError(octocrab::Error),
     Other(anyhow::Error),
 }
@@ -59,9 +171,16 @@ impl fmt::Display for ConfigError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ConfigError::NotFound(file) => write!(f, "File not found: {}", file),
+            ConfigError::Forbidden(err) => write!(f, "Access denied: {}", err),
+            ConfigError::RateLimited(route) => write!(f, "Rate limited on {}", route),
             ConfigError::NoContent => write!(f, "No content available"),
             ConfigError::DecodeError(err) => write!(f, "Decode error: {}", err),
             ConfigError::Utf8Error(err) => write!(f, "UTF-8 conversion error: {}", err),
+            ConfigError::InvalidYaml(file, err) => write!(f, "Invalid YAML in {}: {}", file, err),
+            ConfigError::TruncatedTree(tree) => write!(f, "Tree {} is truncated", tree),
+            ConfigError::Unreconcilable(reason) => write!(f, "Cannot reconcile: {}", reason),
+            ConfigError::InvalidStack(reason) => write!(f, "{}", reason),
+            ConfigError::Unsupported(reason) => write!(f, "Unsupported: {}", reason),
             ConfigError::OctocrabError(err) => write!(f, "Octocrab error {}", err),
             ConfigError::Other(err) => write!(f, "Other error: {}", err),
         }
@@ -71,8 +190,12 @@ impl std::error::Error for ConfigError {}
 
 #[derive(Clone)]
 pub struct GithubClient {
-    octocrab: Octocrab,
+    default_host: GithubHost,
+    other_hosts: HashMap<String, GithubHost>,
     organization_name: String,
+    hostname_gh: String,
+    retry_config: RetryConfig,
+    content_cache: Arc<ContentCache>,
 }
 
 impl From<octocrab::models::repos::ContentItems> for SerializableContentItems {
@@ -100,71 +223,126 @@ impl From<octocrab::models::repos::Content> for SerializableContent {
 
 impl GithubClient {
     pub async fn new(
-        access_token: String,
+        auth: GithubAuth,
         organization_name: String,
         hostname_gh: String,
+        retry_config: RetryConfig,
     ) -> Result<Self, anyhow::Error> {
-        let octocrab = OctocrabBuilder::new()
-            .base_uri(format!("https://{}/api/v3", hostname_gh))?
-            .personal_token(access_token)
-            .build()?;
-
-        match octocrab.current().user().await {
-            Ok(user) => {
-                info!(
-                    "Authenticated as {}, {} & {}",
-                    user.login, user.r#type, user.id
-                )
-            }
-            Err(e) => {
-                info!("Authentication failed. Please check your access token");
-                return Err(e.into());
-            }
-        }
+        let default_host =
+            GithubHost::connect(&hostname_gh, auth, &organization_name, retry_config.clone())
+                .await?;
 
         Ok(GithubClient {
-            octocrab,
+            default_host,
+            other_hosts: HashMap::new(),
             organization_name,
+            hostname_gh,
+            retry_config,
+            content_cache: Arc::new(ContentCache::new(CacheConfig::default())),
         })
     }
 
-    pub async fn get_number_of_repo(&self) -> Result<usize, anyhow::Error> {
-        let repos = self
-            .octocrab
-            .current()
-            .list_repos_for_authenticated_user()
-            .page(1)
-            .send()
-            .await;
+    pub fn with_content_cache(mut self, content_cache: ContentCache) -> Self {
+        self.content_cache = Arc::new(content_cache);
+        self
+    }
 
-        match repos {
-            Ok(repo) => Ok(repo.items.len()),
-            Err(e) => {
-                return Err(e.into());
-            }
+    pub fn content_cache(&self) -> &ContentCache {
+        &self.content_cache
+    }
+
+    /// Makes stacks written `hostname/owner/repo` reachable. An app is first
+    /// looked up on `default_owner`, as owners differ from one host to another.
+    pub async fn add_host(
+        &mut self,
+        hostname: String,
+        auth: GithubAuth,
+        default_owner: &str,
+    ) -> Result<(), anyhow::Error> {
+        let host =
+            GithubHost::connect(&hostname, auth, default_owner, self.retry_config.clone()).await?;
+        self.other_hosts.insert(hostname, host);
+        Ok(())
+    }
+
+    fn locate<'a>(
+        &'a self,
+        stack: &'a str,
+    ) -> Result<(&'a GithubHost, StackLocation<'a>), ConfigError> {
+        let location = StackLocation::parse(stack, &self.organization_name)?;
+        let host = match location.host {
+            None => &self.default_host,
+            Some(hostname) if hostname == self.hostname_gh => &self.default_host,
+            Some(hostname) => self.other_hosts.get(hostname).ok_or_else(|| {
+                ConfigError::InvalidStack(format!("Unknown Github host {}", hostname))
+            })?,
+        };
+        Ok((host, location))
+    }
+
+    /// File and directory reads go through the content cache, keyed by host
+    /// and route.
+    async fn get_cached<R: octocrab::FromResponse>(
+        &self,
+        host: &GithubHost,
+        location: &StackLocation<'_>,
+        route: &str,
+    ) -> Result<R, ConfigError> {
+        let key = format!("{}{}", location.host.unwrap_or(&self.hostname_gh), route);
+        host.get_cached(location.owner, route, &key, &self.content_cache)
+            .await
+    }
+
+    fn contents_route(location: &StackLocation, path: &str, reference: Option<&str>) -> String {
+        let route = location.route(&format!("/contents/{}", encode_path(path)));
+        match reference {
+            Some(reference) => format!("{}?{}", route, encode_query(&[("ref", reference)])),
+            None => route,
+        }
+    }
+
+    /// The latest known quota of the default host, refreshed through the
+    /// rate-limit endpoint which doesn't count against it.
+    pub async fn get_rate_limit(&self) -> RateLimitStatus {
+        if let Err(e) = self
+            .default_host
+            .get_with_retry::<IgnoredAny>(&self.organization_name, "/rate_limit")
+            .await
+        {
+            info!("Cannot refresh rate limit, because {}", e);
         }
+        self.default_host.rate_limit()
+    }
+
+    pub async fn get_number_of_repo(&self, filter: &RepoFilter) -> Result<usize, ConfigError> {
+        self.get_list_of_repos(filter)
+            .await
+            .map(|repos| repos.len())
     }
 
     pub async fn get_list_of_repos(
         &self,
-    ) -> Result<Vec<octocrab::models::Repository>, anyhow::Error> {
+        filter: &RepoFilter,
+    ) -> Result<Vec<octocrab::models::Repository>, ConfigError> {
         let repos = self
-            .octocrab
-            .current()
-            .list_repos_for_authenticated_user()
-            .per_page(100)
-            .send()
+            .default_host
+            .get_all_pages_with_retry::<octocrab::models::Repository>(
+                &self.organization_name,
+                &self.default_host.owner_repos_route(&self.organization_name),
+            )
             .await;
 
         match repos {
-            Ok(page) => {
-                if page.next.is_some() {
-                    info!("There are some more pages to fetch !")
-                }
-                Ok(page.items)
+            Ok(repos) => {
+                info!("Fetched {} repositories", repos.len());
+                Ok(repos
+                    .into_iter()
+                    .filter(|repo| filter.matches(repo))
+                    .collect())
             }
             Err(e) => {
-                return Err(e.into());
+                error!("Cannot fetch every page of repositories, because {}", e);
+                Err(e)
             }
         }
     }
@@ -172,16 +350,15 @@ impl GithubClient {
     pub async fn get_repo(
         &self,
         repository_name: &str,
-    ) -> Result<octocrab::models::Repository, anyhow::Error> {
+    ) -> Result<octocrab::models::Repository, ConfigError> {
+        let (host, location) = self.locate(repository_name)?;
         info!(
             "Getting repository {} for organization {}",
-            self.organization_name, repository_name
+            location.repo, location.owner
         );
 
-        let result = self
-            .octocrab
-            .repos(&self.organization_name, repository_name)
-            .get()
+        let result: Result<octocrab::models::Repository, ConfigError> = host
+            .get_with_retry(location.owner, &location.route(""))
             .await;
 
         match result {
@@ -197,7 +374,7 @@ impl GithubClient {
                     "Cannot retrieve info for {}, because {}",
                     repository_name, e
                 );
-                Err(e.into())
+                Err(e)
             }
         }
     }
@@ -206,13 +383,14 @@ impl GithubClient {
         &self,
         repository_name: &str,
         folder_path: &str,
-    ) -> Result<SerializableContentItems, anyhow::Error> {
-        let content_items = self
-            .octocrab
-            .repos(&self.organization_name, repository_name)
-            .get_content()
-            .path(folder_path)
-            .send()
+    ) -> Result<SerializableContentItems, ConfigError> {
+        let (host, location) = self.locate(repository_name)?;
+        let content_items: Result<ContentItems, ConfigError> = self
+            .get_cached(
+                host,
+                &location,
+                &Self::contents_route(&location, folder_path, None),
+            )
             .await;
 
         match content_items {
@@ -225,7 +403,7 @@ impl GithubClient {
                     "Cannot retrieve info for {}, because {}",
                     repository_name, e
                 );
-                Err(e.into())
+                Err(e)
             }
         }
     }
@@ -234,13 +412,15 @@ impl GithubClient {
         &self,
         repository_name: &str,
         path: &str,
-    ) -> Result<SerializableContentItems, anyhow::Error> {
-        let content_items = self
-            .octocrab
-            .repos(&self.organization_name, repository_name)
-            .get_content()
-            .path(format!("/{}", path))
-            .send()
+        reference: Option<&str>,
+    ) -> Result<SerializableContentItems, ConfigError> {
+        let (host, location) = self.locate(repository_name)?;
+        let content_items: Result<ContentItems, ConfigError> = self
+            .get_cached(
+                host,
+                &location,
+                &Self::contents_route(&location, path, reference),
+            )
             .await;
 
         match content_items {
@@ -253,7 +433,7 @@ impl GithubClient {
                     "Cannot retrieve info for {}, because {}",
                     repository_name, e
                 );
-                Err(e.into())
+                Err(e)
             }
         }
     }
@@ -262,20 +442,21 @@ impl GithubClient {
         &self,
         stack_a: &str,
         file: &str,
-    ) -> Result<SerializableContentItems, anyhow::Error> {
-        let content = self
-            .octocrab
-            .repos(&self.organization_name, stack_a)
-            .get_content()
-            .path(file)
-            .send()
+    ) -> Result<SerializableContentItems, ConfigError> {
+        let (host, location) = self.locate(stack_a)?;
+        let content: Result<ContentItems, ConfigError> = self
+            .get_cached(
+                host,
+                &location,
+                &Self::contents_route(&location, file, None),
+            )
             .await;
 
         match content {
             Ok(cont) => Ok(cont.into()),
             Err(e) => {
                 error!("Cannot retrieve content ; {}", e);
-                Err(e.into())
+                Err(e)
             }
         }
     }
@@ -285,12 +466,13 @@ impl GithubClient {
         stack_a: &str,
         file: &str,
     ) -> Result<String, anyhow::Error> {
-        let content = self
-            .octocrab
-            .repos(&self.organization_name, stack_a)
-            .get_content()
-            .path(file)
-            .send()
+        let (host, location) = self.locate(stack_a)?;
+        let content: ContentItems = self
+            .get_cached(
+                host,
+                &location,
+                &Self::contents_route(&location, file, None),
+            )
             .await?;
 
         if let Some(content_item) = content.items.into_iter().next() {
@@ -300,7 +482,7 @@ impl GithubClient {
                 // Trim the string to remove any leading or trailing whitespace
                 let trimmed_encoded_string = cleaned_encoded_string.trim();
                 // Now you can pass a reference to `trimmed_encoded_string` to `decode`
-                let decoded = decode(&trimmed_encoded_string);
+                let decoded = decode(trimmed_encoded_string);
 
                 match decoded {
                     Ok(dec) => {
@@ -326,19 +508,89 @@ impl GithubClient {
         }
     }
 
+    /// Resolves a branch, tag or SHA to the commit SHA it points to, `None`
+    /// standing for the head of the default branch.
+    pub async fn resolve_commit_sha(
+        &self,
+        repository_name: &str,
+        reference: Option<&str>,
+    ) -> Result<String, ConfigError> {
+        let reference = reference.unwrap_or("HEAD");
+        let (host, location) = self.locate(repository_name)?;
+        let commit: RepoCommit = host
+            .get_with_retry(
+                location.owner,
+                &location.route(&format!("/commits/{}", encode_path(reference))),
+            )
+            .await
+            .map_err(|e| {
+                error!(
+                    "Cannot resolve {} for {}, because {}",
+                    reference, repository_name, e
+                );
+                e
+            })?;
+
+        info!(
+            "Resolved {} for {} to commit {}",
+            reference, repository_name, commit.sha
+        );
+        Ok(commit.sha)
+    }
+
+    /// Lists the commits touching `file`, newest first, starting from `reference`.
+    pub async fn get_file_commits(
+        &self,
+        repository_name: &str,
+        file: &str,
+        reference: Option<&str>,
+        count: u8,
+    ) -> Result<Vec<RepoCommit>, ConfigError> {
+        let count = count.to_string();
+        let mut parameters = vec![("path", file), ("per_page", count.as_str())];
+        if let Some(reference) = reference {
+            parameters.push(("sha", reference));
+        }
+        let (host, location) = self.locate(repository_name)?;
+        let route = location.route(&format!("/commits?{}", encode_query(&parameters)));
+
+        match host
+            .get_with_retry::<Page<RepoCommit>>(location.owner, &route)
+            .await
+        {
+            Ok(page) => {
+                info!(
+                    "Found {} commits for {} in {}",
+                    page.items.len(),
+                    file,
+                    repository_name
+                );
+                Ok(page.items)
+            }
+            Err(e) => {
+                error!(
+                    "Cannot list commits of {} for {}, because {}",
+                    file, repository_name, e
+                );
+                Err(e)
+            }
+        }
+    }
+
     pub async fn get_config_from_stack_and_file_string(
         &self,
         stack: &str,
         file: &str,
+        reference: Option<&str>,
     ) -> Result<String, ConfigError> {
-        let content = self
-            .octocrab
-            .repos(&self.organization_name, stack)
-            .get_content()
-            .path(file)
-            .send()
-            .await
-            .map_err(ConfigError::from)?;
+        let (host, location) = self.locate(stack)?;
+        let content: ContentItems = self
+            .get_cached(
+                host,
+                &location,
+                &Self::contents_route(&location, file, reference),
+            )
+            .await?;
 
         let content_item = content
             .items
@@ -346,14 +598,451 @@ impl GithubClient {
             .next()
             .ok_or(ConfigError::NotFound(file.to_string()))?;
 
+        self.read_content_item(stack, content_item).await
+    }
+
+    /// The contents API leaves `content` empty for files over 1 MB, those are
+    /// read through the blob API instead, or their `download_url` as a last resort.
+    async fn read_content_item(
+        &self,
+        repository_name: &str,
+        content_item: Content,
+    ) -> Result<String, ConfigError> {
+        match content_item.content.as_deref() {
+            Some(content_string) if !content_string.trim().is_empty() => {
+                return decode_content(content_string)
+            }
+            _ if content_item.r#type != "file" => return Err(ConfigError::NoContent),
+            _ if content_item.size == 0 => return Ok(String::new()),
+            _ => {}
+        }
+
+        info!(
+            "Content of {} in {} isn't inlined ({} bytes), reading blob {}",
+            content_item.path, repository_name, content_item.size, content_item.sha
+        );
+        match self.get_blob(repository_name, &content_item.sha).await {
+            Ok(content) => Ok(content),
+            Err(e) => match content_item.download_url {
+                Some(download_url) => {
+                    warn!(
+                        "Cannot read blob {} of {}, because {}, downloading it instead",
+                        content_item.sha, repository_name, e
+                    );
+                    let (host, _) = self.locate(repository_name)?;
+                    host.download(&download_url).await
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Fetches the whole tree of a commit in one call, instead of listing
+    /// folders one by one through the contents API.
+    pub async fn get_tree(
+        &self,
+        repository_name: &str,
+        reference: &str,
+    ) -> Result<GitTree, ConfigError> {
+        let (host, location) = self.locate(repository_name)?;
+        let route = location.route(&format!("/git/trees/{}?recursive=1", reference));
+        let tree: GitTree = self
+            .get_cached(host, &location, &route)
+            .await
+            .map_err(|e| {
+                error!(
+                    "Cannot retrieve tree {} for {}, because {}",
+                    reference, repository_name, e
+                );
+                e
+            })?;
+
+        // Comparing part of the files would pass drift checks on partial data
+        if tree.truncated {
+            error!(
+                "Tree {} of {} is truncated, its files can't all be listed",
+                reference, repository_name
+            );
+            return Err(ConfigError::TruncatedTree(format!(
+                "{}@{}",
+                repository_name, reference
+            )));
+        }
+        info!(
+            "Retrieved tree {} of {} with {} entries",
+            reference,
+            repository_name,
+            tree.tree.len()
+        );
+        Ok(tree)
+    }
+
+    pub async fn get_blob(
+        &self,
+        repository_name: &str,
+        blob_sha: &str,
+    ) -> Result<String, ConfigError> {
+        let (host, location) = self.locate(repository_name)?;
+        let route = location.route(&format!("/git/blobs/{}", blob_sha));
+        let blob: GitBlob = self.get_cached(host, &location, &route).await?;
+
+        if blob.encoding != "base64" {
+            return Err(
+                anyhow::Error::msg(format!("Unsupported blob encoding {}", blob.encoding)).into(),
+            );
+        }
+        decode_content(&blob.content)
+    }
+}
+
+/// Percent-encodes each segment of a file path, dropping empty ones.
+pub(crate) fn encode_path(path: &str) -> String {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub(crate) fn encode_query(parameters: &[(&str, &str)]) -> String {
+    serde_urlencoded::to_string(parameters).unwrap_or_default()
+}
+
+pub(crate) fn decode_content(content_string: &str) -> Result<String, ConfigError> {
+    let cleaned_encoded_string = content_string.replace("\n", "").replace("\r", "");
+    let trimed_encoded_string = cleaned_encoded_string.trim();
+
+    let decoded_string = decode(trimed_encoded_string).map_err(ConfigError::from)?;
+
+    String::from_utf8(decoded_string).map_err(ConfigError::from)
+}
+
+#[rocket::async_trait]
+impl ConfigSource for GithubClient {
+    async fn resolve_revision(
+        &self,
+        stack: &str,
+        reference: Option<&str>,
+    ) -> Result<String, ConfigError> {
+        self.resolve_commit_sha(stack, reference).await
+    }
+
+    async fn list_files(&self, stack: &str, revision: &str) -> Result<SourceTree, ConfigError> {
+        self.get_tree(stack, revision).await.map(SourceTree::from)
+    }
+
+    async fn read_file(
+        &self,
+        stack: &str,
+        path: &str,
+        revision: &str,
+    ) -> Result<String, ConfigError> {
+        self.get_config_from_stack_and_file_string(stack, path, Some(revision))
+            .await
+    }
+
+    /// Blobs are content-addressed, so the tree entry is enough to read them.
+    async fn read_entry(
+        &self,
+        stack: &str,
+        _revision: &str,
+        entry: &SourceEntry,
+    ) -> Result<String, ConfigError> {
+        self.get_blob(stack, &entry.id).await
+    }
+
+    async fn blame_file(
+        &self,
+        stack: &str,
+        path: &str,
+        revision: &str,
+    ) -> Result<Vec<BlameRange>, ConfigError> {
+        self.get_blame(stack, path, revision).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, ResponseTemplate};
+
+    #[test]
+    fn git_tree_keeps_folders_and_blobs() {
+        let tree: GitTree = rocket::serde::json::from_str(
+            r#"{
+                "tree": [
+                    {"path": "configs", "type": "tree", "sha": "t1"},
+                    {"path": "configs/config-overrides.yml", "type": "blob", "sha": "b1"},
+                    {"path": "vendor/lib", "type": "commit", "sha": "c1"}
+                ],
+                "truncated": false
+            }"#,
+        )
+        .unwrap();
+
+        let tree = SourceTree::from(tree);
+
+        assert_eq!(tree.entries.len(), 2);
+        assert!(tree.entries[0].is_dir);
+        assert_eq!(tree.entries[1].path, "configs/config-overrides.yml");
+        assert_eq!(tree.entries[1].id, "b1");
+    }
+
+    #[tokio::test]
+    async fn truncated_trees_are_an_error() {
+        let github = mock::MockGithub::start().await;
+        github
+            .get(
+                &mock::repo_route("stack-a", "/git/trees/c1"),
+                rocket::serde::json::json!({ "sha": "c1", "tree": [], "truncated": true }),
+            )
+            .await;
+        let github_client = github.client().await;
+
+        assert!(matches!(
+            github_client.list_files("stack-a", "c1").await,
+            Err(ConfigError::TruncatedTree(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn lists_filtered_repos_from_the_mock() {
+        let github = mock::MockGithub::start().await;
+        // Tokens see the repos of every organization of their user
+        Mock::given(method("GET"))
+            .and(path("/user/repos"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+            .expect(0)
+            .mount(&github.server)
+            .await;
+        github
+            .list_repos(vec![
+                mock::repository("stack-a"),
+                mock::repository("stack-b"),
+                mock::repository("tooling"),
+            ])
+            .await;
+        let github_client = github.client().await;
+
+        let filter = RepoFilter {
+            name_prefix: Some("stack-".to_string()),
+            ..RepoFilter::default()
+        };
+        let repos = github_client.get_list_of_repos(&filter).await.unwrap();
 
-        let content_string = content_item.content.ok_or(ConfigError::NoContent)?;
+        assert_eq!(
+            repos
+                .iter()
+                .map(|repo| repo.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["stack-a", "stack-b"]
+        );
+    }
 
-        let cleaned_encoded_string = content_string.replace("\n", "").replace("\r", "");
-        let trimed_encoded_string = cleaned_encoded_string.trim();
+    #[tokio::test]
+    async fn resolves_references_with_reserved_characters() {
+        let github = mock::MockGithub::start().await;
+        github.commit("stack-a", "feature/x?y", "c1").await;
+        let github_client = github.client().await;
 
-        let decoded_string = decode(trimed_encoded_string).map_err(ConfigError::from)?;
+        let sha = github_client
+            .resolve_commit_sha("stack-a", Some("feature/x?y"))
+            .await
+            .unwrap();
 
-        String::from_utf8(decoded_string).map_err(ConfigError::from)
+        assert_eq!(sha, "c1");
+    }
+
+    #[tokio::test]
+    async fn reads_a_stack_at_a_commit_through_the_source() {
+        let github = mock::MockGithub::start().await;
+        github.commit("stack-a", "main", "c1").await;
+        github
+            .tree(
+                "stack-a",
+                "c1",
+                &[("configs/svc/config-overrides.yml", "b1")],
+            )
+            .await;
+        github.blob("stack-a", "b1", "port: 80\n").await;
+        let github_client = github.client().await;
+
+        let revision = github_client
+            .resolve_revision("stack-a", Some("main"))
+            .await
+            .unwrap();
+        let tree = github_client
+            .list_files("stack-a", &revision)
+            .await
+            .unwrap();
+        let entry = tree
+            .entries
+            .iter()
+            .find(|entry| entry.path == "configs/svc/config-overrides.yml")
+            .unwrap();
+
+        assert_eq!(revision, "c1");
+        assert!(tree
+            .entries
+            .iter()
+            .any(|entry| entry.is_dir && entry.path == "configs/svc"));
+        assert_eq!(
+            github_client
+                .read_entry("stack-a", &revision, entry)
+                .await
+                .unwrap(),
+            "port: 80\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_reads_are_revalidated_once_stale() {
+        let github = mock::MockGithub::start().await;
+        let route = mock::repo_route("stack-a", "/git/blobs/b1");
+        let blob = rocket::serde::json::json!({
+            "sha": "b1", "content": base64::encode("port: 80\n"), "encoding": "base64"
+        });
+        Mock::given(method("GET"))
+            .and(path(route.as_str()))
+            .and(header("If-None-Match", "\"b1\""))
+            .respond_with(ResponseTemplate::new(304))
+            .with_priority(1)
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(route.as_str()))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"b1\"")
+                    .set_body_json(blob),
+            )
+            .expect(2)
+            .mount(&github.server)
+            .await;
+        let fresh_client = github.client().await;
+        let stale_client =
+            github
+                .client()
+                .await
+                .with_content_cache(ContentCache::new(CacheConfig {
+                    ttl: Duration::ZERO,
+                    ..CacheConfig::default()
+                }));
+
+        for github_client in [&fresh_client, &fresh_client, &stale_client, &stale_client] {
+            assert_eq!(
+                github_client.get_blob("stack-a", "b1").await.unwrap(),
+                "port: 80\n"
+            );
+        }
+
+        // Both caches hold the same blob
+        let bytes = fresh_client.content_cache().stats().bytes;
+        assert!(bytes > 0);
+        assert_eq!(
+            fresh_client.content_cache().stats(),
+            cache::CacheStats {
+                hits: 1,
+                misses: 1,
+                revalidated: 0,
+                entries: 1,
+                bytes
+            }
+        );
+        assert_eq!(
+            stale_client.content_cache().stats(),
+            cache::CacheStats {
+                hits: 1,
+                misses: 1,
+                revalidated: 1,
+                entries: 1,
+                bytes
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_encode_the_path_and_are_retried_once_rate_limited() {
+        let github = mock::MockGithub::start().await;
+        let route = mock::repo_route("stack-a", "/contents/my%20svc/a%23b.yml");
+        Mock::given(method("PUT"))
+            .and(path(route.as_str()))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(route.as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(rocket::serde::json::json!({})))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(mock::repo_route("stack-b", "/contents/a.yml")))
+            .respond_with(ResponseTemplate::new(502))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        let github_client = github.client().await;
+
+        github_client
+            .put_file(
+                "stack-a",
+                "main",
+                "/my svc/a#b.yml",
+                "port: 80\n",
+                None,
+                "Sync",
+            )
+            .await
+            .unwrap();
+        assert!(github_client
+            .put_file("stack-b", "main", "a.yml", "port: 80\n", None, "Sync")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn large_files_are_downloaded_when_their_blob_is_unreadable() {
+        let github = mock::MockGithub::start().await;
+        let file = "configs/svc/config-overrides.yml";
+        let mut item = mock::content(file, "b1", "");
+        item["size"] = 2_000_000.into();
+        item["download_url"] = format!("{}/raw/{}?token=secret", github.server.uri(), file).into();
+        github
+            .get(
+                &mock::repo_route("stack-a", &format!("/contents/{}", file)),
+                item,
+            )
+            .await;
+        let raw_route = format!("/raw/{}", file);
+        Mock::given(method("GET"))
+            .and(path(raw_route.as_str()))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(raw_route.as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_string("port: 80\n"))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        let github_client = github.client().await;
+
+        assert_eq!(
+            github_client
+                .get_config_from_stack_and_file_string("stack-a", file, None)
+                .await
+                .unwrap(),
+            "port: 80\n"
+        );
     }
 }
@@ -0,0 +1,327 @@
+use base64::encode;
+use octocrab::models::repos::ContentItems;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+
+use super::{ConfigError, GithubClient};
+
+#[derive(Deserialize)]
+struct RepoDefaultBranch {
+    default_branch: String,
+}
+
+#[derive(Deserialize)]
+struct GitRefObject {
+    sha: String,
+}
+
+#[derive(Deserialize)]
+struct GitRef {
+    object: GitRefObject,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PullRequest {
+    pub number: u64,
+    pub html_url: String,
+}
+
+#[derive(Deserialize)]
+struct PullRequestFile {
+    filename: String,
+}
+
+#[derive(Deserialize)]
+struct IssueComment {
+    id: u64,
+    body: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitState {
+    Pending,
+    Success,
+    Failure,
+    Error,
+}
+
+/// A file along with its blob SHA, which the contents API needs to update it.
+pub struct ShaFile {
+    pub content: String,
+    pub sha: String,
+}
+
+impl GithubClient {
+    /// The default branch and the commit it points to.
+    pub async fn get_default_branch(&self, stack: &str) -> Result<(String, String), ConfigError> {
+        let (host, location) = self.locate(stack)?;
+        let repo: RepoDefaultBranch = host
+            .get_with_retry(location.owner, &location.route(""))
+            .await?;
+        let git_ref: GitRef = host
+            .get_with_retry(
+                location.owner,
+                &location.route(&format!("/git/ref/heads/{}", repo.default_branch)),
+            )
+            .await?;
+        Ok((repo.default_branch, git_ref.object.sha))
+    }
+
+    pub async fn get_file_with_sha(
+        &self,
+        stack: &str,
+        file: &str,
+        reference: &str,
+    ) -> Result<ShaFile, ConfigError> {
+        let (host, location) = self.locate(stack)?;
+        let content: ContentItems = host
+            .get_with_retry(
+                location.owner,
+                &Self::contents_route(&location, file, Some(reference)),
+            )
+            .await?;
+        let content_item = content
+            .items
+            .into_iter()
+            .next()
+            .ok_or(ConfigError::NotFound(file.to_string()))?;
+
+        let sha = content_item.sha.clone();
+        let content = self.read_content_item(stack, content_item).await?;
+        Ok(ShaFile { content, sha })
+    }
+
+    pub async fn create_branch(
+        &self,
+        stack: &str,
+        branch: &str,
+        sha: &str,
+    ) -> Result<(), ConfigError> {
+        let (host, location) = self.locate(stack)?;
+        let body = rocket::serde::json::json!({
+            "ref": format!("refs/heads/{}", branch),
+            "sha": sha,
+        });
+        host.post::<_, IgnoredAny>(location.owner, &location.route("/git/refs"), &body)
+            .await?;
+        info!("Created branch {} on {} at {}", branch, stack, sha);
+        Ok(())
+    }
+
+    /// Commits `content` to `file` on `branch`, `previous_sha` being the blob
+    /// it replaces, if the file exists.
+    pub async fn put_file(
+        &self,
+        stack: &str,
+        branch: &str,
+        file: &str,
+        content: &str,
+        previous_sha: Option<&str>,
+        message: &str,
+    ) -> Result<(), ConfigError> {
+        let (host, location) = self.locate(stack)?;
+        let mut body = rocket::serde::json::json!({
+            "message": message,
+            "content": encode(content),
+            "branch": branch,
+        });
+        if let Some(previous_sha) = previous_sha {
+            body["sha"] = previous_sha.into();
+        }
+        host.put::<_, IgnoredAny>(
+            location.owner,
+            &Self::contents_route(&location, file, None),
+            &body,
+        )
+        .await?;
+        info!("Committed {} on branch {} of {}", file, branch, stack);
+        Ok(())
+    }
+
+    pub async fn open_pull_request(
+        &self,
+        stack: &str,
+        head: &str,
+        base: &str,
+        title: &str,
+        body: &str,
+    ) -> Result<PullRequest, ConfigError> {
+        let (host, location) = self.locate(stack)?;
+        let body = rocket::serde::json::json!({
+            "title": title,
+            "head": head,
+            "base": base,
+            "body": body,
+        });
+        let pull_request: PullRequest = host
+            .post(location.owner, &location.route("/pulls"), &body)
+            .await?;
+        info!("Opened pull request #{} on {}", pull_request.number, stack);
+        Ok(pull_request)
+    }
+
+    /// Paths of the files a pull request adds, changes or removes.
+    pub async fn list_pull_request_files(
+        &self,
+        stack: &str,
+        number: u64,
+    ) -> Result<Vec<String>, ConfigError> {
+        let (host, location) = self.locate(stack)?;
+        let files: Vec<PullRequestFile> = host
+            .get_all_pages_with_retry(
+                location.owner,
+                &location.route(&format!("/pulls/{}/files?per_page=100", number)),
+            )
+            .await?;
+        Ok(files.into_iter().map(|file| file.filename).collect())
+    }
+
+    /// Updates the comment of the pull request containing `marker`, or posts
+    /// it, so that each push doesn't add another comment.
+    pub async fn upsert_comment(
+        &self,
+        stack: &str,
+        number: u64,
+        marker: &str,
+        body: &str,
+    ) -> Result<(), ConfigError> {
+        let (host, location) = self.locate(stack)?;
+        let comments: Vec<IssueComment> = host
+            .get_all_pages_with_retry(
+                location.owner,
+                &location.route(&format!("/issues/{}/comments?per_page=100", number)),
+            )
+            .await?;
+        let body = rocket::serde::json::json!({ "body": body });
+
+        match comments.into_iter().find(|comment| {
+            comment
+                .body
+                .as_deref()
+                .is_some_and(|body| body.contains(marker))
+        }) {
+            Some(comment) => {
+                host.patch::<_, IgnoredAny>(
+                    location.owner,
+                    &location.route(&format!("/issues/comments/{}", comment.id)),
+                    &body,
+                )
+                .await?;
+                info!("Updated comment {} on #{} of {}", comment.id, number, stack);
+            }
+            None => {
+                host.post::<_, IgnoredAny>(
+                    location.owner,
+                    &location.route(&format!("/issues/{}/comments", number)),
+                    &body,
+                )
+                .await?;
+                info!("Commented on #{} of {}", number, stack);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn set_commit_status(
+        &self,
+        stack: &str,
+        sha: &str,
+        state: CommitState,
+        context: &str,
+        description: &str,
+    ) -> Result<(), ConfigError> {
+        let (host, location) = self.locate(stack)?;
+        let body = rocket::serde::json::json!({
+            "state": state,
+            "context": context,
+            "description": description,
+        });
+        host.post::<_, IgnoredAny>(
+            location.owner,
+            &location.route(&format!("/statuses/{}", sha)),
+            &body,
+        )
+        .await?;
+        info!("Set status {:?} on {} of {}", state, sha, stack);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::mock::{repo_route, MockGithub};
+    use rocket::serde::json::json;
+    use wiremock::matchers::{body_json, method, path};
+    use wiremock::{Mock, ResponseTemplate};
+
+    const MARKER: &str = "<!-- drift -->";
+
+    #[tokio::test]
+    async fn upserts_the_comment_with_the_marker() {
+        let github = MockGithub::start().await;
+        github
+            .get(
+                &repo_route("stack-a", "/issues/7/comments"),
+                json!([
+                    { "id": 1, "body": "Looks good" },
+                    { "id": 2, "body": format!("{}\nOld drift", MARKER) }
+                ]),
+            )
+            .await;
+        github
+            .get(&repo_route("stack-a", "/issues/8/comments"), json!([]))
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path(repo_route("stack-a", "/issues/comments/2")))
+            .and(body_json(json!({ "body": "New drift" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(repo_route("stack-a", "/issues/8/comments")))
+            .and(body_json(json!({ "body": "New drift" })))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({})))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        let github_client = github.client().await;
+
+        for number in [7, 8] {
+            github_client
+                .upsert_comment("stack-a", number, MARKER, "New drift")
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn sets_the_commit_status() {
+        let github = MockGithub::start().await;
+        Mock::given(method("POST"))
+            .and(path(repo_route("stack-a", "/statuses/c1")))
+            .and(body_json(json!({
+                "state": "failure",
+                "context": "compare-configs/drift",
+                "description": "1 drifting keys not on the allow-list"
+            })))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({})))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        let github_client = github.client().await;
+
+        github_client
+            .set_commit_status(
+                "stack-a",
+                "c1",
+                CommitState::Failure,
+                "compare-configs/drift",
+                "1 drifting keys not on the allow-list",
+            )
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::{HeaderMap, StatusCode};
+use rand::Rng;
+use serde::Serialize;
+
+#[derive(Clone)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Reads `GH_MAX_RETRIES`, `GH_RETRY_BASE_DELAY_MS` and `GH_RETRY_MAX_DELAY_MS`,
+    /// keeping the default for any missing or invalid value.
+    pub fn from_env() -> Self {
+        let default = RetryConfig::default();
+        let read = |name: &str| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        RetryConfig {
+            max_retries: read("GH_MAX_RETRIES")
+                .map(|value| value as u32)
+                .unwrap_or(default.max_retries),
+            base_delay: read("GH_RETRY_BASE_DELAY_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.base_delay),
+            max_delay: read("GH_RETRY_MAX_DELAY_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_delay),
+        }
+    }
+
+    /// How long to wait before retrying a response, or `None` when it must not
+    /// be retried. Rate-limit headers win over the exponential backoff, and a
+    /// wait longer than `max_delay` isn't worth it.
+    pub fn retry_delay(
+        &self,
+        status: StatusCode,
+        headers: &HeaderMap,
+        attempt: u32,
+    ) -> Option<Duration> {
+        let retry_after = header_u64(headers, "retry-after");
+        let quota_exhausted = header_u64(headers, "x-ratelimit-remaining") == Some(0);
+
+        if !is_rate_limited(status, headers) && !status.is_server_error() {
+            return None;
+        }
+
+        let delay = match (retry_after, header_u64(headers, "x-ratelimit-reset")) {
+            (Some(seconds), _) => Duration::from_secs(seconds),
+            (None, Some(reset)) if quota_exhausted => {
+                Duration::from_secs(reset.saturating_sub(now_epoch()) + 1)
+            }
+            _ => self.backoff(attempt),
+        };
+
+        if delay > self.max_delay {
+            None
+        } else {
+            Some(delay)
+        }
+    }
+
+    /// Exponential backoff with jitter, between half and all of `base_delay * 2^attempt`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let half = ceiling / 2;
+        half + ceiling
+            .saturating_sub(half)
+            .mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RateLimitStatus {
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    pub used: Option<u64>,
+    pub reset: Option<u64>,
+    pub resource: Option<String>,
+    pub retries: u64,
+}
+
+impl RateLimitStatus {
+    /// Keeps the latest rate-limit headers sent by GitHub, Enterprise servers
+    /// with rate limiting disabled don't send any.
+    pub fn record(&mut self, headers: &HeaderMap) {
+        if let Some(limit) = header_u64(headers, "x-ratelimit-limit") {
+            self.limit = Some(limit);
+            self.remaining = header_u64(headers, "x-ratelimit-remaining");
+            self.used = header_u64(headers, "x-ratelimit-used");
+            self.reset = header_u64(headers, "x-ratelimit-reset");
+            self.resource = headers
+                .get("x-ratelimit-resource")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+        }
+    }
+}
+
+/// GitHub answers 403 as well as 429 once the quota is exhausted.
+pub fn is_rate_limited(status: StatusCode, headers: &HeaderMap) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || (status == StatusCode::FORBIDDEN
+            && (header_u64(headers, "retry-after").is_some()
+                || header_u64(headers, "x-ratelimit-remaining") == Some(0)))
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn now_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(values: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn retry_delay_only_retries_rate_limits_and_server_errors() {
+        let config = RetryConfig::default();
+
+        assert!(config
+            .retry_delay(StatusCode::NOT_FOUND, &HeaderMap::new(), 0)
+            .is_none());
+        assert!(config
+            .retry_delay(StatusCode::FORBIDDEN, &HeaderMap::new(), 0)
+            .is_none());
+        assert!(config
+            .retry_delay(StatusCode::BAD_GATEWAY, &HeaderMap::new(), 0)
+            .is_some());
+        assert_eq!(
+            config.retry_delay(StatusCode::FORBIDDEN, &headers(&[("retry-after", "7")]), 0),
+            Some(Duration::from_secs(7))
+        );
+    }
+
+    #[test]
+    fn retry_delay_waits_for_the_quota_reset() {
+        let config = RetryConfig::default();
+        let reset = (now_epoch() + 10).to_string();
+
+        let delay = config
+            .retry_delay(
+                StatusCode::FORBIDDEN,
+                &headers(&[
+                    ("x-ratelimit-remaining", "0"),
+                    ("x-ratelimit-reset", &reset),
+                ]),
+                0,
+            )
+            .unwrap();
+        assert!(delay >= Duration::from_secs(10) && delay <= Duration::from_secs(11));
+
+        let far_reset = (now_epoch() + 3600).to_string();
+        assert!(config
+            .retry_delay(
+                StatusCode::FORBIDDEN,
+                &headers(&[
+                    ("x-ratelimit-remaining", "0"),
+                    ("x-ratelimit-reset", &far_reset)
+                ]),
+                0,
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn backoff_grows_and_stays_under_max_delay() {
+        let config = RetryConfig::default();
+
+        for attempt in 0..20 {
+            let ceiling = config
+                .base_delay
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(config.max_delay);
+            let delay = config.backoff(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling);
+        }
+    }
+
+    #[test]
+    fn rate_limit_status_ignores_responses_without_headers() {
+        let mut status = RateLimitStatus::default();
+        status.record(&headers(&[
+            ("x-ratelimit-limit", "5000"),
+            ("x-ratelimit-remaining", "4999"),
+            ("x-ratelimit-resource", "core"),
+        ]));
+        status.record(&HeaderMap::new());
+
+        assert_eq!(status.limit, Some(5000));
+        assert_eq!(status.remaining, Some(4999));
+        assert_eq!(status.resource.as_deref(), Some("core"));
+    }
+}
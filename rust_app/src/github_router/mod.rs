@@ -1,12 +1,15 @@
 use std::sync::Arc;
 
-use github::{GithubClient, SerializableContentItems};
-use rocket::http::Status;
-use rocket::response::status;
+use github::cache::CacheStats;
+use github::discovery::{StackCatalogEntry, StackDiscovery};
+use github::rate_limit::RateLimitStatus;
+use github::{ConfigError, GithubClient, RepoFilter, SerializableContentItems};
 use rocket::serde::json::Json;
 use rocket::State;
 use serde::{Deserialize, Serialize};
 
+use crate::diff_router::AppConfig;
+use crate::error::AppError;
 use crate::github;
 
 #[derive(Serialize)]
@@ -22,33 +25,66 @@ pub async fn simple_json() -> Json<Message> {
     })
 }
 
-#[get("/repos/numberOfRepos")]
+#[get("/rateLimit")]
+pub async fn get_rate_limit(github_client: &State<Arc<GithubClient>>) -> Json<RateLimitStatus> {
+    Json(github_client.get_rate_limit().await)
+}
+
+#[get("/cacheStats")]
+pub async fn get_cache_stats(github_client: &State<Arc<GithubClient>>) -> Json<CacheStats> {
+    Json(github_client.content_cache().stats())
+}
+
+#[get("/repos/numberOfRepos?<filter..>")]
 pub async fn get_nb_repo(
     github_client: &State<Arc<GithubClient>>,
-) -> Result<Json<usize>, status::Custom<String>> {
+    filter: RepoFilter,
+) -> Result<Json<usize>, AppError> {
     github_client
-        .get_number_of_repo()
+        .get_number_of_repo(&filter)
         .await
         .map(Json)
         .map_err(|e| {
             error!("Failed to retrieve number of repository");
-            status::Custom(Status::InternalServerError, e.to_string())
+            AppError::from(e)
         })
 }
 
-#[get("/repos/list")]
+#[get("/repos/list?<filter..>")]
 pub async fn get_list_of_repos(
     github_client: &State<Arc<GithubClient>>,
-) -> Result<Json<Vec<octocrab::models::Repository>>, status::Custom<String>> {
+    filter: RepoFilter,
+) -> Result<Json<Vec<octocrab::models::Repository>>, AppError> {
     info!("Fetching list of repos !");
 
     github_client
-        .get_list_of_repos()
+        .get_list_of_repos(&filter)
         .await
         .map(Json)
         .map_err(|e| {
             error!("Failed to fetch list of repos");
-            status::Custom(Status::InternalServerError, e.to_string())
+            AppError::from(e)
+        })
+}
+
+#[get("/stacks/discover")]
+pub async fn discover_stacks(
+    github_client: &State<Arc<GithubClient>>,
+    discovery: &State<Option<StackDiscovery>>,
+    app_config: &State<Arc<AppConfig>>,
+) -> Result<Json<Vec<StackCatalogEntry>>, AppError> {
+    let discovery = discovery.as_ref().ok_or_else(|| {
+        ConfigError::Unsupported(
+            "Set STACK_NAME_PATTERN or STACK_TOPIC to discover stacks".to_string(),
+        )
+    })?;
+    github_client
+        .discover_stacks(discovery, &app_config.file_patterns)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Failed to discover stacks");
+            AppError::from(e)
         })
 }
 
@@ -56,7 +92,7 @@ pub async fn get_list_of_repos(
 pub async fn get_repo(
     github_client: &State<Arc<GithubClient>>,
     repo_name: &str,
-) -> Result<Json<octocrab::models::Repository>, status::Custom<String>> {
+) -> Result<Json<octocrab::models::Repository>, AppError> {
     info!("Repo name is {}", repo_name);
 
     github_client
@@ -65,7 +101,7 @@ pub async fn get_repo(
         .map(Json)
         .map_err(|e| {
             error!("Failed to retrieve repository '{}'", repo_name);
-            status::Custom(Status::InternalServerError, e.to_string())
+            AppError::from(e)
         })
 }
 
@@ -74,14 +110,14 @@ pub async fn get_repo_contents(
     github_client: &State<Arc<GithubClient>>,
     repo_name: &str,
     path: &str,
-) -> Result<Json<SerializableContentItems>, status::Custom<String>> {
+) -> Result<Json<SerializableContentItems>, AppError> {
     github_client
         .get_contents(repo_name, path)
         .await
         .map(Json)
         .map_err(|e| {
             error!("Failed to retrieve contents for {} and {}", repo_name, path);
-            status::Custom(Status::InternalServerError, e.to_string())
+            AppError::from(e)
         })
 }
 
@@ -90,14 +126,14 @@ pub async fn get_repo_all_contents(
     github_client: &State<Arc<GithubClient>>,
     repo_name: &str,
     path: &str,
-) -> Result<Json<SerializableContentItems>, status::Custom<String>> {
+) -> Result<Json<SerializableContentItems>, AppError> {
     github_client
-        .get_contents_for_repo(repo_name, path)
+        .get_contents_for_repo(repo_name, path, None)
         .await
         .map(Json)
         .map_err(|e| {
             error!("Failed to retrieve contents for {}", repo_name);
-            status::Custom(Status::InternalServerError, e.to_string())
+            AppError::from(e)
         })
 }
 
@@ -111,26 +147,136 @@ pub struct PayloadContent {
 pub async fn get_config_from_stack_and_file(
     github_client: &State<Arc<GithubClient>>,
     payload: Json<PayloadContent>,
-) -> Result<Json<SerializableContentItems>, Status> {
-    match github_client
+) -> Result<Json<SerializableContentItems>, AppError> {
+    github_client
         .get_config_from_stack_and_file(&payload.repo_name, &payload.path)
         .await
-    {
-        Ok(content) => Ok(Json(content)),
-        Err(_) => Err(Status::InternalServerError),
-    }
+        .map(Json)
+        .map_err(AppError::from)
 }
 
 #[post("/repos/contents/string", data = "<payload>")]
 pub async fn get_config_from_stack_and_file_string(
     github_client: &State<Arc<GithubClient>>,
     payload: Json<PayloadContent>,
-) -> Result<Json<String>, Status> {
-    match github_client
-        .get_config_from_stack_and_file_string(&payload.repo_name, &payload.path)
+) -> Result<Json<String>, AppError> {
+    github_client
+        .get_config_from_stack_and_file_string(&payload.repo_name, &payload.path, None)
         .await
-    {
-        Ok(content) => Ok(Json(content)),
-        Err(_) => Err(Status::InternalServerError),
+        .map(Json)
+        .map_err(AppError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::mock::{self, MockGithub};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::{json, Value};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, ResponseTemplate};
+
+    async fn client(github: &MockGithub) -> Client {
+        let rocket = rocket::build()
+            .manage(Arc::new(github.client().await))
+            .mount(
+                "/",
+                routes![
+                    get_nb_repo,
+                    get_list_of_repos,
+                    get_repo,
+                    get_config_from_stack_and_file_string
+                ],
+            );
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn repos_are_filtered_by_the_query() {
+        let github = MockGithub::start().await;
+        let mut archived = mock::repository("stack-old");
+        archived["archived"] = true.into();
+        github
+            .list_repos(vec![
+                mock::repository("stack-a"),
+                archived,
+                mock::repository("tooling"),
+            ])
+            .await;
+        let client = client(&github).await;
+
+        let response = client
+            .get("/repos/numberOfRepos?name_prefix=stack-")
+            .dispatch()
+            .await;
+        assert_eq!(response.into_json::<usize>().await, Some(2));
+
+        let response = client
+            .get("/repos/list?name_prefix=stack-&archived=false")
+            .dispatch()
+            .await;
+        let repos: Value = response.into_json().await.unwrap();
+        assert_eq!(repos.as_array().unwrap().len(), 1);
+        assert_eq!(repos[0]["name"], "stack-a");
+    }
+
+    #[tokio::test]
+    async fn repos_are_fetched_by_name() {
+        let github = MockGithub::start().await;
+        github.repository("stack-a").await;
+        let client = client(&github).await;
+
+        let response = client.get("/repos/stack-a").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let repo: Value = response.into_json().await.unwrap();
+        assert_eq!(repo["full_name"], "my-org/stack-a");
+
+        let response = client.get("/repos/stack-b").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let error: Value = response.into_json().await.unwrap();
+        assert_eq!(error["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn github_denials_are_forbidden() {
+        let github = MockGithub::start().await;
+        Mock::given(method("GET"))
+            .and(path(mock::repo_route("stack-a", "")))
+            .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+                "message": "Resource not accessible by integration",
+                "documentation_url": "https://docs.github.com/rest"
+            })))
+            .mount(&github.server)
+            .await;
+        let client = client(&github).await;
+
+        let response = client.get("/repos/stack-a").dispatch().await;
+
+        assert_eq!(response.status(), Status::Forbidden);
+        let error: Value = response.into_json().await.unwrap();
+        assert_eq!(error["code"], "forbidden");
+    }
+
+    #[tokio::test]
+    async fn file_contents_are_decoded() {
+        let github = MockGithub::start().await;
+        github
+            .file("stack-a", "configs/app.yml", None, "b1", "port: 80\n")
+            .await;
+        let client = client(&github).await;
+
+        let response = client
+            .post("/repos/contents/string")
+            .header(ContentType::JSON)
+            .body(json!({ "repo_name": "stack-a", "path": "configs/app.yml" }).to_string())
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<String>().await.as_deref(),
+            Some("port: 80\n")
+        );
     }
 }
@@ -0,0 +1,510 @@
+use std::time::Duration;
+
+use http::{HeaderMap, Request, Response, StatusCode};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::github::rate_limit::RetryConfig;
+use crate::github::{decode_content, encode_query, ConfigError};
+use crate::source::{ConfigSource, SourceEntry, SourceTree};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GitlabProject {
+    pub id: u64,
+    pub path_with_namespace: String,
+    pub default_branch: Option<String>,
+    pub web_url: String,
+    #[serde(default)]
+    pub archived: bool,
+    pub visibility: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitlabCommit {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct GitlabTreeEntry {
+    id: String,
+    path: String,
+    r#type: String,
+}
+
+/// Both files and blobs come base64-encoded.
+#[derive(Deserialize)]
+struct GitlabFile {
+    content: String,
+    encoding: String,
+}
+
+/// Reads stacks from GitLab projects, `group/project` being the stack name.
+#[derive(Clone)]
+pub struct GitlabClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+    base_url: String,
+    access_token: String,
+    retry_config: RetryConfig,
+}
+
+impl GitlabClient {
+    /// `url` is the root of the GitLab server, `https://` being assumed without a scheme.
+    pub fn new(url: &str, access_token: String, retry_config: RetryConfig) -> Self {
+        let url = url.trim_end_matches('/');
+        let base_url = if url.contains("://") {
+            format!("{}/api/v4", url)
+        } else {
+            format!("https://{}/api/v4", url)
+        };
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        GitlabClient {
+            client: Client::builder().build(connector),
+            base_url,
+            access_token,
+            retry_config,
+        }
+    }
+
+    async fn send(&self, route: &str) -> Result<Response<Body>, anyhow::Error> {
+        let request = Request::get(format!("{}{}", self.base_url, route))
+            .header("PRIVATE-TOKEN", &self.access_token)
+            .body(Body::empty())?;
+        Ok(
+            tokio::time::timeout(REQUEST_TIMEOUT, self.client.request(request))
+                .await
+                .map_err(|_| anyhow::anyhow!("no response after {:?}", REQUEST_TIMEOUT))??,
+        )
+    }
+
+    /// Retries GETs that failed, timed out or were answered with 429 or 5xx, as
+    /// the GitHub hosts do.
+    async fn send_with_retry(&self, route: &str) -> Result<Response<Body>, anyhow::Error> {
+        let mut attempt = 0;
+        loop {
+            let result = self.send(route).await;
+            let (delay, reason) = match &result {
+                Ok(response) => (
+                    self.retry_config
+                        .retry_delay(response.status(), response.headers(), attempt),
+                    response.status().to_string(),
+                ),
+                Err(e) => (Some(self.retry_config.backoff(attempt)), e.to_string()),
+            };
+
+            match delay {
+                Some(delay) if attempt < self.retry_config.max_retries => {
+                    attempt += 1;
+                    warn!(
+                        "GET {} failed with {}, retry {}/{} in {:?}",
+                        route, reason, attempt, self.retry_config.max_retries, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, route: &str) -> Result<(T, HeaderMap), ConfigError> {
+        let response = self.send_with_retry(route).await?;
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        match status {
+            StatusCode::NOT_FOUND => return Err(ConfigError::NotFound(route.to_string())),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                return Err(ConfigError::Forbidden(route.to_string()))
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                return Err(ConfigError::RateLimited(route.to_string()))
+            }
+            _ => {}
+        }
+        if !status.is_success() {
+            error!("GET {} failed with {}", route, status);
+            return Err(anyhow::anyhow!(
+                "GET {} failed with {}: {}",
+                route,
+                status,
+                String::from_utf8_lossy(&body)
+            )
+            .into());
+        }
+        let value = rocket::serde::json::from_slice(&body).map_err(anyhow::Error::from)?;
+        Ok((value, headers))
+    }
+
+    fn project_route(project: &str, suffix: &str) -> String {
+        format!("/projects/{}{}", encode_segment(project), suffix)
+    }
+
+    pub async fn get_project(&self, project: &str) -> Result<GitlabProject, ConfigError> {
+        let (project, _) = self.get(&Self::project_route(project, "")).await?;
+        Ok(project)
+    }
+}
+
+#[rocket::async_trait]
+impl ConfigSource for GitlabClient {
+    async fn resolve_revision(
+        &self,
+        stack: &str,
+        reference: Option<&str>,
+    ) -> Result<String, ConfigError> {
+        let reference = match reference {
+            Some(reference) => reference.to_string(),
+            // Empty projects have no default branch
+            None => self
+                .get_project(stack)
+                .await?
+                .default_branch
+                .ok_or_else(|| {
+                    ConfigError::NotFound(format!("{}, the project has no commits", stack))
+                })?,
+        };
+
+        let (commit, _): (GitlabCommit, _) = self
+            .get(&Self::project_route(
+                stack,
+                &format!("/repository/commits/{}", encode_segment(&reference)),
+            ))
+            .await?;
+        info!(
+            "Resolved {} for {} to commit {}",
+            reference, stack, commit.id
+        );
+        Ok(commit.id)
+    }
+
+    async fn list_files(&self, stack: &str, revision: &str) -> Result<SourceTree, ConfigError> {
+        let mut entries = Vec::new();
+        let mut page = "1".to_string();
+        loop {
+            let query = encode_query(&[
+                ("ref", revision),
+                ("recursive", "true"),
+                ("per_page", "100"),
+                ("page", &page),
+            ]);
+            let (tree, headers): (Vec<GitlabTreeEntry>, _) = self
+                .get(&Self::project_route(
+                    stack,
+                    &format!("/repository/tree?{}", query),
+                ))
+                .await?;
+
+            // Submodules point to commits of other repositories
+            entries.extend(
+                tree.into_iter()
+                    .filter(|entry| entry.r#type == "tree" || entry.r#type == "blob")
+                    .map(|entry| SourceEntry {
+                        is_dir: entry.r#type == "tree",
+                        path: entry.path,
+                        id: entry.id,
+                    }),
+            );
+
+            match headers
+                .get("x-next-page")
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+            {
+                Some(next_page) => page = next_page.to_string(),
+                None => break,
+            }
+        }
+
+        info!(
+            "Retrieved tree {} of {} with {} entries",
+            revision,
+            stack,
+            entries.len()
+        );
+        Ok(SourceTree { entries })
+    }
+
+    async fn read_file(
+        &self,
+        stack: &str,
+        path: &str,
+        revision: &str,
+    ) -> Result<String, ConfigError> {
+        let (file, _): (GitlabFile, _) = self
+            .get(&Self::project_route(
+                stack,
+                &format!(
+                    "/repository/files/{}?{}",
+                    encode_segment(path.trim_start_matches('/')),
+                    encode_query(&[("ref", revision)])
+                ),
+            ))
+            .await?;
+        decode_gitlab_file(file)
+    }
+
+    /// Blobs are content-addressed, so the tree entry is enough to read them.
+    async fn read_entry(
+        &self,
+        stack: &str,
+        _revision: &str,
+        entry: &SourceEntry,
+    ) -> Result<String, ConfigError> {
+        let (blob, _): (GitlabFile, _) = self
+            .get(&Self::project_route(
+                stack,
+                &format!("/repository/blobs/{}", encode_segment(&entry.id)),
+            ))
+            .await?;
+        decode_gitlab_file(blob)
+    }
+}
+
+fn decode_gitlab_file(file: GitlabFile) -> Result<String, ConfigError> {
+    if file.encoding != "base64" {
+        return Err(anyhow::anyhow!("Unsupported encoding {}", file.encoding).into());
+    }
+    decode_content(&file.content)
+}
+
+/// GitLab takes project and file paths as a single URL-encoded path segment.
+fn encode_segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff_router::{diff_stacks, AppConfig};
+    use crate::github::mock::MockGithub;
+    use crate::models;
+    use crate::source::patterns::FilePatterns;
+    use crate::source::RoutedSource;
+    use std::sync::Arc;
+    use wiremock::matchers::{header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const FILE: &str = "configs/svc/config-overrides.yml";
+
+    fn gitlab_client(server: &MockServer) -> GitlabClient {
+        let retry_config = RetryConfig {
+            max_retries: 2,
+            base_delay: std::time::Duration::from_millis(1),
+            max_delay: std::time::Duration::from_millis(10),
+        };
+        GitlabClient::new(&server.uri(), "glpat-x".to_string(), retry_config)
+    }
+
+    async fn mock_project(server: &MockServer) {
+        let project = "/api/v4/projects/group%2Fstack-b";
+        Mock::given(method("GET"))
+            .and(path(project))
+            .and(header("PRIVATE-TOKEN", "glpat-x"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(rocket::serde::json::json!({
+                    "id": 7,
+                    "path_with_namespace": "group/stack-b",
+                    "default_branch": "main",
+                    "web_url": "https://gitlab.example.com/group/stack-b",
+                    "visibility": "private"
+                })),
+            )
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{}/repository/commits/main", project)))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(rocket::serde::json::json!({ "id": "c0ffee" })),
+            )
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{}/repository/tree", project)))
+            .and(query_param("page", "1"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("x-next-page", "2")
+                    .set_body_json(rocket::serde::json::json!([
+                        { "id": "t1", "path": "configs", "type": "tree" },
+                        { "id": "t2", "path": "configs/svc", "type": "tree" }
+                    ])),
+            )
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{}/repository/tree", project)))
+            .and(query_param("page", "2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("x-next-page", "")
+                    .set_body_json(rocket::serde::json::json!([
+                        { "id": "b1", "path": FILE, "type": "blob" },
+                        { "id": "s1", "path": "vendor", "type": "commit" }
+                    ])),
+            )
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!(
+                "{}/repository/files/configs%2Fsvc%2Fconfig-overrides.yml",
+                project
+            )))
+            .and(query_param("ref", "c0ffee"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(rocket::serde::json::json!({
+                    "content": base64::encode("port: 80\nhost: b\n"),
+                    "encoding": "base64"
+                })),
+            )
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{}/repository/blobs/b1", project)))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(rocket::serde::json::json!({
+                    "content": base64::encode("port: 80\nhost: b\n"),
+                    "encoding": "base64"
+                })),
+            )
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn lists_and_reads_a_project() {
+        let server = MockServer::start().await;
+        mock_project(&server).await;
+        let gitlab = gitlab_client(&server);
+
+        let project = gitlab.get_project("group/stack-b").await.unwrap();
+        let revision = gitlab
+            .resolve_revision("group/stack-b", None)
+            .await
+            .unwrap();
+        let tree = gitlab.list_files("group/stack-b", &revision).await.unwrap();
+
+        assert_eq!(project.id, 7);
+        assert_eq!(revision, "c0ffee");
+        assert_eq!(tree.entries.len(), 3);
+        let patterns = FilePatterns::parse(&["configs/**"]).unwrap();
+        assert_eq!(tree.matching_files(&patterns).len(), 1);
+        assert_eq!(
+            gitlab
+                .read_file("group/stack-b", FILE, &revision)
+                .await
+                .unwrap(),
+            "port: 80\nhost: b\n"
+        );
+        assert!(matches!(
+            gitlab.get_project("group/missing").await,
+            Err(ConfigError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn empty_projects_have_no_revision() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v4/projects/group%2Fempty"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(rocket::serde::json::json!({
+                    "id": 8,
+                    "path_with_namespace": "group/empty",
+                    "default_branch": null,
+                    "web_url": "https://gitlab.example.com/group/empty"
+                })),
+            )
+            .mount(&server)
+            .await;
+        let gitlab = gitlab_client(&server);
+
+        assert!(matches!(
+            gitlab.resolve_revision("group/empty", None).await,
+            Err(ConfigError::NotFound(message)) if message.contains("no commits")
+        ));
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v4/projects/group%2Fstack-b"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        mock_project(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/api/v4/projects/group%2Fbusy"))
+            .respond_with(ResponseTemplate::new(429))
+            .expect(3)
+            .mount(&server)
+            .await;
+        let gitlab = gitlab_client(&server);
+
+        assert_eq!(gitlab.get_project("group/stack-b").await.unwrap().id, 7);
+        assert!(matches!(
+            gitlab.get_project("group/busy").await,
+            Err(ConfigError::RateLimited(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn compares_a_github_stack_with_a_gitlab_stack() {
+        let server = MockServer::start().await;
+        mock_project(&server).await;
+        let github = MockGithub::start().await;
+        github.commit("stack-a", "main", "c1").await;
+        github.tree("stack-a", "c1", &[(FILE, "b-a")]).await;
+        github.blob("stack-a", "b-a", "port: 8080\nhost: b\n").await;
+
+        let source = RoutedSource::new(Arc::new(github.client().await))
+            .with("gitlab", Arc::new(gitlab_client(&server)));
+        let app_config = AppConfig {
+            file_patterns: FilePatterns::parse(&["configs/**"]).unwrap(),
+        };
+        let payload = models::ComputeAllDiffPayload {
+            stack_a: "stack-a".to_string(),
+            stack_b: "gitlab:group/stack-b".to_string(),
+            ref_a: Some("main".to_string()),
+            ref_b: None,
+        };
+
+        let response = diff_stacks(payload, &source, &app_config)
+            .await
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        assert_eq!(response.compared_files, vec![FILE]);
+        assert_eq!(response.files_with_diff.len(), 1);
+        let diff = &response.files_with_diff[0];
+        assert!(diff.left_not_right.is_empty() && diff.right_not_left.is_empty());
+        assert_eq!(diff.same_key_diff_value, vec!["/port"]);
+        assert_eq!(
+            (diff.sha_a.as_deref(), diff.sha_b.as_deref()),
+            (Some("c1"), Some("c0ffee"))
+        );
+    }
+}
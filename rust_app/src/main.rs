@@ -1,23 +1,40 @@
+mod blame;
 mod db;
 mod diff_router;
+mod error;
 mod github;
 mod github_router;
+mod gitlab;
 mod logger;
 mod models;
+mod pr_check;
+mod reconcile;
+mod report;
+mod source;
 mod utils;
+mod webhook_router;
 
 use db::MongoDbFairing;
 use diff_router::AppConfig;
 use dotenv::dotenv;
+use github::auth::{host_suffix, GithubAuth};
+use github::cache::{CacheConfig, ContentCache};
+use github::discovery::StackDiscovery;
+use github::rate_limit::RetryConfig;
 use github::GithubClient;
+use gitlab::GitlabClient;
 use rocket_cors::{AllowedOrigins, Cors, CorsOptions};
+use source::filesystem::FilesystemSource;
+use source::git::GitSource;
+use source::patterns::FilePatterns;
+use source::{ConfigSource, RoutedSource};
 use std::env;
 use std::sync::Arc;
+use webhook_router::WebhookConfig;
 
 #[macro_use]
 extern crate rocket;
 
-
 fn instantiate_cors(allowed: &str) -> Cors {
     CorsOptions {
         allowed_origins: AllowedOrigins::some_exact(&[allowed]),
@@ -27,52 +44,166 @@ fn instantiate_cors(allowed: &str) -> Cors {
     .expect("Failed to instantiate CORS")
 }
 
+async fn create_github_client() -> GithubClient {
+    let github_auth = GithubAuth::from_env().expect("Invalid Github authentication");
+    let organization_name = env::var("ORGANIZATION_GH").expect("No Github organization name");
+    let hostname_gh = env::var("HOSTNAME_GH").expect("No Github hostname");
+
+    let mut github_client = GithubClient::new(
+        github_auth,
+        organization_name.clone(),
+        hostname_gh,
+        RetryConfig::from_env(),
+    )
+    .await
+    .expect("Failed to create GithubClient")
+    .with_content_cache(ContentCache::new(CacheConfig::from_env()));
+
+    for hostname in env::var("EXTRA_HOSTNAMES_GH")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|hostname| !hostname.is_empty())
+    {
+        let auth = GithubAuth::from_env_for_host(hostname).expect("Invalid Github authentication");
+        // `ORGANIZATION_GH_GHE_MY_CORP_IO` for ghe.my-corp.io, else `ORGANIZATION_GH`
+        let default_owner = env::var(format!("ORGANIZATION_GH_{}", host_suffix(hostname)))
+            .unwrap_or_else(|_| organization_name.clone());
+        github_client
+            .add_host(hostname.to_string(), auth, &default_owner)
+            .await
+            .expect("Failed to connect to Github host");
+    }
+    github_client
+}
+
 #[launch]
 async fn rocket() -> _ {
     info!("hello");
 
-    let _log = logger::setup_logging().expect("Can't instantiate logger");
+    logger::setup_logging().expect("Can't instantiate logger");
 
     dotenv().ok();
 
     let allowed_origin_str = env::var("ORIGINS").expect("Invalid ORIGINS");
-    let access_token = env::var("ACCESS_TOKEN_GH").expect("No Github token");
-    let organization_name = env::var("ORGANIZATION_GH").expect("No Github organization name");
-    let hostname_gh = env::var("HOSTNAME_GH").expect("No Github hostname");
-    let folder_a = env::var("FOLDER_A_NAME").expect("No folder name");
-    let folder_b = env::var("FOLDER_B_NAME").expect("No folder name");
+    let config_source = env::var("CONFIG_SOURCE").unwrap_or_else(|_| "github".to_string());
+    // Space-separated globs, `!` excluding files, e.g. `services/**/config*.{yml,yaml} !**/test/**`
+    let file_patterns = match env::var("CONFIG_FILE_PATTERNS") {
+        Ok(patterns) => {
+            info!("Comparing files matching {}", patterns);
+            FilePatterns::parse(&patterns.split_whitespace().collect::<Vec<_>>())
+        }
+        Err(_) => {
+            let folder_a = env::var("FOLDER_A_NAME").expect("No folder name");
+            let folder_b = env::var("FOLDER_B_NAME").expect("No folder name");
+            FilePatterns::config_overrides_under(&[&folder_a, &folder_b])
+        }
+    }
+    .expect("Invalid CONFIG_FILE_PATTERNS");
 
-    let app_config = AppConfig { folder_a, folder_b };
+    let app_config = AppConfig { file_patterns };
 
     info!("Allowed origins are {}", allowed_origin_str);
 
-    let github_client = GithubClient::new(access_token, organization_name, hostname_gh)
-        .await
-        .expect("Failed to create GithubClient");
-
-    rocket::build()
+    let rocket = rocket::build()
         .attach(instantiate_cors(&allowed_origin_str))
         .attach(MongoDbFairing)
-        .manage(Arc::new(github_client))
         .manage(Arc::new(app_config))
+        .register("/", catchers![error::default_catcher])
         .mount(
             "/",
             routes![
-                github_router::simple_json,
-                github_router::get_repo_contents,
-                github_router::get_repo,
-                github_router::get_nb_repo,
-                github_router::get_list_of_repos,
-                github_router::get_config_from_stack_and_file,
-                github_router::get_config_from_stack_and_file_string,
-                github_router::get_repo_all_contents,
                 diff_router::get_diff_by_id,
                 diff_router::get_configs_from_stacks_name,
                 diff_router::insert_diff,
                 diff_router::get_all_diffs_from_stacks,
                 diff_router::get_latest_diffs_from_stacks,
                 diff_router::toggle_review_endpoint,
+                diff_router::blame_diff,
+                diff_router::list_runs,
+                diff_router::get_run_diffs,
                 diff_router::compute_diff_for_all_files,
+                diff_router::compute_diff_for_all_files_ci,
             ],
-        )
+        );
+
+    let (rocket, source): (_, Arc<dyn ConfigSource>) = match config_source.as_str() {
+        "github" => {
+            let github_client = Arc::new(create_github_client().await);
+            let stack_discovery = StackDiscovery::from_env().expect("Invalid STACK_NAME_PATTERN");
+            if stack_discovery.is_none() {
+                info!(
+                    "Stack discovery is off, set STACK_NAME_PATTERN or STACK_TOPIC to turn it on"
+                );
+            }
+            let rocket = rocket
+                .manage(github_client.clone())
+                .manage(stack_discovery)
+                .mount(
+                    "/",
+                    routes![
+                        github_router::simple_json,
+                        github_router::get_repo_contents,
+                        github_router::get_repo,
+                        github_router::get_nb_repo,
+                        github_router::get_list_of_repos,
+                        github_router::get_config_from_stack_and_file,
+                        github_router::get_config_from_stack_and_file_string,
+                        github_router::get_repo_all_contents,
+                        github_router::get_rate_limit,
+                        github_router::get_cache_stats,
+                        github_router::discover_stacks,
+                        diff_router::get_file_history,
+                        diff_router::reconcile_diff,
+                    ],
+                );
+            (rocket, github_client)
+        }
+        "filesystem" => {
+            let root = env::var("CONFIG_SOURCE_ROOT").expect("No config source root");
+            info!("Reading stacks from directory {}", root);
+            (rocket, Arc::new(FilesystemSource::new(root)))
+        }
+        "git" => {
+            let root = env::var("CONFIG_SOURCE_ROOT").expect("No config source root");
+            info!("Reading stacks from git repositories in {}", root);
+            (rocket, Arc::new(GitSource::new(root)))
+        }
+        other => panic!(
+            "Unknown CONFIG_SOURCE {}, expected github, filesystem or git",
+            other
+        ),
+    };
+
+    // GitLab projects are compared with stacks of the main source as `gitlab:group/project`
+    let source: Arc<dyn ConfigSource> = match env::var("GITLAB_URL") {
+        Ok(gitlab_url) => {
+            let access_token = env::var("GITLAB_TOKEN").expect("No GitLab token");
+            info!("Reading gitlab: stacks from {}", gitlab_url);
+            Arc::new(RoutedSource::new(source).with(
+                "gitlab",
+                Arc::new(GitlabClient::new(
+                    &gitlab_url,
+                    access_token,
+                    RetryConfig::from_env(),
+                )),
+            ))
+        }
+        Err(_) => source,
+    };
+
+    let rocket = match WebhookConfig::from_env().expect("Invalid webhook configuration") {
+        Some(webhook_config) => {
+            let webhook_config = match rocket.state::<Arc<GithubClient>>() {
+                Some(github_client) => webhook_config.with_github_client(github_client.clone()),
+                None => webhook_config,
+            };
+            rocket
+                .manage(webhook_config)
+                .mount("/", routes![webhook_router::github_webhook])
+        }
+        None => rocket,
+    };
+
+    rocket.manage(source)
 }
@@ -1,4 +1,5 @@
 use bson::{oid::ObjectId, DateTime};
+use rocket::FromForm;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -10,6 +11,10 @@ pub struct DiffBaseSchema {
     pub right_not_left: Vec<String>,
     pub same_key_diff_value: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha_a: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha_b: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub created_at: Option<DateTime>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub updated_at: Option<DateTime>,
@@ -26,8 +31,16 @@ pub struct FileDiff {
     pub right_not_left: Vec<String>,
     pub same_key_diff_value: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha_a: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha_b: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<ObjectId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub reviewed: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub pr_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub created_at: Option<DateTime>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub updated_at: Option<DateTime>,
@@ -43,8 +56,12 @@ pub struct GetAllDiffsResponse {
     pub stack_a: String,
     pub stack_b: String,
     pub files_with_diff: Vec<FileDiff>,
+    /// Set when there are more diffs, to pass as `cursor` for the next page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetAllDiffsNoStackResponse {
     pub files_with_diff: Vec<FileDiff>,
@@ -54,8 +71,65 @@ pub struct GetAllDiffsNoStackResponse {
 pub struct GetAllDiffsPayload {
     pub stack_a: String,
     pub stack_b: String,
+    #[serde(flatten)]
+    pub query: DiffQuery,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffSort {
+    #[default]
+    NewestFirst,
+    OldestFirst,
+    FileAsc,
+    FileDesc,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffCategory {
+    LeftNotRight,
+    RightNotLeft,
+    SameKeyDiffValue,
+}
+
+/// Filters, sort and page of a diff listing, every filter being optional.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DiffQuery {
+    pub reviewed: Option<bool>,
+    pub file_prefix: Option<String>,
+    pub created_after: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_before: Option<chrono::DateTime<chrono::Utc>>,
+    /// Keeps the diffs having paths in any of these categories.
+    #[serde(default)]
+    pub non_empty: Vec<DiffCategory>,
+    #[serde(default)]
+    pub sort: DiffSort,
+    #[serde(flatten)]
+    pub page: Page,
+}
+
+/// Which page of a listing to return, the first one of the default size unless
+/// a limit or a cursor is set.
+#[derive(Serialize, Deserialize, FromForm, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Page {
+    pub limit: Option<i64>,
+    /// The `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+}
+
+impl Page {
+    /// Query parameters take over the page of the body, as `Link` headers set them.
+    pub fn or(self, other: Page) -> Page {
+        if self.limit.is_some() || self.cursor.is_some() {
+            self
+        } else {
+            other
+        }
+    }
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetLatestDiffResponse {
     pub stack_a: String,
@@ -69,6 +143,8 @@ pub struct GetConfigsFromStacksPayload {
     pub stack_a: String,
     pub stack_b: String,
     pub file: String,
+    pub ref_a: Option<String>,
+    pub ref_b: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -76,6 +152,8 @@ pub struct GetConfigsFromStacksResponse {
     pub stack_a: String,
     pub stack_b: String,
     pub file: String,
+    pub sha_a: String,
+    pub sha_b: String,
     pub config_a: String,
     pub config_b: String,
 }
@@ -84,13 +162,50 @@ pub struct GetConfigsFromStacksResponse {
 pub struct ComputeAllDiffPayload {
     pub stack_a: String,
     pub stack_b: String,
+    pub ref_a: Option<String>,
+    pub ref_b: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ComputeAllDiffResponse {
     pub stack_a: String,
     pub stack_b: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<ObjectId>,
     pub files_with_diff: Vec<FileDiff>,
+    /// Every file found in either stack, with a diff or not.
+    #[serde(default)]
+    pub compared_files: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileHistoryPayload {
+    pub stack: String,
+    pub file: String,
+    pub reference: Option<String>,
+    pub limit: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileHistoryEntry {
+    pub sha: String,
+    pub author: Option<String>,
+    pub author_login: Option<String>,
+    pub date: Option<chrono::DateTime<chrono::Utc>>,
+    pub message: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    /// Why the commit couldn't be diffed, its changes being left empty.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileHistoryResponse {
+    pub stack: String,
+    pub file: String,
+    pub history: Vec<FileHistoryEntry>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -102,3 +217,89 @@ pub struct ToggleReviewPayload {
 pub struct ToggleReviewResponse {
     pub status: String,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReconcileDiffPayload {
+    pub id: String,
+    pub paths: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReconcileDiffResponse {
+    pub id: String,
+    pub pr_number: u64,
+    pub pr_url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlameDiffPayload {
+    pub id: String,
+}
+
+/// The last change to a path in one stack, `line` being unset for a whole file.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LineBlame {
+    pub line: Option<usize>,
+    pub sha: String,
+    pub author: String,
+    pub date: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PathBlame {
+    pub category: String,
+    pub path: String,
+    pub blame_a: Option<LineBlame>,
+    pub blame_b: Option<LineBlame>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlameDiffResponse {
+    pub id: String,
+    pub stack_a: String,
+    pub stack_b: String,
+    pub file: String,
+    pub sha_a: String,
+    pub sha_b: String,
+    pub paths: Vec<PathBlame>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// How many files differ in a run, and how many paths in each category.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunCounts {
+    pub files_with_diff: u32,
+    pub left_not_right: u32,
+    pub right_not_left: u32,
+    pub same_key_diff_value: u32,
+}
+
+/// One comparison of two stacks, which every diff it found references.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ComparisonRun {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub stack_a: String,
+    pub stack_b: String,
+    pub ref_a: Option<String>,
+    pub ref_b: Option<String>,
+    pub status: RunStatus,
+    pub started_at: DateTime,
+    pub finished_at: Option<DateTime>,
+    pub counts: Option<RunCounts>,
+    pub error: Option<String>,
+}
+
+#[derive(FromForm, Debug, Default)]
+pub struct RunFilter {
+    pub stack_a: Option<String>,
+    pub stack_b: Option<String>,
+    pub limit: Option<i64>,
+}
@@ -0,0 +1,392 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::HashSet;
+
+use crate::diff_router::{diff_stacks, AppConfig};
+use crate::error::AppError;
+use crate::github::pull_request::CommitState;
+use crate::github::{ConfigError, GithubClient};
+use crate::models;
+use crate::report::categories;
+use crate::source::ConfigSource;
+use crate::webhook_router::StackPair;
+
+/// Finds the comment to update on each push.
+const COMMENT_MARKER: &str = "<!-- compare-configs drift -->";
+
+const STATUS_CONTEXT: &str = "compare-configs/drift";
+
+/// Key paths whose drift doesn't fail pull requests, as globs such as
+/// `/image/tag` or `/resources/**`.
+pub struct AllowList {
+    globs: GlobSet,
+}
+
+impl AllowList {
+    pub fn parse<S: AsRef<str>>(patterns: &[S]) -> Result<Self, globset::Error> {
+        let mut globs = GlobSetBuilder::new();
+        for pattern in patterns {
+            globs.add(
+                GlobBuilder::new(pattern.as_ref())
+                    .literal_separator(true)
+                    .build()?,
+            );
+        }
+        Ok(AllowList {
+            globs: globs.build()?,
+        })
+    }
+
+    pub fn is_allowed(&self, path: &str) -> bool {
+        self.globs.is_match(path)
+    }
+}
+
+/// A key that differs between the stacks of a pair, in one file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Drift {
+    pub file: String,
+    pub path: String,
+    pub kind: &'static str,
+}
+
+/// What a pull request changes to the drift of one pair.
+pub struct PairDrift {
+    pub pair: StackPair,
+    pub introduced: Vec<Drift>,
+    pub allowed: Vec<Drift>,
+}
+
+fn drifts(response: &models::ComputeAllDiffResponse) -> Vec<Drift> {
+    response
+        .files_with_diff
+        .iter()
+        .flat_map(|diff| {
+            categories(diff).into_iter().flat_map(move |(kind, paths)| {
+                paths.iter().map(move |path| Drift {
+                    file: diff.file.clone(),
+                    path: path.clone(),
+                    kind,
+                })
+            })
+        })
+        .collect()
+}
+
+/// Drift at the head of the pull request that its base doesn't have, split
+/// between the keys the allow-list tolerates and the others.
+pub fn introduced_drift(
+    pair: StackPair,
+    base: &models::ComputeAllDiffResponse,
+    head: &models::ComputeAllDiffResponse,
+    allow_list: &AllowList,
+) -> PairDrift {
+    let base_drifts: HashSet<Drift> = drifts(base).into_iter().collect();
+    let (allowed, introduced) = drifts(head)
+        .into_iter()
+        .filter(|drift| !base_drifts.contains(drift))
+        .partition(|drift| allow_list.is_allowed(&drift.path));
+
+    PairDrift {
+        pair,
+        introduced,
+        allowed,
+    }
+}
+
+pub fn render_comment(pair_drifts: &[PairDrift]) -> String {
+    let mut comment = format!("{}\n### Config drift\n", COMMENT_MARKER);
+    for pair_drift in pair_drifts {
+        comment.push_str(&format!(
+            "\n#### {} vs {}\n\n",
+            pair_drift.pair.stack_a, pair_drift.pair.stack_b
+        ));
+        if pair_drift.introduced.is_empty() && pair_drift.allowed.is_empty() {
+            comment.push_str("No new drift.\n");
+            continue;
+        }
+        comment.push_str("| File | Key | Drift | |\n|---|---|---|---|\n");
+        for (drift, allowed) in pair_drift
+            .introduced
+            .iter()
+            .map(|drift| (drift, false))
+            .chain(pair_drift.allowed.iter().map(|drift| (drift, true)))
+        {
+            comment.push_str(&format!(
+                "| `{}` | `{}` | {} | {} |\n",
+                drift.file,
+                drift.path,
+                drift.kind,
+                if allowed { "allowed" } else { ":x:" }
+            ));
+        }
+    }
+    comment
+}
+
+fn status_description(pair_drifts: &[PairDrift]) -> (CommitState, String) {
+    let introduced: usize = pair_drifts
+        .iter()
+        .map(|pair_drift| pair_drift.introduced.len())
+        .sum();
+    if introduced == 0 {
+        (CommitState::Success, "No new drift".to_string())
+    } else {
+        (
+            CommitState::Failure,
+            format!("{} drifting keys not on the allow-list", introduced),
+        )
+    }
+}
+
+/// Why the drift of a pull request couldn't be computed, as short as GitHub
+/// wants commit status descriptions.
+fn error_description(error: &AppError) -> &'static str {
+    match error {
+        AppError::Config(ConfigError::RateLimited(_)) => "GitHub rate limit hit, retry later",
+        AppError::Config(ConfigError::Forbidden(_)) => "A stack can't be read",
+        AppError::Config(ConfigError::NotFound(_)) => "A stack or commit wasn't found",
+        AppError::Config(ConfigError::InvalidYaml(..)) => "A config file isn't valid YAML",
+        AppError::Config(ConfigError::TruncatedTree(_)) => "A stack is too large to list",
+        _ => "Couldn't compute drift",
+    }
+}
+
+/// A pair with a stack in the repository of a pull request.
+pub struct CheckedPair {
+    pub pair: StackPair,
+    /// Whether the pull request is on stack A of the pair, rather than B.
+    pub on_stack_a: bool,
+}
+
+/// Where a pull request is, and what it compares.
+pub struct PullRequestCheck<'a> {
+    /// The repository of the pull request, as a stack.
+    pub stack: &'a str,
+    pub number: u64,
+    pub base_sha: &'a str,
+    pub head_sha: &'a str,
+    pub pairs: Vec<CheckedPair>,
+}
+
+/// Computes the drift of each pair at the base and the head of the pull
+/// request, then reports it as a comment and a commit status. Once pending,
+/// the status ends up in error if anything fails.
+pub async fn check_pull_request(
+    github_client: &GithubClient,
+    source: &dyn ConfigSource,
+    app_config: &AppConfig,
+    allow_list: &AllowList,
+    check: PullRequestCheck<'_>,
+) -> Result<(), AppError> {
+    let files = github_client
+        .list_pull_request_files(check.stack, check.number)
+        .await?;
+    if !files
+        .iter()
+        .any(|file| app_config.file_patterns.is_match(file))
+    {
+        info!(
+            "#{} of {} doesn't change any config file",
+            check.number, check.stack
+        );
+        return Ok(());
+    }
+
+    github_client
+        .set_commit_status(
+            check.stack,
+            check.head_sha,
+            CommitState::Pending,
+            STATUS_CONTEXT,
+            "Computing drift",
+        )
+        .await?;
+
+    let (stack, head_sha) = (check.stack, check.head_sha);
+    let result = report_drift(github_client, source, app_config, allow_list, check).await;
+    if let Err(e) = &result {
+        github_client
+            .set_commit_status(
+                stack,
+                head_sha,
+                CommitState::Error,
+                STATUS_CONTEXT,
+                error_description(e),
+            )
+            .await?;
+    }
+    result
+}
+
+async fn report_drift(
+    github_client: &GithubClient,
+    source: &dyn ConfigSource,
+    app_config: &AppConfig,
+    allow_list: &AllowList,
+    check: PullRequestCheck<'_>,
+) -> Result<(), AppError> {
+    let mut pair_drifts = Vec::new();
+    for CheckedPair { pair, on_stack_a } in check.pairs {
+        let payload = |sha: &str| {
+            let reference = Some(sha.to_string());
+            models::ComputeAllDiffPayload {
+                stack_a: pair.stack_a.clone(),
+                stack_b: pair.stack_b.clone(),
+                ref_a: if on_stack_a { reference.clone() } else { None },
+                ref_b: if on_stack_a { None } else { reference },
+            }
+        };
+        let (base, head) = rocket::futures::try_join!(
+            diff_stacks(payload(check.base_sha), source, app_config),
+            diff_stacks(payload(check.head_sha), source, app_config)
+        )?;
+        pair_drifts.push(introduced_drift(pair, &base, &head, allow_list));
+    }
+
+    github_client
+        .upsert_comment(
+            check.stack,
+            check.number,
+            COMMENT_MARKER,
+            &render_comment(&pair_drifts),
+        )
+        .await?;
+    let (state, description) = status_description(&pair_drifts);
+    github_client
+        .set_commit_status(
+            check.stack,
+            check.head_sha,
+            state,
+            STATUS_CONTEXT,
+            &description,
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::mock::{repo_route, MockGithub};
+    use crate::source::patterns::FilePatterns;
+    use rocket::serde::json::json;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, ResponseTemplate};
+
+    fn response(file: &str, same_key_diff_value: &[&str]) -> models::ComputeAllDiffResponse {
+        models::ComputeAllDiffResponse {
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+            run_id: None,
+            files_with_diff: vec![models::FileDiff {
+                id: None,
+                stack_a: "stack-a".to_string(),
+                stack_b: "stack-b".to_string(),
+                file: file.to_string(),
+                left_not_right: Vec::new(),
+                right_not_left: Vec::new(),
+                same_key_diff_value: same_key_diff_value
+                    .iter()
+                    .map(|path| path.to_string())
+                    .collect(),
+                sha_a: None,
+                sha_b: None,
+                run_id: None,
+                reviewed: None,
+                pr_url: None,
+                created_at: None,
+                updated_at: None,
+            }],
+            compared_files: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn only_new_drift_outside_of_the_allow_list_fails() {
+        let pair = StackPair {
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+        };
+        let file = "configs/svc/config-overrides.yml";
+        let allow_list = AllowList::parse(&["/image/**"]).unwrap();
+        let base = response(file, &["/port"]);
+        let head = response(file, &["/port", "/image/tag", "/replicas"]);
+
+        let pair_drift = introduced_drift(pair, &base, &head, &allow_list);
+        assert_eq!(
+            pair_drift
+                .introduced
+                .iter()
+                .map(|drift| drift.path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["/replicas"]
+        );
+        assert_eq!(pair_drift.allowed[0].path, "/image/tag");
+
+        let pair_drifts = vec![pair_drift];
+        let comment = render_comment(&pair_drifts);
+        assert!(comment.starts_with(COMMENT_MARKER));
+        assert!(comment.contains(
+            "| `configs/svc/config-overrides.yml` | `/replicas` | same_key_diff_value | :x: |"
+        ));
+        assert!(comment.contains("| `/image/tag` | same_key_diff_value | allowed |"));
+        assert_eq!(status_description(&pair_drifts).0, CommitState::Failure);
+    }
+
+    #[tokio::test]
+    async fn failed_checks_leave_an_error_status() {
+        let github = MockGithub::start().await;
+        github
+            .get(
+                &repo_route("stack-a", "/pulls/5/files"),
+                json!([{ "filename": "configs/svc/config-overrides.yml" }]),
+            )
+            .await;
+        // Neither commit of the pair exists
+        for (state, description) in [
+            ("pending", "Computing drift"),
+            ("error", "A stack or commit wasn't found"),
+        ] {
+            Mock::given(method("POST"))
+                .and(path(repo_route("stack-a", "/statuses/h1")))
+                .and(body_partial_json(
+                    json!({ "state": state, "description": description }),
+                ))
+                .respond_with(ResponseTemplate::new(201).set_body_json(json!({})))
+                .expect(1)
+                .mount(&github.server)
+                .await;
+        }
+        let github_client = github.client().await;
+        let app_config = AppConfig {
+            file_patterns: FilePatterns::config_overrides_under(&["configs"]).unwrap(),
+        };
+        let check = PullRequestCheck {
+            stack: "stack-a",
+            number: 5,
+            base_sha: "b0",
+            head_sha: "h1",
+            pairs: vec![CheckedPair {
+                pair: StackPair {
+                    stack_a: "stack-a".to_string(),
+                    stack_b: "stack-b".to_string(),
+                },
+                on_stack_a: true,
+            }],
+        };
+
+        let result = check_pull_request(
+            &github_client,
+            &github_client,
+            &app_config,
+            &AllowList::parse::<&str>(&[]).unwrap(),
+            check,
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(AppError::Config(ConfigError::NotFound(_)))
+        ));
+    }
+}
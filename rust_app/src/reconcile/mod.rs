@@ -0,0 +1,385 @@
+use chrono::Utc;
+use serde_yaml::{Mapping, Value};
+
+use crate::github::pull_request::PullRequest;
+use crate::github::{ConfigError, GithubClient};
+use crate::models;
+
+/// Stands for the whole file in a diff, when one of the stacks doesn't have it.
+pub const WHOLE_FILE: &str = "/*";
+
+/// Paths that the diff doesn't report, which can't be synced.
+pub fn unknown_paths<'a>(diff: &models::FileDiff, paths: &'a [String]) -> Vec<&'a str> {
+    paths
+        .iter()
+        .filter(|path| {
+            !diff.left_not_right.contains(path)
+                && !diff.right_not_left.contains(path)
+                && !diff.same_key_diff_value.contains(path)
+        })
+        .map(String::as_str)
+        .collect()
+}
+
+/// Copies the values at `paths` from `yaml_a` into `yaml_b`, paths missing
+/// from A being removed from B. B is written anew, so files with comments,
+/// which would be lost, are refused, as well as paths that don't name a
+/// single key of both files.
+pub fn copy_paths(yaml_a: &str, yaml_b: &str, paths: &[String]) -> Result<String, ConfigError> {
+    if paths.iter().any(|path| path == WHOLE_FILE) {
+        return Ok(yaml_a.to_string());
+    }
+    if has_comments(yaml_b) {
+        return Err(ConfigError::Unreconcilable(
+            "the file of stack B has comments, which would be lost".to_string(),
+        ));
+    }
+    let value_a = parse_mapping(yaml_a)?;
+    let mut value_b = parse_mapping(yaml_b)?;
+
+    for path in paths {
+        let keys: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let unresolvable = || {
+            ConfigError::Unreconcilable(format!("{} doesn't name a single key of both files", path))
+        };
+        let in_a = lookup(&value_a, &keys).ok_or_else(unresolvable)?;
+        let in_b = lookup(&value_b, &keys).ok_or_else(unresolvable)?;
+        match (in_a, in_b) {
+            (Some(value), _) => set_path(&mut value_b, &keys, value.clone()),
+            (None, Some(_)) => remove_path(&mut value_b, &keys),
+            (None, None) => return Err(unresolvable()),
+        }
+    }
+    let updated = serde_yaml::to_string(&value_b).map_err(anyhow::Error::from)?;
+    // serde_yaml starts with a document marker the file may not have had
+    match updated.strip_prefix("---\n") {
+        Some(document) if !yaml_b.starts_with("---") => Ok(document.to_string()),
+        _ => Ok(updated),
+    }
+}
+
+/// Comment lines, and comments after a value. Quoted values with ` #` are
+/// taken for comments too, to be safe.
+fn has_comments(yaml: &str) -> bool {
+    yaml.lines()
+        .any(|line| line.trim_start().starts_with('#') || line.contains(" #"))
+}
+
+/// Looks up a path the way the diff flattens files, `None` when it can't name
+/// a single key: keys which aren't strings all flatten to an empty segment, and
+/// keys with a `/` to several segments.
+fn lookup<'v>(value: &'v Value, keys: &[&str]) -> Option<Option<&'v Value>> {
+    let mut current = value;
+    for (index, key) in keys.iter().enumerate() {
+        if key.is_empty() {
+            return None;
+        }
+        let mapping = match current.as_mapping() {
+            Some(mapping) => mapping,
+            None => return Some(None),
+        };
+        let rest = keys[index..].join("/");
+        let mut found = None;
+        for (candidate, value) in mapping {
+            match candidate.as_str() {
+                Some(candidate) if candidate == *key => found = Some(value),
+                Some(candidate)
+                    if candidate.contains('/')
+                        && (rest == candidate || rest.starts_with(&format!("{}/", candidate))) =>
+                {
+                    return None
+                }
+                _ => {}
+            }
+        }
+        match found {
+            Some(value) => current = value,
+            None => return Some(None),
+        }
+    }
+    Some(Some(current))
+}
+
+fn parse_mapping(yaml: &str) -> Result<Value, ConfigError> {
+    if yaml.trim().is_empty() {
+        return Ok(Value::Mapping(Mapping::new()));
+    }
+    serde_yaml::from_str(yaml).map_err(|e| anyhow::Error::from(e).into())
+}
+
+fn set_path(value: &mut Value, keys: &[&str], new_value: Value) {
+    let (last, parents) = match keys.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut current = value;
+    for key in parents {
+        if !current.is_mapping() {
+            *current = Value::Mapping(Mapping::new());
+        }
+        current = match current {
+            Value::Mapping(mapping) => mapping
+                .entry(Value::String(key.to_string()))
+                .or_insert_with(|| Value::Mapping(Mapping::new())),
+            _ => unreachable!(),
+        };
+    }
+    if !current.is_mapping() {
+        *current = Value::Mapping(Mapping::new());
+    }
+    if let Value::Mapping(mapping) = current {
+        mapping.insert(Value::String(last.to_string()), new_value);
+    }
+}
+
+fn remove_path(value: &mut Value, keys: &[&str]) {
+    let (last, parents) = match keys.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let parent = parents.iter().try_fold(value, |value, key| {
+        value
+            .as_mapping_mut()?
+            .get_mut(&Value::String(key.to_string()))
+    });
+    if let Some(Value::Mapping(mapping)) = parent {
+        mapping.remove(&Value::String(last.to_string()));
+    }
+}
+
+/// Opens a pull request on stack B of `diff` copying the values at `paths`
+/// from stack A, on top of the head of B's default branch.
+pub async fn open_reconciliation_pr(
+    github_client: &GithubClient,
+    diff: &models::FileDiff,
+    paths: &[String],
+) -> Result<PullRequest, ConfigError> {
+    let reference_a = match &diff.sha_a {
+        Some(sha_a) => sha_a.clone(),
+        None => {
+            github_client
+                .resolve_commit_sha(&diff.stack_a, None)
+                .await?
+        }
+    };
+    let config_a = github_client
+        .get_config_from_stack_and_file_string(&diff.stack_a, &diff.file, Some(&reference_a))
+        .await?;
+
+    let (base, base_sha) = github_client.get_default_branch(&diff.stack_b).await?;
+    let (config_b, previous_sha) = match github_client
+        .get_file_with_sha(&diff.stack_b, &diff.file, &base_sha)
+        .await
+    {
+        Ok(file) => (file.content, Some(file.sha)),
+        Err(ConfigError::NotFound(_)) => (String::new(), None),
+        Err(e) => return Err(e),
+    };
+    let updated_config = copy_paths(&config_a, &config_b, paths)?;
+
+    let diff_id = diff.id.map(|id| id.to_hex()).unwrap_or_default();
+    let branch = format!("reconcile/{}-{}", diff_id, Utc::now().timestamp());
+    let title = format!("Sync {} from {}", diff.file, diff.stack_a);
+    github_client
+        .create_branch(&diff.stack_b, &branch, &base_sha)
+        .await?;
+    github_client
+        .put_file(
+            &diff.stack_b,
+            &branch,
+            &diff.file,
+            &updated_config,
+            previous_sha.as_deref(),
+            &title,
+        )
+        .await?;
+
+    let body = format!(
+        "Copies from {} at {} the values of:\n\n{}\n\nDiff {}",
+        diff.stack_a,
+        reference_a,
+        paths
+            .iter()
+            .map(|path| format!("- `{}`", path))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        diff_id
+    );
+    github_client
+        .open_pull_request(&diff.stack_b, &branch, &base, &title, &body)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::mock::{repo_route, MockGithub};
+    use rocket::serde::json::json;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, ResponseTemplate};
+
+    const FILE: &str = "configs/svc/config-overrides.yml";
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    #[test]
+    fn copies_values_and_removes_missing_paths() {
+        let yaml_a = "port: 80\ndb:\n  host: a\n  pool: 5\n";
+        let yaml_b = "port: 8080\ndb:\n  host: b\nextra: true\nkept: 1\n";
+
+        let updated = copy_paths(yaml_a, yaml_b, &paths(&["/port", "/db/pool", "/extra"]));
+        let updated: Value = serde_yaml::from_str(&updated.unwrap()).unwrap();
+
+        assert_eq!(
+            updated,
+            serde_yaml::from_str::<Value>("port: 80\ndb:\n  host: b\n  pool: 5\nkept: 1\n")
+                .unwrap()
+        );
+        assert_eq!(
+            copy_paths(yaml_a, "", &paths(&["/*"])).unwrap(),
+            yaml_a.to_string()
+        );
+        assert_eq!(
+            copy_paths("a:\n  b: 1\n", "", &paths(&["/a/b"])).unwrap(),
+            "a:\n  b: 1\n"
+        );
+    }
+
+    #[test]
+    fn refuses_paths_naming_no_single_key() {
+        let yaml_a = "1: a\nport: 80\nx/y: 1\nx:\n  y: 2\n";
+        let yaml_b = "1: b\nport: 8080\n";
+
+        for path in ["/", "/x/y", "/missing"] {
+            assert!(
+                matches!(
+                    copy_paths(yaml_a, yaml_b, &paths(&[path])),
+                    Err(ConfigError::Unreconcilable(_))
+                ),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(
+            copy_paths(yaml_a, yaml_b, &paths(&["/port"])).unwrap(),
+            "1: b\nport: 80\n"
+        );
+    }
+
+    #[test]
+    fn refuses_files_with_comments() {
+        for yaml_b in ["# Owned by ops\nport: 8080\n", "port: 8080 # tuned\n"] {
+            assert!(matches!(
+                copy_paths("port: 80\n", yaml_b, &paths(&["/port"])),
+                Err(ConfigError::Unreconcilable(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn only_reported_paths_can_be_synced() {
+        let diff = models::FileDiff {
+            id: None,
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+            file: FILE.to_string(),
+            left_not_right: paths(&["/db/pool"]),
+            right_not_left: paths(&["/extra"]),
+            same_key_diff_value: paths(&["/port"]),
+            sha_a: None,
+            sha_b: None,
+            run_id: None,
+            reviewed: None,
+            pr_url: None,
+            created_at: None,
+            updated_at: None,
+        };
+
+        assert!(unknown_paths(&diff, &paths(&["/port", "/extra"])).is_empty());
+        assert_eq!(
+            unknown_paths(&diff, &paths(&["/port", "/host"])),
+            vec!["/host"]
+        );
+    }
+
+    #[tokio::test]
+    async fn opens_a_pull_request_on_stack_b() {
+        let github = MockGithub::start().await;
+        github
+            .file("stack-a", FILE, Some("sha-a"), "blob-a", "port: 80\n")
+            .await;
+        github.repository("stack-b").await;
+        github
+            .get(
+                &repo_route("stack-b", "/git/ref/heads/main"),
+                json!({ "object": { "sha": "head-b" } }),
+            )
+            .await;
+        github
+            .file(
+                "stack-b",
+                FILE,
+                Some("head-b"),
+                "blob-b",
+                "port: 8080\nhost: b\n",
+            )
+            .await;
+        Mock::given(method("POST"))
+            .and(path(repo_route("stack-b", "/git/refs")))
+            .and(body_partial_json(json!({ "sha": "head-b" })))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({})))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(repo_route("stack-b", &format!("/contents/{}", FILE))))
+            .and(body_partial_json(json!({
+                "sha": "blob-b",
+                "content": base64::encode("port: 80\nhost: b\n")
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(repo_route("stack-b", "/pulls")))
+            .and(body_partial_json(json!({ "base": "main" })))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({
+                "number": 12,
+                "html_url": "https://github.com/my-org/stack-b/pull/12"
+            })))
+            .expect(1)
+            .mount(&github.server)
+            .await;
+
+        let github_client = github.client().await;
+        let diff = models::FileDiff {
+            id: None,
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+            file: FILE.to_string(),
+            left_not_right: Vec::new(),
+            right_not_left: paths(&["/host"]),
+            same_key_diff_value: paths(&["/port"]),
+            sha_a: Some("sha-a".to_string()),
+            sha_b: None,
+            run_id: None,
+            reviewed: None,
+            pr_url: None,
+            created_at: None,
+            updated_at: None,
+        };
+
+        let pull_request = open_reconciliation_pr(&github_client, &diff, &paths(&["/port"]))
+            .await
+            .unwrap();
+
+        assert_eq!(pull_request.number, 12);
+        assert_eq!(
+            pull_request.html_url,
+            "https://github.com/my-org/stack-b/pull/12"
+        );
+    }
+}
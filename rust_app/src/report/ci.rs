@@ -0,0 +1,300 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rocket::http::ContentType;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{json, Value};
+use rocket::{FromFormField, Request};
+
+use super::{categories, escape_html};
+use crate::models;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, FromFormField)]
+pub enum CiFormat {
+    Junit,
+    Sarif,
+}
+
+pub struct CiReport {
+    pub format: CiFormat,
+    pub response: models::ComputeAllDiffResponse,
+    pub reviewed_diffs: Vec<models::FileDiff>,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for CiReport {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
+        let (content_type, body) = match self.format {
+            CiFormat::Junit => (
+                ContentType::XML,
+                render_junit(&self.response, &self.reviewed_diffs),
+            ),
+            CiFormat::Sarif => (
+                ContentType::new("application", "sarif+json"),
+                render_sarif(&self.response, &self.reviewed_diffs).to_string(),
+            ),
+        };
+
+        Response::build()
+            .header(content_type)
+            .sized_body(body.len(), std::io::Cursor::new(body))
+            .ok()
+    }
+}
+
+const RULES: [(&str, &str); 3] = [
+    ("left_not_right", "Key is in stack A but not in stack B"),
+    ("right_not_left", "Key is in stack B but not in stack A"),
+    (
+        "same_key_diff_value",
+        "Key is in both stacks with a different value",
+    ),
+];
+
+fn has_drift(diff: &models::FileDiff) -> bool {
+    categories(diff).iter().any(|(_, paths)| !paths.is_empty())
+}
+
+/// A diff counts as reviewed when it is flagged as such, or when a diff with
+/// the exact same paths was reviewed for this file in a previous run.
+fn is_reviewed(diff: &models::FileDiff, reviewed_diffs: &[models::FileDiff]) -> bool {
+    let same_paths = |a: &[String], b: &[String]| {
+        let mut a = a.to_vec();
+        let mut b = b.to_vec();
+        a.sort();
+        b.sort();
+        a == b
+    };
+
+    diff.reviewed.as_deref() == Some("true")
+        || reviewed_diffs.iter().any(|reviewed| {
+            reviewed.file == diff.file
+                && categories(diff)
+                    .iter()
+                    .zip(categories(reviewed))
+                    .all(|((_, paths), (_, reviewed_paths))| same_paths(paths, reviewed_paths))
+        })
+}
+
+/// One testcase per compared file, failing when the file has drift nobody
+/// reviewed yet.
+pub fn render_junit(
+    response: &models::ComputeAllDiffResponse,
+    reviewed_diffs: &[models::FileDiff],
+) -> String {
+    let suite_name = escape_html(&format!("{} vs {}", response.stack_a, response.stack_b));
+    let class_name = escape_html(&format!("{}.{}", response.stack_a, response.stack_b));
+
+    let diffs: BTreeMap<&str, &models::FileDiff> = response
+        .files_with_diff
+        .iter()
+        .map(|diff| (diff.file.as_str(), diff))
+        .collect();
+    let files: BTreeSet<&str> = response
+        .compared_files
+        .iter()
+        .map(String::as_str)
+        .chain(diffs.keys().copied())
+        .collect();
+
+    let mut failures = 0;
+    let mut testcases = String::new();
+    for file in &files {
+        testcases.push_str(&format!(
+            "    <testcase classname=\"{}\" name=\"{}\" file=\"{}\"",
+            class_name,
+            escape_html(file),
+            escape_html(file)
+        ));
+        let diff = match diffs.get(file) {
+            Some(diff) if has_drift(diff) && !is_reviewed(diff, reviewed_diffs) => diff,
+            _ => {
+                testcases.push_str("/>\n");
+                continue;
+            }
+        };
+
+        failures += 1;
+        let details: Vec<String> = categories(diff)
+            .into_iter()
+            .flat_map(|(category, paths)| {
+                paths
+                    .iter()
+                    .map(move |path| format!("{}: {}", category, path))
+            })
+            .collect();
+        testcases.push_str(&format!(
+            ">\n      <failure type=\"drift\" message=\"{} unreviewed difference(s)\">{}</failure>\n    </testcase>\n",
+            details.len(),
+            escape_html(&details.join("\n"))
+        ));
+    }
+
+    let tests = files.len();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <testsuites name=\"compare_configs\" tests=\"{tests}\" failures=\"{failures}\">\n  \
+         <testsuite name=\"{suite_name}\" tests=\"{tests}\" failures=\"{failures}\">\n\
+         {testcases}  </testsuite>\n\
+         </testsuites>\n"
+    )
+}
+
+/// One result per differing path, located in the config file of stack A.
+pub fn render_sarif(
+    response: &models::ComputeAllDiffResponse,
+    reviewed_diffs: &[models::FileDiff],
+) -> Value {
+    let rules: Vec<Value> = RULES
+        .iter()
+        .map(|(category, description)| {
+            json!({
+                "id": category,
+                "shortDescription": { "text": description },
+            })
+        })
+        .collect();
+
+    let mut results: Vec<Value> = Vec::new();
+    for diff in &response.files_with_diff {
+        let level = if is_reviewed(diff, reviewed_diffs) {
+            "note"
+        } else {
+            "warning"
+        };
+        for (category, paths) in categories(diff) {
+            for path in paths {
+                results.push(json!({
+                    "ruleId": category,
+                    "level": level,
+                    "message": {
+                        "text": format!(
+                            "{} differs between {} and {}",
+                            path, response.stack_a, response.stack_b
+                        ),
+                    },
+                    "locations": [{
+                        "physicalLocation": {
+                            "artifactLocation": { "uri": diff.file },
+                        },
+                        "logicalLocations": [{ "fullyQualifiedName": path }],
+                    }],
+                }));
+            }
+        }
+    }
+
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "compare_configs",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": rules,
+                },
+            },
+            "automationDetails": {
+                "id": format!("{}/{}", response.stack_a, response.stack_b),
+            },
+            "results": results,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_diff(file: &str, same_key_diff_value: &[&str], reviewed: &str) -> models::FileDiff {
+        models::FileDiff {
+            id: None,
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+            file: file.to_string(),
+            left_not_right: Vec::new(),
+            right_not_left: Vec::new(),
+            same_key_diff_value: same_key_diff_value.iter().map(|p| p.to_string()).collect(),
+            sha_a: None,
+            sha_b: None,
+            run_id: None,
+            reviewed: Some(reviewed.to_string()),
+            pr_url: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn response(files_with_diff: Vec<models::FileDiff>) -> models::ComputeAllDiffResponse {
+        models::ComputeAllDiffResponse {
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+            run_id: None,
+            files_with_diff,
+            compared_files: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn junit_fails_only_on_unreviewed_drift() {
+        let response = response(vec![
+            file_diff("service-a/config-overrides.yml", &["/port"], "false"),
+            file_diff("service-b/config-overrides.yml", &["/host"], "false"),
+        ]);
+        let reviewed_diffs = vec![file_diff(
+            "service-b/config-overrides.yml",
+            &["/host"],
+            "true",
+        )];
+
+        let report = render_junit(&response, &reviewed_diffs);
+
+        assert!(report.contains("tests=\"2\" failures=\"1\""));
+        assert!(report.contains(
+            "name=\"service-a/config-overrides.yml\" file=\"service-a/config-overrides.yml\">\n      <failure"
+        ));
+        assert!(report.contains(
+            "name=\"service-b/config-overrides.yml\" file=\"service-b/config-overrides.yml\"/>"
+        ));
+    }
+
+    #[test]
+    fn junit_passes_every_file_without_drift() {
+        let response = models::ComputeAllDiffResponse {
+            compared_files: vec![
+                "service-a/config-overrides.yml".to_string(),
+                "service-b/config-overrides.yml".to_string(),
+            ],
+            ..response(Vec::new())
+        };
+
+        let report = render_junit(&response, &[]);
+
+        assert!(report.contains("tests=\"2\" failures=\"0\""));
+        assert_eq!(report.matches("<testcase ").count(), 2);
+        assert!(!report.contains("<failure"));
+    }
+
+    #[test]
+    fn sarif_has_one_result_per_path() {
+        let response = response(vec![file_diff(
+            "service-a/config-overrides.yml",
+            &["/port", "/host"],
+            "false",
+        )]);
+
+        let report = render_sarif(&response, &[]);
+        let results = report["runs"][0]["results"].as_array().unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["ruleId"], "same_key_diff_value");
+        assert_eq!(results[0]["level"], "warning");
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "service-a/config-overrides.yml"
+        );
+        assert_eq!(
+            results[1]["locations"][0]["logicalLocations"][0]["fullyQualifiedName"],
+            "/host"
+        );
+    }
+}
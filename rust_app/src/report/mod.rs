@@ -0,0 +1,459 @@
+use std::collections::BTreeMap;
+
+use rocket::http::{ContentType, MediaType, Status};
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::Request;
+
+use crate::models;
+
+pub mod ci;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReportFormat {
+    Json,
+    Markdown,
+    Html,
+    Csv,
+}
+
+impl ReportFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(ReportFormat::Json),
+            "md" | "markdown" => Some(ReportFormat::Markdown),
+            "html" => Some(ReportFormat::Html),
+            "csv" => Some(ReportFormat::Csv),
+            _ => None,
+        }
+    }
+
+    fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        if media_type.is_json() {
+            Some(ReportFormat::Json)
+        } else if media_type.is_html() {
+            Some(ReportFormat::Html)
+        } else if media_type.is_csv() {
+            Some(ReportFormat::Csv)
+        } else if media_type.top() == "text" && media_type.sub() == "markdown" {
+            Some(ReportFormat::Markdown)
+        } else {
+            None
+        }
+    }
+}
+
+/// Picks the report format from the `format` query parameter, falling back on
+/// the `Accept` header and then on JSON.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ReportFormat {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if let Some(format) = req.query_value::<&str>("format") {
+            return match format.ok().and_then(ReportFormat::from_name) {
+                Some(report_format) => Outcome::Success(report_format),
+                None => Outcome::Error((
+                    Status::BadRequest,
+                    "Unknown report format, expected json, markdown, html or csv".to_string(),
+                )),
+            };
+        }
+
+        let report_format = req
+            .accept()
+            .and_then(|accept| accept.media_types().find_map(ReportFormat::from_media_type))
+            .unwrap_or(ReportFormat::Json);
+
+        Outcome::Success(report_format)
+    }
+}
+
+pub struct DiffReport {
+    pub format: ReportFormat,
+    /// The page asked for, which the link to the next one keeps the limit of.
+    pub page: models::Page,
+    pub response: models::GetAllDiffsResponse,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for DiffReport {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        // Only JSON has room for the cursor, every format gets it as a link
+        let link = self
+            .response
+            .next_cursor
+            .as_deref()
+            .map(|next_cursor| next_link(req, &self.page, next_cursor));
+        let stack_a = &self.response.stack_a;
+        let stack_b = &self.response.stack_b;
+        let file_diffs = &self.response.files_with_diff;
+
+        let rendered = match self.format {
+            ReportFormat::Json => None,
+            ReportFormat::Markdown => Some((
+                ContentType::new("text", "markdown"),
+                render_markdown(stack_a, stack_b, file_diffs),
+            )),
+            ReportFormat::Html => {
+                Some((ContentType::HTML, render_html(stack_a, stack_b, file_diffs)))
+            }
+            ReportFormat::Csv => Some((ContentType::CSV, render_csv(file_diffs))),
+        };
+        let mut response = match rendered {
+            None => Json(self.response).respond_to(req)?,
+            Some((content_type, body)) => Response::build()
+                .header(content_type)
+                .sized_body(body.len(), std::io::Cursor::new(body))
+                .finalize(),
+        };
+
+        if let Some(link) = link {
+            response.set_raw_header("Link", link);
+        }
+        Ok(response)
+    }
+}
+
+/// The request again with the cursor of the next page, its limit being kept.
+fn next_link(req: &Request<'_>, page: &models::Page, next_cursor: &str) -> String {
+    let mut query: Vec<String> = req
+        .uri()
+        .query()
+        .map(|query| {
+            query
+                .as_str()
+                .split('&')
+                .filter(|pair| !pair.starts_with("cursor=") && !pair.starts_with("limit="))
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(limit) = page.limit {
+        query.push(format!("limit={}", limit));
+    }
+    // Cursors are URL-safe base64
+    query.push(format!("cursor={}", next_cursor));
+    format!("<{}?{}>; rel=\"next\"", req.uri().path(), query.join("&"))
+}
+
+/// The diff categories of a `FileDiff`, same-valued keys are never reported.
+pub(crate) fn categories(diff: &models::FileDiff) -> [(&'static str, &[String]); 3] {
+    [
+        ("left_not_right", &diff.left_not_right),
+        ("right_not_left", &diff.right_not_left),
+        ("same_key_diff_value", &diff.same_key_diff_value),
+    ]
+}
+
+struct ReportEntry<'a> {
+    category: &'static str,
+    path: &'a str,
+    diff: &'a models::FileDiff,
+}
+
+/// Flattens the diffs into one entry per path, grouped by file and ordered by
+/// category inside each file.
+fn group_by_file(file_diffs: &[models::FileDiff]) -> BTreeMap<&str, Vec<ReportEntry<'_>>> {
+    let mut grouped: BTreeMap<&str, Vec<ReportEntry>> = BTreeMap::new();
+
+    for diff in file_diffs {
+        let entries = grouped.entry(diff.file.as_str()).or_default();
+        for (category, paths) in categories(diff) {
+            entries.extend(paths.iter().map(|path| ReportEntry {
+                category,
+                path,
+                diff,
+            }));
+        }
+    }
+
+    for entries in grouped.values_mut() {
+        entries.sort_by_key(|entry| entry.category);
+    }
+    grouped
+}
+
+fn created_at(diff: &models::FileDiff) -> String {
+    diff.created_at
+        .and_then(|date| date.try_to_rfc3339_string().ok())
+        .unwrap_or_default()
+}
+
+fn reviewed(diff: &models::FileDiff) -> &str {
+    diff.reviewed.as_deref().unwrap_or("false")
+}
+
+fn escape_markdown(value: &str) -> String {
+    value.replace('|', "\\|")
+}
+
+/// `value` as inline code, fenced by more backticks than any run of them it
+/// has, and padded when it starts or ends with one.
+fn code_span(value: &str) -> String {
+    let longest_run = value
+        .split(|character| character != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    let fence = "`".repeat(longest_run + 1);
+    let padding = if value.starts_with('`') || value.ends_with('`') {
+        " "
+    } else {
+        ""
+    };
+    format!(
+        "{}{}{}{}{}",
+        fence,
+        padding,
+        escape_markdown(value),
+        padding,
+        fence
+    )
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn render_markdown(stack_a: &str, stack_b: &str, file_diffs: &[models::FileDiff]) -> String {
+    let mut report = format!(
+        "# Config drift between {} and {}\n",
+        escape_markdown(stack_a),
+        escape_markdown(stack_b)
+    );
+
+    for (file, entries) in group_by_file(file_diffs) {
+        report.push_str(&format!("\n## {}\n\n", escape_markdown(file)));
+        if entries.is_empty() {
+            report.push_str("No diff\n");
+            continue;
+        }
+        report.push_str("| Category | Path | Created at | Reviewed |\n");
+        report.push_str("| --- | --- | --- | --- |\n");
+        for entry in entries {
+            report.push_str(&format!(
+                "| {} | {} | {} | {} |\n",
+                entry.category,
+                code_span(entry.path),
+                created_at(entry.diff),
+                reviewed(entry.diff)
+            ));
+        }
+    }
+    report
+}
+
+pub fn render_html(stack_a: &str, stack_b: &str, file_diffs: &[models::FileDiff]) -> String {
+    let title = format!(
+        "Config drift between {} and {}",
+        escape_html(stack_a),
+        escape_html(stack_b)
+    );
+    let mut report = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>\n\
+         body {{ font-family: sans-serif; margin: 2em; }}\n\
+         table {{ border-collapse: collapse; margin-bottom: 2em; }}\n\
+         th, td {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; }}\n\
+         th {{ background: #f0f0f0; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+
+    for (file, entries) in group_by_file(file_diffs) {
+        report.push_str(&format!("<h2>{}</h2>\n", escape_html(file)));
+        if entries.is_empty() {
+            report.push_str("<p>No diff</p>\n");
+            continue;
+        }
+        report.push_str(
+            "<table>\n<tr><th>Category</th><th>Path</th><th>Created at</th><th>Reviewed</th></tr>\n",
+        );
+        for entry in entries {
+            report.push_str(&format!(
+                "<tr><td>{}</td><td><code>{}</code></td><td>{}</td><td>{}</td></tr>\n",
+                entry.category,
+                escape_html(entry.path),
+                created_at(entry.diff),
+                escape_html(reviewed(entry.diff))
+            ));
+        }
+        report.push_str("</table>\n");
+    }
+    report.push_str("</body>\n</html>\n");
+    report
+}
+
+pub fn render_csv(file_diffs: &[models::FileDiff]) -> String {
+    let mut report = String::from("file,category,path,created_at,reviewed,diff_id\n");
+
+    for (file, entries) in group_by_file(file_diffs) {
+        for entry in entries {
+            let diff_id = entry.diff.id.map(|id| id.to_hex()).unwrap_or_default();
+            report.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                escape_csv(file),
+                entry.category,
+                escape_csv(entry.path),
+                created_at(entry.diff),
+                escape_csv(reviewed(entry.diff)),
+                diff_id
+            ));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_diff(
+        file: &str,
+        left_not_right: &[&str],
+        same_key_diff_value: &[&str],
+    ) -> models::FileDiff {
+        models::FileDiff {
+            id: None,
+            stack_a: "stack-a".to_string(),
+            stack_b: "stack-b".to_string(),
+            file: file.to_string(),
+            left_not_right: left_not_right.iter().map(|p| p.to_string()).collect(),
+            right_not_left: Vec::new(),
+            same_key_diff_value: same_key_diff_value.iter().map(|p| p.to_string()).collect(),
+            sha_a: None,
+            sha_b: None,
+            run_id: None,
+            reviewed: Some("false".to_string()),
+            pr_url: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn next_pages_are_linked_with_the_same_limit() {
+        let client = rocket::local::blocking::Client::debug_with(Vec::new()).unwrap();
+        let request = client.get("/runs/abc/diffs?format=csv&cursor=old");
+        let page = models::Page {
+            limit: Some(2),
+            cursor: Some("old".to_string()),
+        };
+
+        assert_eq!(
+            next_link(request.inner(), &page, "new"),
+            "</runs/abc/diffs?format=csv&limit=2&cursor=new>; rel=\"next\""
+        );
+    }
+
+    /// Two pages, the second one being asked for with the cursor of the first.
+    #[get("/diffs?<page..>")]
+    fn paged_diffs(page: models::Page, format: ReportFormat) -> DiffReport {
+        let next_cursor = page.cursor.is_none().then(|| "page-2".to_string());
+        DiffReport {
+            format,
+            page,
+            response: models::GetAllDiffsResponse {
+                stack_a: "stack-a".to_string(),
+                stack_b: "stack-b".to_string(),
+                files_with_diff: vec![file_diff("a/service", &["/a"], &[])],
+                next_cursor,
+            },
+        }
+    }
+
+    #[test]
+    fn listings_are_paged_through_their_links() {
+        let rocket = rocket::build().mount("/", routes![paged_diffs]);
+        let client = rocket::local::blocking::Client::tracked(rocket).unwrap();
+
+        let mut uri = "/diffs?format=csv".to_string();
+        let mut uris = Vec::new();
+        loop {
+            let response = client.get(uri.clone()).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            uris.push(uri);
+            match response.headers().get_one("Link") {
+                Some(link) => {
+                    uri = link
+                        .trim_start_matches('<')
+                        .split('>')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                }
+                None => break,
+            }
+        }
+
+        assert_eq!(
+            uris,
+            vec!["/diffs?format=csv", "/diffs?format=csv&cursor=page-2"]
+        );
+    }
+
+    #[test]
+    fn render_csv_groups_by_file_and_category() {
+        let diffs = vec![
+            file_diff("b/service", &[], &["/port"]),
+            file_diff("a/service", &["/x,y"], &["/host"]),
+        ];
+
+        assert_eq!(
+            render_csv(&diffs),
+            "file,category,path,created_at,reviewed,diff_id\n\
+             a/service,left_not_right,\"/x,y\",,false,\n\
+             a/service,same_key_diff_value,/host,,false,\n\
+             b/service,same_key_diff_value,/port,,false,\n"
+        );
+    }
+
+    #[test]
+    fn render_markdown_has_one_section_per_file() {
+        let diffs = vec![
+            file_diff("a/service", &["/a|b"], &[]),
+            file_diff("b/service", &[], &[]),
+        ];
+
+        let report = render_markdown("stack-a", "stack-b", &diffs);
+
+        assert!(report.starts_with("# Config drift between stack-a and stack-b\n"));
+        assert!(report.contains("## a/service\n"));
+        assert!(report.contains("| left_not_right | `/a\\|b` |  | false |\n"));
+        assert!(report.contains("## b/service\n\nNo diff\n"));
+    }
+
+    #[test]
+    fn markdown_code_spans_outgrow_the_backticks_of_paths() {
+        let diffs = vec![file_diff("service", &["/a`b", "/``c", "`d"], &[])];
+
+        let report = render_markdown("stack-a", "stack-b", &diffs);
+
+        assert!(report.contains("| left_not_right | ``/a`b`` |"));
+        assert!(report.contains("| left_not_right | ```/``c``` |"));
+        assert!(report.contains("| left_not_right | `` `d `` |"));
+    }
+
+    #[test]
+    fn render_html_escapes_paths() {
+        let diffs = vec![file_diff("a/service", &["/<script>"], &[])];
+
+        let report = render_html("stack-a", "stack-b", &diffs);
+
+        assert!(report.contains("<code>/&lt;script&gt;</code>"));
+        assert!(!report.contains("<code>/<script></code>"));
+    }
+}
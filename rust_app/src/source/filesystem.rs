@@ -0,0 +1,235 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{join_under, ConfigSource, SourceEntry, SourceTree};
+use crate::github::ConfigError;
+
+/// The only revision of a directory, which has no history.
+const WORKING_TREE: &str = "working-tree";
+
+/// Reads every stack as a directory under `root`, `owner/repo` stacks being nested.
+pub struct FilesystemSource {
+    root: PathBuf,
+}
+
+impl FilesystemSource {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FilesystemSource { root: root.into() }
+    }
+
+    fn resolve(&self, stack: &str, path: &str) -> Result<PathBuf, ConfigError> {
+        join_under(&self.root, stack, path)
+    }
+
+    /// Resolves `path` through its symlinks, which must not lead out of the stack.
+    async fn resolve_file(&self, stack: &str, path: &str) -> Result<PathBuf, ConfigError> {
+        let stack_root = tokio::fs::canonicalize(self.resolve(stack, "")?)
+            .await
+            .map_err(|e| io_error(e, stack))?;
+        let file = tokio::fs::canonicalize(self.resolve(stack, path)?)
+            .await
+            .map_err(|e| io_error(e, path))?;
+        if !file.starts_with(&stack_root) {
+            return Err(ConfigError::InvalidStack(format!(
+                "Invalid path {}, it links outside of {}",
+                path, stack
+            )));
+        }
+        Ok(file)
+    }
+}
+
+#[rocket::async_trait]
+impl ConfigSource for FilesystemSource {
+    async fn resolve_revision(
+        &self,
+        stack: &str,
+        reference: Option<&str>,
+    ) -> Result<String, ConfigError> {
+        if let Some(reference) = reference {
+            return Err(anyhow::anyhow!(
+                "Cannot read {} of {}, directories have no revisions",
+                reference,
+                stack
+            )
+            .into());
+        }
+        let stack_root = self.resolve(stack, "")?;
+        if !tokio::fs::metadata(&stack_root)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            return Err(ConfigError::NotFound(stack.to_string()));
+        }
+        Ok(WORKING_TREE.to_string())
+    }
+
+    async fn list_files(&self, stack: &str, _revision: &str) -> Result<SourceTree, ConfigError> {
+        let stack_root = self.resolve(stack, "")?;
+        let stack_name = stack.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let mut entries = Vec::new();
+            walk(&stack_root, "", &mut entries).map_err(|e| io_error(e, &stack_name))?;
+            info!("Listed {} entries of {}", entries.len(), stack_name);
+            Ok(SourceTree { entries })
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+    }
+
+    async fn read_file(
+        &self,
+        stack: &str,
+        path: &str,
+        _revision: &str,
+    ) -> Result<String, ConfigError> {
+        tokio::fs::read_to_string(self.resolve_file(stack, path).await?)
+            .await
+            .map_err(|e| io_error(e, path))
+    }
+}
+
+fn walk(directory: &Path, prefix: &str, entries: &mut Vec<SourceEntry>) -> io::Result<()> {
+    for dir_entry in fs::read_dir(directory)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        if name == ".git" {
+            continue;
+        }
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+
+        // Symlinks are skipped, they could point outside of the stack
+        let file_type = dir_entry.file_type()?;
+        if file_type.is_dir() {
+            entries.push(SourceEntry {
+                path: path.clone(),
+                is_dir: true,
+                id: String::new(),
+            });
+            walk(&dir_entry.path(), &path, entries)?;
+        } else if file_type.is_file() {
+            // Files would have to be read to get a content id, they are when compared
+            entries.push(SourceEntry {
+                path,
+                is_dir: false,
+                id: String::new(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn io_error(e: io::Error, path: &str) -> ConfigError {
+    if e.kind() == io::ErrorKind::NotFound {
+        ConfigError::NotFound(path.to_string())
+    } else {
+        anyhow::Error::from(e)
+            .context(format!("Cannot read {}", path))
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::patterns::FilePatterns;
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_and_reads_stack_directories() {
+        let root = tempfile::tempdir().unwrap();
+        write(
+            root.path(),
+            "stack-a/configs/svc/config-overrides.yml",
+            "a: 1\n",
+        );
+        write(
+            root.path(),
+            "stack-b/configs/svc/config-overrides.yml",
+            "a: 1\n",
+        );
+        let source = FilesystemSource::new(root.path());
+
+        let revision = source.resolve_revision("stack-a", None).await.unwrap();
+        let tree_a = source.list_files("stack-a", &revision).await.unwrap();
+        let tree_b = source.list_files("stack-b", &revision).await.unwrap();
+        let file = "configs/svc/config-overrides.yml";
+
+        let patterns = FilePatterns::parse(&["configs/**"]).unwrap();
+        let matching_files = tree_a.matching_files(&patterns);
+        assert_eq!(matching_files.len(), 1);
+        assert_eq!(matching_files[0].id, "");
+        assert_eq!(tree_b.matching_files(&patterns).len(), 1);
+        assert_eq!(
+            source.read_file("stack-a", file, &revision).await.unwrap(),
+            "a: 1\n"
+        );
+        assert!(matches!(
+            source.read_file("stack-a", "missing.yml", &revision).await,
+            Err(ConfigError::NotFound(_))
+        ));
+        assert!(matches!(
+            source.resolve_revision("stack-c", None).await,
+            Err(ConfigError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_paths_outside_of_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let source = FilesystemSource::new(root.path().join("stacks"));
+
+        assert!(source
+            .read_file("../stacks", "secret", WORKING_TREE)
+            .await
+            .is_err());
+        assert!(source
+            .read_file("stack-a", "../../secret", WORKING_TREE)
+            .await
+            .is_err());
+        assert!(source.resolve_revision("/etc", None).await.is_err());
+        assert!(source
+            .resolve_revision("stack-a", Some("main"))
+            .await
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_symlinks_outside_of_the_stack() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path(), "secret", "token: abc\n");
+        write(root.path(), "stacks/stack-a/config.yml", "a: 1\n");
+        let stack_root = root.path().join("stacks/stack-a");
+        std::os::unix::fs::symlink(root.path().join("secret"), stack_root.join("secret.yml"))
+            .unwrap();
+        std::os::unix::fs::symlink(stack_root.join("config.yml"), stack_root.join("link.yml"))
+            .unwrap();
+        let source = FilesystemSource::new(root.path().join("stacks"));
+
+        assert!(matches!(
+            source
+                .read_file("stack-a", "secret.yml", WORKING_TREE)
+                .await,
+            Err(ConfigError::InvalidStack(_))
+        ));
+        assert_eq!(
+            source
+                .read_file("stack-a", "link.yml", WORKING_TREE)
+                .await
+                .unwrap(),
+            "a: 1\n"
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use chrono::DateTime;
+use git2::{BlameOptions, ErrorCode, ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use std::path::{Path, PathBuf};
+
+use super::{join_under, BlameRange, ConfigSource, SourceEntry, SourceTree};
+use crate::github::ConfigError;
+
+/// Reads every stack from a local git repository under `root`, either a working
+/// clone in `root/<stack>` or a bare mirror in `root/<stack>.git`.
+pub struct GitSource {
+    root: PathBuf,
+}
+
+impl GitSource {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        GitSource { root: root.into() }
+    }
+
+    fn repository_path(&self, stack: &str) -> Result<PathBuf, ConfigError> {
+        let path = join_under(&self.root, stack, "")?;
+        if path.is_dir() {
+            return Ok(path);
+        }
+        let bare_path = join_under(&self.root, &format!("{}.git", stack), "")?;
+        if bare_path.is_dir() {
+            return Ok(bare_path);
+        }
+        Err(ConfigError::NotFound(stack.to_string()))
+    }
+
+    /// `git2::Repository` isn't `Sync`, so every call opens the repository on
+    /// a blocking thread.
+    async fn with_repository<T, F>(&self, stack: &str, f: F) -> Result<T, ConfigError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Repository) -> Result<T, ConfigError> + Send + 'static,
+    {
+        let path = self.repository_path(stack)?;
+        tokio::task::spawn_blocking(move || {
+            let repository =
+                Repository::open(&path).map_err(|e| git_error(e, &path.to_string_lossy()))?;
+            f(&repository)
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+    }
+}
+
+#[rocket::async_trait]
+impl ConfigSource for GitSource {
+    async fn resolve_revision(
+        &self,
+        stack: &str,
+        reference: Option<&str>,
+    ) -> Result<String, ConfigError> {
+        let reference = reference.unwrap_or("HEAD").to_string();
+        let stack_name = stack.to_string();
+
+        self.with_repository(stack, move |repository| {
+            let commit = repository
+                .revparse_single(&reference)
+                .and_then(|object| object.peel_to_commit())
+                .map_err(|e| git_error(e, &reference))?;
+            info!(
+                "Resolved {} for {} to commit {}",
+                reference,
+                stack_name,
+                commit.id()
+            );
+            Ok(commit.id().to_string())
+        })
+        .await
+    }
+
+    async fn list_files(&self, stack: &str, revision: &str) -> Result<SourceTree, ConfigError> {
+        let revision = revision.to_string();
+
+        self.with_repository(stack, move |repository| {
+            let tree = commit_tree(repository, &revision)?;
+            let mut entries = Vec::new();
+            tree.walk(TreeWalkMode::PreOrder, |parent, entry| {
+                let is_dir = match entry.kind() {
+                    Some(ObjectType::Tree) => true,
+                    Some(ObjectType::Blob) => false,
+                    // Submodules point to commits of other repositories
+                    _ => return TreeWalkResult::Skip,
+                };
+                entries.push(SourceEntry {
+                    path: format!("{}{}", parent, entry.name().unwrap_or_default()),
+                    is_dir,
+                    id: entry.id().to_string(),
+                });
+                TreeWalkResult::Ok
+            })
+            .map_err(|e| git_error(e, &revision))?;
+            Ok(SourceTree { entries })
+        })
+        .await
+    }
+
+    async fn read_file(
+        &self,
+        stack: &str,
+        path: &str,
+        revision: &str,
+    ) -> Result<String, ConfigError> {
+        let path = path.trim_start_matches('/').to_string();
+        let revision = revision.to_string();
+
+        self.with_repository(stack, move |repository| {
+            let entry = commit_tree(repository, &revision)?
+                .get_path(Path::new(&path))
+                .map_err(|e| git_error(e, &path))?;
+            read_blob(repository, entry.id())
+        })
+        .await
+    }
+
+    /// Blobs are content-addressed, so the tree entry is enough to read them.
+    async fn read_entry(
+        &self,
+        stack: &str,
+        _revision: &str,
+        entry: &SourceEntry,
+    ) -> Result<String, ConfigError> {
+        let oid = Oid::from_str(&entry.id).map_err(|e| git_error(e, &entry.id))?;
+        self.with_repository(stack, move |repository| read_blob(repository, oid))
+            .await
+    }
+
+    async fn blame_file(
+        &self,
+        stack: &str,
+        path: &str,
+        revision: &str,
+    ) -> Result<Vec<BlameRange>, ConfigError> {
+        let path = path.trim_start_matches('/').to_string();
+        let revision = revision.to_string();
+
+        self.with_repository(stack, move |repository| {
+            let oid = Oid::from_str(&revision).map_err(|e| git_error(e, &revision))?;
+            let mut options = BlameOptions::new();
+            options.newest_commit(oid);
+            let blame = repository
+                .blame_file(Path::new(&path), Some(&mut options))
+                .map_err(|e| git_error(e, &path))?;
+
+            Ok(blame
+                .iter()
+                .map(|hunk| {
+                    let signature = hunk.final_signature();
+                    let start_line = hunk.final_start_line();
+                    BlameRange {
+                        start_line,
+                        end_line: start_line + hunk.lines_in_hunk().saturating_sub(1),
+                        commit_sha: hunk.final_commit_id().to_string(),
+                        author: signature.name().unwrap_or_default().to_string(),
+                        date: DateTime::from_timestamp(signature.when().seconds(), 0)
+                            .unwrap_or_default(),
+                    }
+                })
+                .collect())
+        })
+        .await
+    }
+}
+
+fn commit_tree<'r>(
+    repository: &'r Repository,
+    revision: &str,
+) -> Result<git2::Tree<'r>, ConfigError> {
+    Oid::from_str(revision)
+        .and_then(|oid| repository.find_commit(oid))
+        .and_then(|commit| commit.tree())
+        .map_err(|e| git_error(e, revision))
+}
+
+fn read_blob(repository: &Repository, oid: Oid) -> Result<String, ConfigError> {
+    let blob = repository
+        .find_blob(oid)
+        .map_err(|e| git_error(e, &oid.to_string()))?;
+    String::from_utf8(blob.content().to_vec()).map_err(ConfigError::from)
+}
+
+fn git_error(e: git2::Error, what: &str) -> ConfigError {
+    if e.code() == ErrorCode::NotFound {
+        ConfigError::NotFound(what.to_string())
+    } else {
+        anyhow::Error::from(e)
+            .context(format!("Cannot read {}", what))
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::patterns::FilePatterns;
+    use git2::Signature;
+
+    fn commit(repository: &Repository, files: &[(&str, &str)], message: &str) -> Oid {
+        let workdir = repository.workdir().unwrap();
+        let mut index = repository.index().unwrap();
+        for (path, content) in files {
+            let full_path = workdir.join(path);
+            std::fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+            std::fs::write(full_path, content).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+
+        let tree = repository.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("Jane Doe", "jane@example.com").unwrap();
+        let parents: Vec<git2::Commit> = repository
+            .head()
+            .ok()
+            .and_then(|head| head.peel_to_commit().ok())
+            .into_iter()
+            .collect();
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+        repository
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                &tree,
+                &parents,
+            )
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_stacks_at_any_revision() {
+        let root = tempfile::tempdir().unwrap();
+        let repository = Repository::init(root.path().join("stack-a")).unwrap();
+        let file = "configs/svc/config-overrides.yml";
+        let first = commit(&repository, &[(file, "a: 1\n")], "First");
+        commit(&repository, &[(file, "a: 2\n")], "Second");
+        repository
+            .tag_lightweight("v1", &repository.find_object(first, None).unwrap(), false)
+            .unwrap();
+        let source = GitSource::new(root.path());
+
+        let head = source.resolve_revision("stack-a", None).await.unwrap();
+        let v1 = source
+            .resolve_revision("stack-a", Some("v1"))
+            .await
+            .unwrap();
+        assert_eq!(v1, first.to_string());
+
+        let tree = source.list_files("stack-a", &head).await.unwrap();
+        let patterns = FilePatterns::parse(&["configs/**"]).unwrap();
+        let matching_files = tree.matching_files(&patterns);
+        assert_eq!(matching_files.len(), 1);
+        assert_eq!(
+            source
+                .read_entry("stack-a", &head, matching_files[0])
+                .await
+                .unwrap(),
+            "a: 2\n"
+        );
+        assert_eq!(
+            source.read_file("stack-a", file, &v1).await.unwrap(),
+            "a: 1\n"
+        );
+        assert!(matches!(
+            source.read_file("stack-a", "missing.yml", &v1).await,
+            Err(ConfigError::NotFound(_))
+        ));
+        assert!(matches!(
+            source
+                .resolve_revision("stack-a", Some("no-such-branch"))
+                .await,
+            Err(ConfigError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn blames_lines_at_a_revision() {
+        let root = tempfile::tempdir().unwrap();
+        let repository = Repository::init(root.path().join("stack-a")).unwrap();
+        let file = "configs/svc/config-overrides.yml";
+        let first = commit(&repository, &[(file, "a: 1\nb: 1\n")], "First");
+        let second = commit(&repository, &[(file, "a: 1\nb: 2\n")], "Second");
+        let source = GitSource::new(root.path());
+
+        let ranges = source
+            .blame_file("stack-a", file, &second.to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            ranges
+                .iter()
+                .map(|range| (range.start_line, range.end_line, range.commit_sha.clone()))
+                .collect::<Vec<_>>(),
+            vec![(1, 1, first.to_string()), (2, 2, second.to_string())]
+        );
+        assert_eq!(ranges[0].author, "Jane Doe");
+
+        let ranges = source
+            .blame_file("stack-a", file, &first.to_string())
+            .await
+            .unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].end_line, 2);
+    }
+
+    #[tokio::test]
+    async fn finds_bare_mirrors() {
+        let root = tempfile::tempdir().unwrap();
+        Repository::init_bare(root.path().join("stack-b.git")).unwrap();
+        let source = GitSource::new(root.path());
+
+        assert!(source.repository_path("stack-b").is_ok());
+        assert!(matches!(
+            source.repository_path("stack-c"),
+            Err(ConfigError::NotFound(_))
+        ));
+        assert!(source.repository_path("../stack-b").is_err());
+    }
+}
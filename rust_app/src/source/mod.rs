@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use crate::github::ConfigError;
+use patterns::FilePatterns;
+
+pub mod filesystem;
+pub mod git;
+pub mod patterns;
+
+/// A file or directory of a stack. The `id` of a file addresses its content,
+/// files with the same one being identical, or is empty when the source
+/// can't tell without reading the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceEntry {
+    pub path: String,
+    pub is_dir: bool,
+    pub id: String,
+}
+
+/// Every entry of a stack at one revision, paths being relative to its root.
+#[derive(Debug, Default)]
+pub struct SourceTree {
+    pub entries: Vec<SourceEntry>,
+}
+
+impl SourceTree {
+    /// Files of every folder, at any depth, that `patterns` match.
+    pub fn matching_files(&self, patterns: &FilePatterns) -> Vec<&SourceEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.is_dir && patterns.is_match(&entry.path))
+            .collect()
+    }
+}
+
+/// The commit that last changed lines `start_line` to `end_line` of a file,
+/// counting from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameRange {
+    pub start_line: usize,
+    pub end_line: usize,
+    pub commit_sha: String,
+    pub author: String,
+    pub date: chrono::DateTime<chrono::Utc>,
+}
+
+/// Where stacks are read from, selected with `CONFIG_SOURCE`.
+#[rocket::async_trait]
+pub trait ConfigSource: Send + Sync {
+    /// Pins `reference`, the latest revision when `None`, so that every file of
+    /// a comparison is read from the same snapshot.
+    async fn resolve_revision(
+        &self,
+        stack: &str,
+        reference: Option<&str>,
+    ) -> Result<String, ConfigError>;
+
+    async fn list_files(&self, stack: &str, revision: &str) -> Result<SourceTree, ConfigError>;
+
+    async fn read_file(
+        &self,
+        stack: &str,
+        path: &str,
+        revision: &str,
+    ) -> Result<String, ConfigError>;
+
+    /// Reads an entry listed by `list_files`, sources able to read by id can
+    /// skip the path lookup.
+    async fn read_entry(
+        &self,
+        stack: &str,
+        revision: &str,
+        entry: &SourceEntry,
+    ) -> Result<String, ConfigError> {
+        self.read_file(stack, &entry.path, revision).await
+    }
+
+    /// Who last changed each line of a file, which sources without history
+    /// can't tell.
+    async fn blame_file(
+        &self,
+        stack: &str,
+        _path: &str,
+        _revision: &str,
+    ) -> Result<Vec<BlameRange>, ConfigError> {
+        Err(ConfigError::Unsupported(format!(
+            "The source of {} has no blame",
+            stack
+        )))
+    }
+}
+
+/// Sends stacks written `prefix:stack` to the source registered for `prefix`,
+/// and every other stack to the default one, so that sources can be compared.
+pub struct RoutedSource {
+    default: Arc<dyn ConfigSource>,
+    prefixed: HashMap<String, Arc<dyn ConfigSource>>,
+}
+
+impl RoutedSource {
+    pub fn new(default: Arc<dyn ConfigSource>) -> Self {
+        RoutedSource {
+            default,
+            prefixed: HashMap::new(),
+        }
+    }
+
+    pub fn with(mut self, prefix: &str, source: Arc<dyn ConfigSource>) -> Self {
+        self.prefixed.insert(prefix.to_string(), source);
+        self
+    }
+
+    fn route<'a>(&self, stack: &'a str) -> (&dyn ConfigSource, &'a str) {
+        stack
+            .split_once(':')
+            .and_then(|(prefix, stack)| {
+                self.prefixed
+                    .get(prefix)
+                    .map(|source| (source.as_ref(), stack))
+            })
+            .unwrap_or((self.default.as_ref(), stack))
+    }
+}
+
+#[rocket::async_trait]
+impl ConfigSource for RoutedSource {
+    async fn resolve_revision(
+        &self,
+        stack: &str,
+        reference: Option<&str>,
+    ) -> Result<String, ConfigError> {
+        let (source, stack) = self.route(stack);
+        source.resolve_revision(stack, reference).await
+    }
+
+    async fn list_files(&self, stack: &str, revision: &str) -> Result<SourceTree, ConfigError> {
+        let (source, stack) = self.route(stack);
+        source.list_files(stack, revision).await
+    }
+
+    async fn read_file(
+        &self,
+        stack: &str,
+        path: &str,
+        revision: &str,
+    ) -> Result<String, ConfigError> {
+        let (source, stack) = self.route(stack);
+        source.read_file(stack, path, revision).await
+    }
+
+    async fn read_entry(
+        &self,
+        stack: &str,
+        revision: &str,
+        entry: &SourceEntry,
+    ) -> Result<String, ConfigError> {
+        let (source, stack) = self.route(stack);
+        source.read_entry(stack, revision, entry).await
+    }
+
+    async fn blame_file(
+        &self,
+        stack: &str,
+        path: &str,
+        revision: &str,
+    ) -> Result<Vec<BlameRange>, ConfigError> {
+        let (source, stack) = self.route(stack);
+        source.blame_file(stack, path, revision).await
+    }
+}
+
+/// Stacks and paths come from requests, none of them may escape `root`.
+fn join_under(root: &Path, stack: &str, path: &str) -> Result<PathBuf, ConfigError> {
+    let relative = Path::new(stack).join(path.trim_start_matches('/'));
+    let escapes = relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    if stack.is_empty() || escapes {
+        return Err(ConfigError::InvalidStack(format!(
+            "Invalid path {}",
+            relative.display()
+        )));
+    }
+    Ok(root.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, is_dir: bool, id: &str) -> SourceEntry {
+        SourceEntry {
+            path: path.to_string(),
+            is_dir,
+            id: id.to_string(),
+        }
+    }
+
+    #[test]
+    fn source_tree_lists_matching_files() {
+        let tree = SourceTree {
+            entries: vec![
+                entry("configs", true, "t1"),
+                entry("configs/service-a", true, "t2"),
+                entry("configs/service-a/config-overrides.yml", false, "b1"),
+                entry("configs/service-a/nested", true, "t3"),
+                entry("configs/service-a/nested/config-overrides.yml", false, "b2"),
+                entry("configs-old/service-b", true, "t4"),
+            ],
+        };
+        let patterns = FilePatterns::parse(&["configs/**/config-overrides.yml"]).unwrap();
+
+        assert_eq!(
+            tree.matching_files(&patterns)
+                .iter()
+                .map(|entry| entry.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["b1", "b2"]
+        );
+    }
+}
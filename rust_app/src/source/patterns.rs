@@ -0,0 +1,94 @@
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Which files of a stack are compared, as globs on paths relative to the
+/// stack root. Patterns starting with `!` exclude files, `*` stays within a
+/// folder while `**` crosses any number of them.
+pub struct FilePatterns {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl FilePatterns {
+    pub fn parse<S: AsRef<str>>(patterns: &[S]) -> Result<Self, globset::Error> {
+        let mut include = GlobSetBuilder::new();
+        let mut exclude = GlobSetBuilder::new();
+        let mut has_include = false;
+        for pattern in patterns {
+            let pattern = pattern.as_ref().trim();
+            if pattern.is_empty() {
+                continue;
+            }
+            match pattern.strip_prefix('!') {
+                Some(pattern) => {
+                    exclude.add(glob(pattern)?);
+                }
+                None => {
+                    include.add(glob(pattern)?);
+                    has_include = true;
+                }
+            }
+        }
+        // Excludes alone narrow down the whole stack
+        if !has_include {
+            include.add(glob("**")?);
+        }
+
+        Ok(FilePatterns {
+            include: include.build()?,
+            exclude: exclude.build()?,
+        })
+    }
+
+    /// The former behaviour, the overrides of every direct subfolder of `folders`.
+    pub fn config_overrides_under(folders: &[&str]) -> Result<Self, globset::Error> {
+        let patterns: Vec<String> = folders
+            .iter()
+            .map(|folder| format!("{}/*/config-overrides.yml", folder.trim_matches('/')))
+            .collect();
+        Self::parse(&patterns)
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        let path = path.trim_start_matches('/');
+        self.include.is_match(path) && !self.exclude.is_match(path)
+    }
+}
+
+fn glob(pattern: &str) -> Result<Glob, globset::Error> {
+    GlobBuilder::new(pattern.trim_start_matches('/'))
+        .literal_separator(true)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes_and_excludes_nested_files() {
+        let patterns = FilePatterns::parse(&["services/**/config*.y?ml", "!**/test/**"]).unwrap();
+
+        assert!(patterns.is_match("services/api/config.yaml"));
+        assert!(patterns.is_match("services/api/eu/config-overrides.yaml"));
+        assert!(patterns.is_match("/services/config.yaml"));
+        assert!(!patterns.is_match("services/api/test/config.yaml"));
+        assert!(!patterns.is_match("services/api/values.yaml"));
+        assert!(!patterns.is_match("other/api/config.yaml"));
+        assert!(FilePatterns::parse(&["services/**/config*.{yml,yaml}"])
+            .unwrap()
+            .is_match("services/api/config.yml"));
+    }
+
+    #[test]
+    fn defaults_to_the_overrides_of_direct_subfolders() {
+        let patterns = FilePatterns::config_overrides_under(&["configs", "/configs-eu/"]).unwrap();
+
+        assert!(patterns.is_match("configs/svc/config-overrides.yml"));
+        assert!(patterns.is_match("configs-eu/svc/config-overrides.yml"));
+        assert!(!patterns.is_match("configs/svc/nested/config-overrides.yml"));
+        assert!(!patterns.is_match("configs/config-overrides.yml"));
+        assert!(FilePatterns::parse(&["!**/test/**"])
+            .unwrap()
+            .is_match("a/b.yml"));
+    }
+}
@@ -1,4 +1,3 @@
-use serde_yaml;
 use std::collections::HashMap;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -23,7 +22,10 @@ pub fn compare_dicts(
     stack.push(("".to_owned(), dict_a, dict_b));
 
     while let Some((path, current_dict_a, current_dict_b)) = stack.pop() {
-        for (key, value_a) in current_dict_a {
+        // Keys are walked in order so that paths are reported the same way every time
+        let mut entries_a: Vec<(&String, &NestedValue)> = current_dict_a.iter().collect();
+        entries_a.sort_by_key(|(key, _)| *key);
+        for (key, value_a) in entries_a {
             let new_path = if path.is_empty() {
                 format!("/{}", key)
             } else {
@@ -67,7 +69,9 @@ pub fn compare_dicts(
                 },
             }
         }
-        for key in current_dict_b.keys() {
+        let mut keys_b: Vec<&String> = current_dict_b.keys().collect();
+        keys_b.sort();
+        for key in keys_b {
             if !current_dict_a.contains_key(key) {
                 let new_path = if path.is_empty() {
                     format!("/{}", key)
@@ -88,6 +92,13 @@ pub fn compare_dicts(
 }
 
 fn yaml_string_to_nested_hash_map(yaml_content: &str) -> Result<NestedHashMap, serde_yaml::Error> {
+    // serde_yaml has no document to read in an empty file, or one with only comments
+    if yaml_content.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with('#')
+    }) {
+        return Ok(HashMap::new());
+    }
     let value: serde_yaml::Value = serde_yaml::from_str(yaml_content)?;
     Ok(convert_yaml_value_to_nested_hash_map(&value))
 }
@@ -129,14 +140,24 @@ fn convert_yaml_value_to_nested_value(yaml_value: &serde_yaml::Value) -> NestedV
     }
 }
 
+pub type YamlComparison = (Vec<String>, Vec<String>, Vec<String>, Vec<String>);
+
+/// Fails when either side isn't valid YAML, which config files fetched from a
+/// stack may well be.
 pub fn compare_yaml_strings(
     yaml_a_content: &str,
     yaml_b_content: &str,
-) -> (Vec<String>, Vec<String>, Vec<String>, Vec<String>) {
-    let dict_a = yaml_string_to_nested_hash_map(yaml_a_content).expect("Failed to parse YAML A");
+) -> Result<YamlComparison, serde_yaml::Error> {
+    let dict_a = yaml_string_to_nested_hash_map(yaml_a_content).map_err(|e| {
+        error!("Failed to parse YAML A, because {}", e);
+        e
+    })?;
     info!("Loaded yaml_A as dict with {} keys", dict_a.len());
 
-    let dict_b = yaml_string_to_nested_hash_map(yaml_b_content).expect("Failed to parse YAML B");
+    let dict_b = yaml_string_to_nested_hash_map(yaml_b_content).map_err(|e| {
+        error!("Failed to parse YAML B, because {}", e);
+        e
+    })?;
     info!("Loaded yaml_B as dict with {} keys", dict_b.len());
 
     let (left_not_right, right_not_left, same_key_same_value, same_key_diff_value) =
@@ -159,19 +180,22 @@ pub fn compare_yaml_strings(
         same_key_diff_value.len()
     );
 
-    (
+    Ok((
         left_not_right,
         right_not_left,
         same_key_same_value,
         same_key_diff_value,
-    )
+    ))
 }
 
+#[cfg(test)]
+mod proptests;
+
 #[cfg(test)]
 mod tests {
-    use std::sync::Once;
     use crate::logger;
     use itertools::Itertools;
+    use std::sync::Once;
 
     use super::*;
 
@@ -187,9 +211,9 @@ mod tests {
 
     pub fn setup_logging_for_tests() {
         INIT.call_once(|| {
+            // Rocket's local clients of other tests may have set their logger first
             if let Err(e) = logger::setup_logging() {
                 eprintln!("Failed to set up logging: {}", e);
-                std::process::exit(1);
             }
         });
     }
@@ -471,4 +495,19 @@ mod tests {
         assert_eq!(same_key_same_value, vec!["/config/nested_config"]);
         assert_eq!(same_key_diff_value, Vec::<String>::new());
     }
+
+    #[test]
+    fn test_compare_yaml_strings_with_empty_document() {
+        let (left_not_right, right_not_left, _, _) =
+            compare_yaml_strings("# Nothing yet\n", "a: 1").unwrap();
+
+        assert_eq!(left_not_right, Vec::<String>::new());
+        assert_eq!(right_not_left, vec!["/a"]);
+    }
+
+    #[test]
+    fn test_compare_yaml_strings_invalid() {
+        assert!(compare_yaml_strings("a: [1", "a: 1").is_err());
+        assert!(compare_yaml_strings("a: 1", "\"b").is_err());
+    }
 }
@@ -0,0 +1,98 @@
+use proptest::prelude::*;
+use std::collections::HashSet;
+
+use super::*;
+
+// Keys are drawn from a tiny alphabet so that two generated maps share a lot
+// of paths, and never contain '/' so that paths stay unambiguous.
+fn arb_key() -> impl Strategy<Value = String> {
+    "[a-e]{1,2}"
+}
+
+fn arb_nested_value() -> impl Strategy<Value = NestedValue> {
+    let leaf = "[0-2]{0,1}".prop_map(NestedValue::Value);
+    leaf.prop_recursive(4, 48, 4, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..3).prop_map(NestedValue::List),
+            prop::collection::hash_map(arb_key(), inner, 0..4).prop_map(NestedValue::Map),
+        ]
+    })
+}
+
+fn arb_nested_hash_map() -> impl Strategy<Value = NestedHashMap> {
+    prop::collection::hash_map(arb_key(), arb_nested_value(), 0..6)
+}
+
+fn sorted(mut paths: Vec<String>) -> Vec<String> {
+    paths.sort();
+    paths
+}
+
+// Every path compare_dicts is expected to report once: all keys present on
+// either side, except maps present on both sides which are walked instead.
+fn expected_paths(
+    dict_a: &NestedHashMap,
+    dict_b: &NestedHashMap,
+    path: &str,
+    paths: &mut Vec<String>,
+) {
+    let keys: HashSet<&String> = dict_a.keys().chain(dict_b.keys()).collect();
+    for key in keys {
+        let new_path = format!("{}/{}", path, key);
+        match (dict_a.get(key), dict_b.get(key)) {
+            (Some(NestedValue::Map(sub_dict_a)), Some(NestedValue::Map(sub_dict_b)))
+                if !(sub_dict_a.is_empty() && sub_dict_b.is_empty()) =>
+            {
+                expected_paths(sub_dict_a, sub_dict_b, &new_path, paths)
+            }
+            _ => paths.push(new_path),
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn left_not_right_mirrors_right_not_left(
+        dict_a in arb_nested_hash_map(),
+        dict_b in arb_nested_hash_map(),
+    ) {
+        let (left_not_right_ab, right_not_left_ab, same_key_same_value_ab, same_key_diff_value_ab) =
+            compare_dicts(&dict_a, &dict_b);
+        let (left_not_right_ba, right_not_left_ba, same_key_same_value_ba, same_key_diff_value_ba) =
+            compare_dicts(&dict_b, &dict_a);
+
+        prop_assert_eq!(sorted(left_not_right_ab), sorted(right_not_left_ba));
+        prop_assert_eq!(sorted(right_not_left_ab), sorted(left_not_right_ba));
+        prop_assert_eq!(sorted(same_key_same_value_ab), sorted(same_key_same_value_ba));
+        prop_assert_eq!(sorted(same_key_diff_value_ab), sorted(same_key_diff_value_ba));
+    }
+
+    #[test]
+    fn compare_with_itself_has_no_diff(dict_a in arb_nested_hash_map()) {
+        let (left_not_right, right_not_left, _same_key_same_value, same_key_diff_value) =
+            compare_dicts(&dict_a, &dict_a);
+
+        prop_assert!(left_not_right.is_empty());
+        prop_assert!(right_not_left.is_empty());
+        prop_assert!(same_key_diff_value.is_empty());
+    }
+
+    #[test]
+    fn every_path_is_in_exactly_one_category(
+        dict_a in arb_nested_hash_map(),
+        dict_b in arb_nested_hash_map(),
+    ) {
+        let (left_not_right, right_not_left, same_key_same_value, same_key_diff_value) =
+            compare_dicts(&dict_a, &dict_b);
+
+        let mut reported = left_not_right;
+        reported.extend(right_not_left);
+        reported.extend(same_key_same_value);
+        reported.extend(same_key_diff_value);
+
+        let mut expected = Vec::new();
+        expected_paths(&dict_a, &dict_b, "", &mut expected);
+
+        prop_assert_eq!(sorted(reported), sorted(expected));
+    }
+}
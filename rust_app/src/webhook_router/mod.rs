@@ -0,0 +1,670 @@
+use std::env;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::db::{DiffCollection, RunStore};
+use crate::diff_router::{compute_all_diffs, AppConfig};
+use crate::error::AppError;
+use crate::github::host::StackLocation;
+use crate::github::GithubClient;
+use crate::models;
+use crate::pr_check::{self, AllowList, CheckedPair, PullRequestCheck};
+use crate::source::patterns::FilePatterns;
+use crate::source::ConfigSource;
+
+/// GitHub caps webhook payloads at 25 MB.
+const MAX_PAYLOAD_SIZE_MB: usize = 25;
+
+/// GitHub lists at most 20 commits in a push payload.
+const MAX_PUSH_COMMITS: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StackPair {
+    pub stack_a: String,
+    pub stack_b: String,
+}
+
+pub struct WebhookConfig {
+    secret: String,
+    /// Where stacks without a host live, e.g. `github.com`.
+    default_host: String,
+    default_owner: String,
+    stack_pairs: Vec<StackPair>,
+    allow_list: Arc<AllowList>,
+    /// Pull requests are only checked when stacks are read from GitHub.
+    github_client: Option<Arc<GithubClient>>,
+}
+
+impl WebhookConfig {
+    pub fn new(
+        secret: String,
+        default_host: &str,
+        default_owner: String,
+        stack_pairs: Vec<StackPair>,
+        allow_list: AllowList,
+    ) -> Self {
+        WebhookConfig {
+            secret,
+            default_host: web_host(default_host).to_ascii_lowercase(),
+            default_owner,
+            stack_pairs,
+            allow_list: Arc::new(allow_list),
+            github_client: None,
+        }
+    }
+
+    pub fn with_github_client(mut self, github_client: Arc<GithubClient>) -> Self {
+        self.github_client = Some(github_client);
+        self
+    }
+
+    /// `None` when `WEBHOOK_SECRET_GH` isn't set. `WEBHOOK_STACK_PAIRS` lists the
+    /// pairs to recompute, space-separated, e.g. `stack-a,stack-b stack-a,stack-c`,
+    /// and `DRIFT_ALLOW_LIST` the key paths pull requests may change the drift of.
+    pub fn from_env() -> Result<Option<Self>, anyhow::Error> {
+        let secret = match env::var("WEBHOOK_SECRET_GH") {
+            Ok(secret) => secret,
+            Err(_) => return Ok(None),
+        };
+        let default_host = env::var("HOSTNAME_GH")?;
+        let default_owner = env::var("ORGANIZATION_GH")?;
+        let stack_pairs = env::var("WEBHOOK_STACK_PAIRS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|pair| match pair.split_once(',') {
+                Some((stack_a, stack_b)) if !stack_a.is_empty() && !stack_b.is_empty() => {
+                    Ok(StackPair {
+                        stack_a: stack_a.to_string(),
+                        stack_b: stack_b.to_string(),
+                    })
+                }
+                _ => Err(anyhow::anyhow!("Invalid stack pair {}", pair)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let allow_list = AllowList::parse(
+            &env::var("DRIFT_ALLOW_LIST")
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect::<Vec<_>>(),
+        )?;
+
+        Ok(Some(WebhookConfig::new(
+            secret,
+            &default_host,
+            default_owner,
+            stack_pairs,
+            allow_list,
+        )))
+    }
+
+    /// Checks `X-Hub-Signature-256`, the hex HMAC-SHA256 of the raw body.
+    pub fn verify_signature(&self, body: &[u8], signature: &str) -> bool {
+        let signature = match signature
+            .strip_prefix("sha256=")
+            .and_then(|signature| hex::decode(signature).ok())
+        {
+            Some(signature) => signature,
+            None => return false,
+        };
+        let mut mac = match Hmac::<Sha256>::new_from_slice(self.secret.as_bytes()) {
+            Ok(mac) => mac,
+            Err(_) => return false,
+        };
+        mac.update(body);
+        // Compared in constant time
+        mac.verify_slice(&signature).is_ok()
+    }
+
+    /// Pairs with a stack in the pushed repository, when the push changed
+    /// config files of its default branch. Pushes with too many commits to
+    /// tell recompute every pair of the repository.
+    pub fn affected_pairs(&self, push: &PushEvent, patterns: &FilePatterns) -> Vec<StackPair> {
+        let default_branch = format!("refs/heads/{}", push.repository.default_branch);
+        if push.r#ref != default_branch {
+            info!("Ignoring push to {}, not the default branch", push.r#ref);
+            return Vec::new();
+        }
+        let lists_every_commit = push.commits.len() < MAX_PUSH_COMMITS;
+        if lists_every_commit
+            && !push
+                .commits
+                .iter()
+                .flat_map(|commit| {
+                    commit
+                        .added
+                        .iter()
+                        .chain(&commit.removed)
+                        .chain(&commit.modified)
+                })
+                .any(|file| patterns.is_match(file))
+        {
+            info!(
+                "Push to {} didn't touch any config file",
+                push.repository.full_name
+            );
+            return Vec::new();
+        }
+
+        self.pairs_of(&push.repository)
+            .into_iter()
+            .map(|(_, pair)| pair)
+            .collect()
+    }
+
+    /// Pairs with a stack in `repository`, along with that stack.
+    fn pairs_of(&self, repository: &WebhookRepository) -> Vec<(&str, StackPair)> {
+        self.stack_pairs
+            .iter()
+            .filter_map(|pair| {
+                [&pair.stack_a, &pair.stack_b]
+                    .into_iter()
+                    .find(|stack| self.is_repository(stack, repository))
+                    .map(|stack| (stack.as_str(), pair.clone()))
+            })
+            .collect()
+    }
+
+    fn is_repository(&self, stack: &str, repository: &WebhookRepository) -> bool {
+        let location = match StackLocation::parse(stack, &self.default_owner) {
+            Ok(location) => location,
+            Err(_) => return false,
+        };
+        let host = location.host.unwrap_or(&self.default_host);
+        web_host(&repository.html_url).eq_ignore_ascii_case(host)
+            && repository
+                .full_name
+                .eq_ignore_ascii_case(&format!("{}/{}", location.owner, location.repo))
+    }
+}
+
+/// The host of a URL or of an API base, `github.com` being the host of
+/// `https://api.github.com` as its API is served apart.
+fn web_host(url: &str) -> &str {
+    let address = url.split_once("://").map_or(url, |(_, address)| address);
+    let host = address.split('/').next().unwrap_or(address);
+    if host == "api.github.com" {
+        "github.com"
+    } else {
+        host
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WebhookRepository {
+    pub full_name: String,
+    pub html_url: String,
+    pub default_branch: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PushCommit {
+    #[serde(default)]
+    pub added: Vec<String>,
+    #[serde(default)]
+    pub removed: Vec<String>,
+    #[serde(default)]
+    pub modified: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PushEvent {
+    pub r#ref: String,
+    pub repository: WebhookRepository,
+    #[serde(default)]
+    pub commits: Vec<PushCommit>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PullRequestCommit {
+    pub sha: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PullRequest {
+    pub head: PullRequestCommit,
+    pub base: PullRequestCommit,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PullRequestEvent {
+    pub action: String,
+    pub number: u64,
+    pub pull_request: PullRequest,
+    pub repository: WebhookRepository,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WebhookResponse {
+    pub recomputing: Vec<StackPair>,
+}
+
+/// The `X-GitHub-Event` and `X-Hub-Signature-256` headers of a delivery.
+pub struct GithubDelivery<'r> {
+    event: &'r str,
+    signature: &'r str,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for GithubDelivery<'r> {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        match (
+            headers.get_one("X-GitHub-Event"),
+            headers.get_one("X-Hub-Signature-256"),
+        ) {
+            (Some(event), Some(signature)) => {
+                request::Outcome::Success(GithubDelivery { event, signature })
+            }
+            _ => request::Outcome::Error((
+                Status::BadRequest,
+                "Missing X-GitHub-Event or X-Hub-Signature-256 header".to_string(),
+            )),
+        }
+    }
+}
+
+#[post("/webhooks/github", data = "<data>")]
+pub async fn github_webhook(
+    delivery: GithubDelivery<'_>,
+    data: Data<'_>,
+    webhook_config: &State<WebhookConfig>,
+    source: &State<Arc<dyn ConfigSource>>,
+    app_config: &State<Arc<AppConfig>>,
+    mongo: &State<DiffCollection>,
+    runs: &State<Arc<dyn RunStore>>,
+) -> Result<status::Custom<Json<WebhookResponse>>, AppError> {
+    let body = data
+        .open(MAX_PAYLOAD_SIZE_MB.mebibytes())
+        .into_bytes()
+        .await
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    if !body.is_complete() {
+        return Err(AppError::PayloadTooLarge(
+            "Webhook payload too large".to_string(),
+        ));
+    }
+    if !webhook_config.verify_signature(&body, delivery.signature) {
+        warn!(
+            "Rejected {} webhook with an invalid signature",
+            delivery.event
+        );
+        return Err(AppError::Unauthorized("Invalid signature".to_string()));
+    }
+
+    let parse_error =
+        |e: rocket::serde::json::serde_json::Error| AppError::BadRequest(e.to_string());
+    // GitHub gives up on deliveries after 10 seconds, so diffs are computed in the background
+    let recomputing = match delivery.event {
+        "push" => {
+            let push: PushEvent = rocket::serde::json::from_slice(&body).map_err(parse_error)?;
+            recompute_pushed_pairs(push, webhook_config, source, app_config, mongo, runs)
+        }
+        "pull_request" => {
+            let event: PullRequestEvent =
+                rocket::serde::json::from_slice(&body).map_err(parse_error)?;
+            check_pull_request(event, webhook_config, source, app_config)
+        }
+        event => {
+            info!("Ignoring {} webhook", event);
+            return Ok(status::Custom(
+                Status::Ok,
+                Json(WebhookResponse {
+                    recomputing: Vec::new(),
+                }),
+            ));
+        }
+    };
+
+    Ok(status::Custom(
+        Status::Accepted,
+        Json(WebhookResponse { recomputing }),
+    ))
+}
+
+fn recompute_pushed_pairs(
+    push: PushEvent,
+    webhook_config: &WebhookConfig,
+    source: &State<Arc<dyn ConfigSource>>,
+    app_config: &State<Arc<AppConfig>>,
+    mongo: &State<DiffCollection>,
+    runs: &State<Arc<dyn RunStore>>,
+) -> Vec<StackPair> {
+    let stack_pairs = webhook_config.affected_pairs(&push, &app_config.file_patterns);
+    info!(
+        "Push to {} recomputes {} stack pairs",
+        push.repository.full_name,
+        stack_pairs.len()
+    );
+
+    for pair in stack_pairs.clone() {
+        let source = source.inner().clone();
+        let app_config = app_config.inner().clone();
+        let mongo = mongo.inner().clone();
+        let runs = runs.inner().clone();
+        rocket::tokio::spawn(async move {
+            let payload = models::ComputeAllDiffPayload {
+                stack_a: pair.stack_a,
+                stack_b: pair.stack_b,
+                ref_a: None,
+                ref_b: None,
+            };
+            match compute_all_diffs(payload, source.as_ref(), &app_config, &mongo, runs.as_ref())
+                .await
+            {
+                Ok(response) => info!(
+                    "Recomputed {} diffs between {} and {}",
+                    response.files_with_diff.len(),
+                    response.stack_a,
+                    response.stack_b
+                ),
+                Err(e) => error!("Couldn't recompute diffs, because {}", e),
+            }
+        });
+    }
+    stack_pairs
+}
+
+fn check_pull_request(
+    event: PullRequestEvent,
+    webhook_config: &State<WebhookConfig>,
+    source: &State<Arc<dyn ConfigSource>>,
+    app_config: &State<Arc<AppConfig>>,
+) -> Vec<StackPair> {
+    if !matches!(event.action.as_str(), "opened" | "synchronize" | "reopened") {
+        info!("Ignoring {} pull request", event.action);
+        return Vec::new();
+    }
+    let github_client = match &webhook_config.github_client {
+        Some(github_client) => github_client.clone(),
+        None => {
+            info!("Pull requests are only checked with the github config source");
+            return Vec::new();
+        }
+    };
+    // Pairs may spell the stack of the repository differently
+    let pairs = webhook_config.pairs_of(&event.repository);
+    let stack = match pairs.first() {
+        Some((stack, _)) => stack.to_string(),
+        None => return Vec::new(),
+    };
+    let pairs: Vec<CheckedPair> = pairs
+        .into_iter()
+        .map(|(pair_stack, pair)| CheckedPair {
+            on_stack_a: pair.stack_a == pair_stack,
+            pair,
+        })
+        .collect();
+    info!(
+        "Checking #{} of {} against {} stack pairs",
+        event.number,
+        stack,
+        pairs.len()
+    );
+
+    let allow_list = webhook_config.allow_list.clone();
+    let source = source.inner().clone();
+    let app_config = app_config.inner().clone();
+    let recomputing = pairs.iter().map(|checked| checked.pair.clone()).collect();
+    rocket::tokio::spawn(async move {
+        let check = PullRequestCheck {
+            stack: &stack,
+            number: event.number,
+            base_sha: &event.pull_request.base.sha,
+            head_sha: &event.pull_request.head.sha,
+            pairs,
+        };
+        if let Err(e) = pr_check::check_pull_request(
+            &github_client,
+            source.as_ref(),
+            &app_config,
+            &allow_list,
+            check,
+        )
+        .await
+        {
+            error!(
+                "Couldn't check #{} of {}, because {}",
+                event.number, stack, e
+            );
+        }
+    });
+    recomputing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryRunStore;
+    use crate::github::mock::{repo_route, MockGithub};
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::{json, Value};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, Request, ResponseTemplate};
+
+    const SECRET: &str = "It's a Secret to Everybody";
+
+    fn webhook_config() -> WebhookConfig {
+        let pair = |stack_a: &str, stack_b: &str| StackPair {
+            stack_a: stack_a.to_string(),
+            stack_b: stack_b.to_string(),
+        };
+        WebhookConfig::new(
+            SECRET.to_string(),
+            "github.com",
+            "my-org".to_string(),
+            vec![
+                pair("stack-a", "stack-b"),
+                pair("stack-c", "other-org/stack-a"),
+                pair("stack-c", "github.example.com/my-org/stack-a"),
+            ],
+            AllowList::parse::<&str>(&[]).unwrap(),
+        )
+    }
+
+    fn push(r#ref: &str, modified: &str) -> PushEvent {
+        PushEvent {
+            r#ref: r#ref.to_string(),
+            repository: WebhookRepository {
+                full_name: "my-org/stack-a".to_string(),
+                html_url: "https://github.com/my-org/stack-a".to_string(),
+                default_branch: "main".to_string(),
+            },
+            commits: vec![PushCommit {
+                added: Vec::new(),
+                removed: Vec::new(),
+                modified: vec![modified.to_string()],
+            }],
+        }
+    }
+
+    #[test]
+    fn pushes_only_match_stacks_of_their_host() {
+        let config = webhook_config();
+        let patterns = FilePatterns::parse(&["configs/*/config-overrides.yml"]).unwrap();
+        let mut push = push("refs/heads/main", "configs/svc/config-overrides.yml");
+        push.repository.html_url = "https://github.example.com/my-org/stack-a".to_string();
+
+        assert_eq!(
+            config.affected_pairs(&push, &patterns),
+            vec![config.stack_pairs[2].clone()]
+        );
+    }
+
+    #[test]
+    fn pushes_listing_too_many_commits_recompute_every_pair() {
+        let config = webhook_config();
+        let patterns = FilePatterns::parse(&["configs/*/config-overrides.yml"]).unwrap();
+        let mut push = push("refs/heads/main", "README.md");
+        push.commits = (0..MAX_PUSH_COMMITS)
+            .map(|_| PushCommit {
+                added: Vec::new(),
+                removed: Vec::new(),
+                modified: vec!["README.md".to_string()],
+            })
+            .collect();
+
+        assert_eq!(
+            config.affected_pairs(&push, &patterns),
+            vec![config.stack_pairs[0].clone()]
+        );
+    }
+
+    #[test]
+    fn verifies_the_signature_of_the_body() {
+        let config = webhook_config();
+        // Example delivery from the GitHub documentation
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+        assert!(config.verify_signature(b"Hello, World!", signature));
+        assert!(!config.verify_signature(b"Hello, World?", signature));
+        assert!(!config.verify_signature(b"Hello, World!", "sha256=757107"));
+        assert!(!config.verify_signature(b"Hello, World!", &signature[7..]));
+    }
+
+    #[test]
+    fn recomputes_pairs_of_the_pushed_repository() {
+        let config = webhook_config();
+        let patterns = FilePatterns::parse(&["configs/*/config-overrides.yml"]).unwrap();
+
+        assert_eq!(
+            config.affected_pairs(
+                &push("refs/heads/main", "configs/svc/config-overrides.yml"),
+                &patterns
+            ),
+            vec![config.stack_pairs[0].clone()]
+        );
+        assert!(config
+            .affected_pairs(&push("refs/heads/main", "README.md"), &patterns)
+            .is_empty());
+        assert!(config
+            .affected_pairs(
+                &push("refs/heads/feature", "configs/svc/config-overrides.yml"),
+                &patterns
+            )
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn checks_pull_requests_against_every_spelling_of_their_stack() {
+        let github = MockGithub::start().await;
+        let file = "configs/svc/config-overrides.yml";
+        for (repo, reference, sha, blob) in [
+            ("stack-a", "b0", "b0", "x1"),
+            ("stack-a", "h1", "h1", "x2"),
+            ("stack-b", "HEAD", "c2", "x1"),
+            ("stack-c", "HEAD", "c3", "x1"),
+        ] {
+            github.commit(repo, reference, sha).await;
+            github.tree(repo, sha, &[(file, blob)]).await;
+        }
+        github.blob("stack-a", "x1", "port: 80\n").await;
+        github.blob("stack-a", "x2", "port: 8080\n").await;
+        github.blob("stack-b", "x1", "port: 80\n").await;
+        github.blob("stack-c", "x1", "port: 80\n").await;
+        github
+            .get(
+                &repo_route("stack-a", "/pulls/5/files"),
+                json!([{ "filename": file }]),
+            )
+            .await;
+        github
+            .get(&repo_route("stack-a", "/issues/5/comments"), json!([]))
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({})))
+            .mount(&github.server)
+            .await;
+
+        let github_client = Arc::new(github.client().await);
+        let source: Arc<dyn ConfigSource> = github_client.clone();
+        let mongo_client = mongodb::Client::with_uri_str("mongodb://127.0.0.1:9")
+            .await
+            .unwrap();
+        let pair = |stack_a: &str, stack_b: &str| StackPair {
+            stack_a: stack_a.to_string(),
+            stack_b: stack_b.to_string(),
+        };
+        let webhook_config = WebhookConfig::new(
+            SECRET.to_string(),
+            "github.com",
+            "my-org".to_string(),
+            vec![
+                pair("stack-a", "stack-b"),
+                pair("stack-c", "my-org/stack-a"),
+            ],
+            AllowList::parse::<&str>(&[]).unwrap(),
+        )
+        .with_github_client(github_client);
+        let rocket = rocket::build()
+            .manage(webhook_config)
+            .manage(source)
+            .manage(Arc::new(AppConfig {
+                file_patterns: FilePatterns::config_overrides_under(&["configs"]).unwrap(),
+            }))
+            .manage(DiffCollection::init(&mongo_client.database("test")).await)
+            .manage(Arc::new(MemoryRunStore::default()) as Arc<dyn RunStore>)
+            .mount("/", routes![github_webhook]);
+        let client = Client::tracked(rocket).await.unwrap();
+        let body = json!({
+            "action": "synchronize",
+            "number": 5,
+            "pull_request": { "base": { "sha": "b0" }, "head": { "sha": "h1" } },
+            "repository": {
+                "full_name": "my-org/stack-a",
+                "html_url": "https://github.com/my-org/stack-a",
+                "default_branch": "main"
+            }
+        })
+        .to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+
+        let response = client
+            .post("/webhooks/github")
+            .header(Header::new("X-GitHub-Event", "pull_request"))
+            .header(Header::new(
+                "X-Hub-Signature-256",
+                format!("sha256={}", hex::encode(mac.finalize().into_bytes())),
+            ))
+            .body(body)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Accepted);
+
+        // The check runs in the background, until it sets the final status
+        let statuses = |requests: &[Request]| -> Vec<Value> {
+            requests
+                .iter()
+                .filter(|request| request.url.path() == repo_route("stack-a", "/statuses/h1"))
+                .map(|request| request.body_json().unwrap())
+                .collect()
+        };
+        let mut final_status = None;
+        for _ in 0..100 {
+            let requests = github.server.received_requests().await.unwrap();
+            if let Some(status) = statuses(&requests).into_iter().nth(1) {
+                final_status = Some(status);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        let final_status = final_status.expect("No final status");
+        assert_eq!(final_status["state"], "failure");
+        assert_eq!(
+            final_status["description"],
+            "2 drifting keys not on the allow-list"
+        );
+    }
+}